@@ -3,7 +3,12 @@
 // This file may not be copied, modified, or distributed except according
 // to those terms.
 
-use clap::{crate_version, value_parser, Arg, ArgAction, ColorChoice, Command};
+use clap::error::ErrorKind;
+use clap::{
+    crate_version, value_parser, Arg, ArgAction, ArgMatches, ColorChoice,
+    Command,
+};
+use hyperex::utils::HEADER_FIELDS;
 
 pub fn build_app() -> Command {
     let clap_color_setting = if std::env::var_os("NO_COLOR").is_none() {
@@ -22,24 +27,86 @@ pub fn build_app() -> Command {
             "Note: `hyperex -h` prints a short and concise overview while `hyperex --help` gives all \
                  details.",
         )
+        .after_long_help(
+            "Environment:\n    \
+                HYPEREX_PRIMERS    FORWARD,REVERSE primer pairs separated by `;`, used when no primer\n                       \
+                or region is given on the command line\n    \
+                HYPEREX_REGION     region names separated by `,`, used when no primer or region is\n                       \
+                given on the command line nor in HYPEREX_PRIMERS\n\n\
+            Note: `hyperex -h` prints a short and concise overview while `hyperex --help` gives all \
+                 details.",
+        )
         .author("Anicet Ebou, anicet.ebou@gmail.com")
         .about("Hypervariable region primer-based extractor")
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            Command::new("benchmark")
+                .about("rank built-in regions by extraction success")
+                .long_about(
+                    "Extracts all the built-in regions from the input without\n\
+                    writing them and prints a table of regions ranked by the\n\
+                    number of records they were extracted from, then by their\n\
+                    mean primer mismatch, with their mean amplicon length"
+                )
+                .override_usage("hyperex benchmark [options] <FILE>")
+                .arg(
+                    Arg::new("FILE")
+                        .help("input fasta or fastq file")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("mismatch")
+                        .help("number of allowed mismatch")
+                        .long("mismatch")
+                        .short('m')
+                        .value_name("N")
+                        .value_parser(value_parser!(u8))
+                        .default_value("0")
+                ),
+        )
         .arg(
             Arg::new("FILE")
-                .help("input fasta file or stdin")
-                .long_help("input fasta file. With no FILE, or when FILE is -, read standard input. Input data can be gzip'd, xz'd or bzip'd")
+                .help("input fasta files or stdin")
+                .long_help("input fasta or fastq files. With no FILE, or when FILE is -, read standard input. Input data can be gzip'd, xz'd or bzip'd. Amplicons of several files are written to the same outputs, record ids being prefixed with the file name without extensions and _")
+                .num_args(1..)
                 .index(1),
         )
+        .arg(
+            Arg::new("split")
+                .help("write each input file outputs separately")
+                .long_help(
+                    "Writes the outputs of each input file with PREFIX.SAMPLE as\n\
+                    prefix, SAMPLE being the file name without extensions,\n\
+                    instead of writing all amplicons to the same outputs"
+                )
+                .long("split")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("input_format")
+                .help("input compression format")
+                .long_help(
+                    "Specifies the compression of the input file instead of\n\
+                    detecting it from its first bytes. `fasta` reads the input\n\
+                    uncompressed, whether fasta or fastq"
+                )
+                .long("input-format")
+                .value_name("STR")
+                .value_parser(["auto", "fasta", "gz", "xz", "bz2", "zstd"])
+                .default_value("auto")
+        )
         .arg(
             Arg::new("forward_primer")
                 .short('f')
                 .long("forward-primer")
                 .help("forward primer sequence")
                 .long_help("Specifies forward primer sequence which can contains IUPAC ambiguities")
-                .conflicts_with("region")
+                .conflicts_with_all(["region", "region_file"])
                 .requires("reverse_primer")
                 .num_args(1..)
                 .number_of_values(1)
+                .action(ArgAction::Append)
                 .value_name("STR")
         )
         .arg(
@@ -47,40 +114,728 @@ pub fn build_app() -> Command {
                 .short('r')
                 .long("reverse-primer")
                 .help("reverse primer sequence")
-                .long_help("Specifies reverse primer sequence which can contains IUPAC ambiguities")
-                .conflicts_with("region")
+                .long_help(
+                    "Specifies reverse primer sequence which can contains IUPAC ambiguities.\n\
+                    When no forward primer is supplied, amplicons extend from the\n\
+                    sequence start (or --length bases upstream) to the reverse primer"
+                )
+                .conflicts_with_all(["region", "region_file"])
                 .num_args(1..)
                 .number_of_values(1)
+                .action(ArgAction::Append)
                 .value_name("STR")
         )
+        .arg(
+            Arg::new("reverse_as_is")
+                .help("reverse primers are given as found on the sequence")
+                .long_help(
+                    "Reverse primers are by default given 5' to 3' on the\n\
+                    opposite strand, as ordered for PCR, and hyperex searches\n\
+                    their reverse complement. With this flag, supplied reverse\n\
+                    primers (--reverse-primer, --region-file or\n\
+                    HYPEREX_PRIMERS) are given as found on the sequence,\n\
+                    already reverse complemented, and searched as they are.\n\
+                    Amplicon headers show them in the usual orientation"
+                )
+                .long("reverse-as-is")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("region")
                 .long("region")
                 .help("hypervariable region name")
                 .long_help(
                     "Specifies 16S rRNA region name wanted. Supported values are\n\
-                    v1v2, v1v3, v1v9, v3v4, v3v5, v4, v4v5, v5v7, v6v9, v7v9"
+                    v1v2, v1v3, v1v9, v3v4, v3v5, v4, v4v5, v5v7, v6v9, v7v9,\n\
+                    the fungal its1, the eukaryotic 18S rRNA 18s_v4 and the\n\
+                    regions defined with --regions-def"
                 )
-                .value_parser(clap::builder::PossibleValuesParser::new(["v1v2", "v1v3", "v1v9", "v3v4", "v3v5", "v4", "v4v5", "v5v7", "v6v9", "v7v9"]))
-                .hide_possible_values(true)
                 .num_args(1..)
                 .number_of_values(1)
+                .action(ArgAction::Append)
                 .value_name("STR")
         )
+        .arg(
+            Arg::new("region_file")
+                .long("region-file")
+                .visible_alias("primers-file")
+                .help("file of primer pairs")
+                .long_help(
+                    "Specifies a comma separated file of primer pairs, one\n\
+                    FORWARD,REVERSE pair per line"
+                )
+                .conflicts_with("region")
+                .value_name("FILE")
+        )
+        .arg(
+            Arg::new("primers_format")
+                .long("primers-format")
+                .help("format of the primer file: csv or qiime")
+                .long_help(
+                    "Format of the --region-file primer file. csv is a comma\n\
+                    separated file of primer pairs, qiime a QIIME 2 metadata\n\
+                    file with forward-primer and reverse-primer columns, the\n\
+                    first column naming the regions"
+                )
+                .value_name("STR")
+                .value_parser(["csv", "qiime"])
+                .default_value("csv")
+        )
+        .arg(
+            Arg::new("regions_def")
+                .long("regions-def")
+                .help("file of user-defined regions")
+                .long_help(
+                    "Specifies a TOML file defining regions in addition to the\n\
+                    built-in ones, each as a table named after the region with\n\
+                    a forward and a reverse primer. Primers are given as\n\
+                    sequences or as built-in primer names, e.g.\n\n\
+                    [its1]\n\
+                    forward = \"CTTGGTCATTTAGAGGAAGTAA\"\n\
+                    reverse = \"GCTGCGTTCTTCATCGATGC\"\n\n\
+                    Defined regions are extracted with --region or, with no\n\
+                    region or primer, along with all the built-in regions.\n\
+                    An optional gff_type sets the GFF feature type of a defined\n\
+                    or built-in region, `region` being used otherwise"
+                )
+                .value_name("FILE")
+        )
+        .arg(
+            Arg::new("length")
+                .help("amplicon length in reverse-only mode")
+                .long_help(
+                    "Specifies the amplicon length, counted upstream of the\n\
+                    reverse primer end, when only reverse primers are supplied.\n\
+                    Amplicons start at the sequence start otherwise"
+                )
+                .long("length")
+                .requires("reverse_primer")
+                .conflicts_with("forward_primer")
+                .value_name("N")
+                .value_parser(value_parser!(usize))
+        )
+        .arg(
+            Arg::new("dedup")
+                .help("remove duplicated amplicons")
+                .long_help(
+                    "Only writes the first amplicon of each distinct sequence\n\
+                    of a region. Left out amplicons are still counted in the\n\
+                    statistics"
+                )
+                .long("dedup")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dedupe_primers")
+                .help("remove duplicated primer pairs")
+                .long_help(
+                    "Removes primer pairs supplied more than once. Duplicated\n\
+                    pairs are otherwise only reported with a warning and their\n\
+                    amplicons are extracted once per occurrence"
+                )
+                .long("dedupe-primers")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("mismatch")
                 .help("number of allowed mismatch")
                 .long_help(
-                    "Specifies the number of allowed mismatch. This cannot\n\
-                    be greater than the length of the lengthest primer"
+                    "Specifies the number of allowed mismatch, for both\n\
+                    primers or as FORWARD,REVERSE to allow the forward and\n\
+                    reverse primers a different number of mismatch. This\n\
+                    cannot be greater than the length of the primers"
                 )
                 .long("mismatch")
                 .short('m')
                 .value_name("N")
+                .value_parser(mismatch)
+                .default_value("0")
+        )
+        .arg(
+            Arg::new("quality_trim")
+                .help("trim fastq reads 3' end below quality Q")
+                .long_help(
+                    "Trims the 3' end bases of fastq reads which Phred quality\n\
+                    is lower than Q before searching primers. Ignored for\n\
+                    fasta input"
+                )
+                .long("quality-trim")
+                .value_name("Q")
                 .value_parser(value_parser!(u8))
-                .hide_possible_values(true)
+        )
+        .arg(
+            Arg::new("only_complete")
+                .help("only extract amplicons with both primers [default]")
+                .long_help(
+                    "Only extracts amplicons for which both the forward and the\n\
+                    reverse primers were found. This is the default behavior"
+                )
+                .long("only-complete")
+                .overrides_with("allow_partial")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("allow_partial")
+                .help("also extract amplicons missing one primer")
+                .long_help(
+                    "Also extracts amplicons for which only one primer was\n\
+                    found. They extend to the sequence end opposite to the\n\
+                    found primer and are marked with partial=forward or\n\
+                    partial=reverse in the fasta header"
+                )
+                .long("allow-partial")
+                .overrides_with("only_complete")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("degap")
+                .help("remove alignment gaps")
+                .long_help(
+                    "Removes gap characters (- and .) from sequences before\n\
+                    primer search, so that aligned sequences can be used.\n\
+                    Coordinates are then relative to the ungapped sequences"
+                )
+                .long("degap")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("match_seq")
+                .help("report primers matched sequences")
+                .long_help(
+                    "Adds the sequences actually matched by the forward and\n\
+                    reverse primers to the fasta header as fwd_match_seq and\n\
+                    rev_match_seq. Both are reported as found on the sequence\n\
+                    strand"
+                )
+                .long("match-seq")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("degenerate_expand")
+                .help("report the bases matched by primer positions")
+                .long_help(
+                    "Aligns each primer to its best hit and adds the base matched\n\
+                    by each primer position to the fasta header as fwd_match and\n\
+                    rev_match, resolving the primers IUPAC ambiguities. Both are\n\
+                    reported in the orientation of the primers, primer positions\n\
+                    without a matched base being written as -"
+                )
+                .long("degenerate-expand")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("min_forward_distance")
+                .help("minimum distance between the primers starts")
+                .long_help(
+                    "Specifies the minimum number of bases from the forward\n\
+                    primer start to the reverse primer start. Amplicons whose\n\
+                    primers are found closer are not extracted"
+                )
+                .long("min-forward-distance")
+                .value_name("N")
+                .value_parser(value_parser!(usize))
+        )
+        .arg(
+            Arg::new("min_reverse_distance")
+                .help("minimum number of bases between the primers")
+                .long_help(
+                    "Specifies the minimum number of bases between the forward\n\
+                    primer end and the reverse primer start. Amplicons whose\n\
+                    primers are found closer are not extracted"
+                )
+                .long("min-reverse-distance")
+                .value_name("N")
+                .value_parser(value_parser!(usize))
+        )
+        .arg(
+            Arg::new("min_length")
+                .help("minimum amplicon length")
+                .long_help(
+                    "Specifies the minimum length of the extracted amplicons.\n\
+                    Shorter amplicons, usually from spurious primer hits, are\n\
+                    not extracted"
+                )
+                .long("min-length")
+                .value_name("N")
+                .value_parser(value_parser!(usize))
+        )
+        .arg(
+            Arg::new("max_length")
+                .help("maximum amplicon length")
+                .long_help(
+                    "Specifies the maximum length of the extracted amplicons.\n\
+                    Longer amplicons, usually from spurious primer hits, are\n\
+                    not extracted"
+                )
+                .long("max-length")
+                .value_name("N")
+                .value_parser(value_parser!(usize))
+        )
+        .arg(
+            Arg::new("length_tolerance")
+                .help("warn about amplicons of unexpected length")
+                .long_help(
+                    "Warns about amplicons of built-in 16S rRNA regions whose\n\
+                    length, primers included, differs from the Escherichia\n\
+                    coli one by more than PERCENT percent. Such amplicons\n\
+                    may come from mispriming"
+                )
+                .long("length-tolerance")
+                .value_name("PERCENT")
+                .value_parser(value_parser!(f64))
+        )
+        .arg(
+            Arg::new("max_ambiguous")
+                .help("set aside amplicons with more ambiguous bases")
+                .long_help(
+                    "Writes amplicons whose fraction of ambiguous bases (other\n\
+                    than A, C, G, T or U) is greater than F to\n\
+                    PREFIX.ambiguous.fa, or PREFIX.ambiguous.fq for FASTQ\n\
+                    input, instead of the main output, with their\n\
+                    ambiguous_fraction in the header. F is between 0 and 1"
+                )
+                .long("max-ambiguous-fraction")
+                .visible_alias("max-ambiguous")
+                .value_name("F")
+                .value_parser(fraction)
+        )
+        .arg(
+            Arg::new("sqlite")
+                .help("also write amplicons to an SQLite database")
+                .long_help(
+                    "Also writes the extracted amplicons to the hits table of\n\
+                    an SQLite database, with their record_id, region, start,\n\
+                    end, strand, sequence and mismatches. Requires hyperex to\n\
+                    be built with the sqlite feature"
+                )
+                .long("sqlite")
+                .value_name("FILE")
+        )
+        .arg(
+            Arg::new("length_histogram")
+                .help("write a histogram of amplicon lengths")
+                .long_help(
+                    "Writes, for each region, the number of extracted amplicons\n\
+                    per range of N lengths to PREFIX.lengths.tsv. Bins run from\n\
+                    the one of the shortest amplicon to the one of the longest,\n\
+                    empty bins included"
+                )
+                .long("length-histogram")
+                .value_name("N")
+                .value_parser(value_parser!(u64).range(1..))
+        )
+        .arg(
+            Arg::new("stats")
+                .help("print run statistics to stderr")
+                .long_help(
+                    "Prints the number of records read and of amplicons\n\
+                    extracted per region to stderr at the end of the run, even\n\
+                    with --quiet. Lines are tab separated and start with STATS:\n\
+                    STATS records N, then STATS region NAME N for each region.\n\
+                    Also writes the count, min, max, mean and median length of\n\
+                    the amplicons of each region to PREFIX.stats.tsv"
+                )
+                .long("stats")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("stats_per_position")
+                .help("write amplicons coverage track")
+                .long_help(
+                    "Writes, for each record, the number of extracted amplicons\n\
+                    covering each of its positions to PREFIX.bedgraph. Each\n\
+                    record is a chromosome of the track and positions are\n\
+                    0-based. Uncovered positions are not reported"
+                )
+                .long("stats-per-position")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("mask")
+                .help("write records with soft-masked regions")
+                .long_help(
+                    "Also writes the whole records to PREFIX.masked.fa, the\n\
+                    bases of their extracted regions, primers included, in\n\
+                    lowercase and the other bases in uppercase"
+                )
+                .long("mask")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("emit_primer_sites")
+                .help("write primer binding sites")
+                .long_help(
+                    "Writes the sequences bound by the forward and reverse\n\
+                    primers of each amplicon to PREFIX.sites.fa, as found on\n\
+                    the sequence strand, with ids ID_fwd and ID_rev. The region,\n\
+                    primer and site coordinates, 1-based and inclusive, are\n\
+                    given in the fasta header"
+                )
+                .long("emit-primer-sites")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("group_by")
+                .help("group output records")
+                .long_help(
+                    "Groups output records. With `region`, all amplicons of the\n\
+                    first requested region are written first, then those of\n\
+                    the second region and so on. Output is buffered in memory\n\
+                    until the end of the run"
+                )
+                .long("group-by")
+                .value_name("STR")
+                .value_parser(["region"])
+        )
+        .arg(
+            Arg::new("trim")
+                .help("remove primers from amplicons")
+                .long_help(
+                    "Removes the binding site of the forward primer, of the\n\
+                    reverse primer, of both or of none of them from the\n\
+                    extracted amplicons. Coordinates are those of the trimmed\n\
+                    amplicons. Amplicons with nothing left between their primers\n\
+                    are not extracted"
+                )
+                .long("trim")
+                .value_name("STR")
+                .value_parser(["forward", "reverse", "both", "none"])
+                .default_value("none")
+        )
+        .arg(
+            Arg::new("trim_primers")
+                .help("remove both primers from amplicons")
+                .long_help(
+                    "Extracts the insert between the forward primer end and the\n\
+                    reverse primer start, as --trim both. FASTA headers note\n\
+                    whether primers were trimmed or kept"
+                )
+                .long("trim-primers")
+                .conflicts_with("trim")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("all_hits")
+                .help("extract the amplicons of all primer hits")
+                .long_help(
+                    "Extracts an amplicon for each forward primer hit and the\n\
+                    nearest reverse primer hit downstream, up to --max-length\n\
+                    bases or 2000 bases, instead of for the best hits only.\n\
+                    Amplicons of a record are numbered by adding _1, _2... to\n\
+                    their fasta id"
+                )
+                .long("all-hits")
+                .conflicts_with("aligner")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("max_regions_per_record")
+                .help("write at most N regions per record")
+                .long_help(
+                    "Stops writing the regions of a record once N were\n\
+                    written, with a warning. This bounds the output of\n\
+                    malformed records, such as concatenated sequences, which\n\
+                    would yield many regions with --all-hits. Unlimited by\n\
+                    default"
+                )
+                .long("max-regions-per-record")
+                .value_name("N")
+                .value_parser(value_parser!(u64).range(1..))
+        )
+        .arg(
+            Arg::new("format")
+                .help("annotation format: gff, bed, both or json")
+                .long_help(
+                    "Writes the amplicon coordinates to PREFIX.gff (GFF3,\n\
+                    1-based), to PREFIX.bed (BED, 0-based and end exclusive)\n\
+                    or to both files. json writes instead PREFIX.json, an\n\
+                    array of the amplicons with their 0-based coordinates,\n\
+                    primers, mismatches and sequence"
+                )
+                .long("format")
+                .value_name("STR")
+                .value_parser(["gff", "bed", "both", "json"])
+                .default_value("gff")
+        )
+        .arg(
+            Arg::new("compress")
+                .help("output compression format")
+                .long_help(
+                    "Compresses the amplicon and annotation files, their names\n\
+                    ending with .gz, .xz or .bz2, e.g. PREFIX.fa.gz. Compressed\n\
+                    files can not be appended to"
+                )
+                .long("compress")
+                .value_name("STR")
+                .value_parser(["none", "gz", "xz", "bz2"])
+                .default_value("none")
+                .conflicts_with("append")
+        )
+        .arg(
+            Arg::new("infer_region")
+                .help("infer regions of primers close to built-in ones")
+                .long_help(
+                    "Adds region_inferred=REGION to the fasta header of amplicons\n\
+                    whose primers are not built-in but within 2 edits of\n\
+                    built-in primers, such as 515F-Y for 515F. REGION is the\n\
+                    region of the closest built-in primers"
+                )
+                .long("infer-region")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("collect_errors")
+                .help("summarize record errors at the end")
+                .long_help(
+                    "Collects records whose sequence type is not recognized,\n\
+                    whose regions are not found or not extracted, and prints\n\
+                    their number with a few example ids at the end of the run\n\
+                    instead of a warning per record"
+                )
+                .long("collect-errors")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("id_prefix")
+                .help("add a prefix to output record ids")
+                .long_help(
+                    "Adds STR before the record ids in all outputs, e.g. a sample\n\
+                    name to merge the outputs of several samples. STR cannot\n\
+                    contain spaces or control characters"
+                )
+                .long("id-prefix")
+                .value_name("STR")
+                .value_parser(id_affix)
+        )
+        .arg(
+            Arg::new("id_suffix")
+                .help("add a suffix to output record ids")
+                .long_help(
+                    "Adds STR after the record ids in all outputs. STR cannot\n\
+                    contain spaces or control characters"
+                )
+                .long("id-suffix")
+                .value_name("STR")
+                .value_parser(id_affix)
+        )
+        .arg(
+            Arg::new("header_format")
+                .help("template of the amplicon fasta headers")
+                .long_help(
+                    "Writes the description of the amplicon fasta headers from\n\
+                    a template instead of region=... forward=... reverse=...\n\
+                    Placeholders {id}, {region}, {fwd}, {rev}, {start}, {end},\n\
+                    {fwd_mismatch} and {rev_mismatch} are replaced by their\n\
+                    value, coordinates being 1-based as in the GFF3 file, e.g.\n\
+                    \"region={region};start={start};end={end}\""
+                )
+                .long("header-format")
+                .value_name("STR")
+                .value_parser(header_format)
+        )
+        .arg(
+            Arg::new("resume_from")
+                .help("skip records preceding record ID")
+                .long_help(
+                    "Skips the input records preceding the record ID, which is\n\
+                    the first one processed. Useful to restart an interrupted\n\
+                    run with --append"
+                )
+                .long("resume-from")
+                .value_name("ID")
+        )
+        .arg(
+            Arg::new("append")
+                .help("add amplicons to existing outputs")
+                .long_help(
+                    "Adds amplicons to the end of existing fasta and GFF output\n\
+                    files, trimmed amplicons of --emit-both included, instead\n\
+                    of refusing to overwrite them. Other outputs are still\n\
+                    replaced with --force only. Compressed and JSON outputs can\n\
+                    not be appended to"
+                )
+                .long("append")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("emit_both")
+                .help("write untrimmed and trimmed amplicons")
+                .long_help(
+                    "Writes untrimmed amplicons to the main output and the same\n\
+                    amplicons without primers to PREFIX.trimmed.fa, as set by\n\
+                    --trim or with both primers removed when --trim is none.\n\
+                    Trimmed amplicons are written with their own coordinates\n\
+                    and annotation files, in the same format, compression and\n\
+                    split by region as the main output"
+                )
+                .long("emit-both")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("strand_agreement")
+                .help("require primers hits to agree on both strands")
+                .long_help(
+                    "Also searches primers on the minus strand and only extracts\n\
+                    amplicons whose primers are found at the same place, up to\n\
+                    N bases, on both strands. Primers with several equally good\n\
+                    hits are otherwise placed on the leftmost forward primer hit\n\
+                    and the rightmost reverse primer hit. Disagreements are\n\
+                    reported as warnings [default: 0]"
+                )
+                .long("require-strand-agreement")
+                .value_name("N")
+                .num_args(0..=1)
+                .default_missing_value("0")
+                .value_parser(value_parser!(usize))
+                .conflicts_with("auto_window")
+        )
+        .arg(
+            Arg::new("strand")
+                .help("strands searched: plus, minus or both")
+                .long_help(
+                    "Strands of the records searched for primers. plus only\n\
+                    searches records as read, minus only their reverse\n\
+                    complement and both searches the reverse complement of\n\
+                    records whose primers are not found in order as read"
+                )
+                .long("strand")
+                .value_name("STR")
+                .value_parser(["plus", "minus", "both"])
+                .default_value("both")
+        )
+        .arg(
+            Arg::new("auto_window")
+                .help("search built-in primers near their expected site")
+                .long_help(
+                    "Only searches built-in primers within 200 bases of their\n\
+                    expected binding site in Escherichia coli numbering,\n\
+                    avoiding spurious hits elsewhere in full-length 16S\n\
+                    sequences. Custom primers are searched in the whole\n\
+                    sequence. Coordinates stay relative to the sequence start"
+                )
+                .long("auto-window")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("max_hits")
+                .help("stop primer search after N hits")
+                .long_help(
+                    "Stops searching a primer in a sequence after N hits, the\n\
+                    best hit among them being kept. This bounds runtime on\n\
+                    repetitive sequences with many near matches, at the risk of\n\
+                    missing a better hit further in the sequence. Only applies\n\
+                    to the myers aligner"
+                )
+                .long("max-hits-per-primer")
+                .value_name("N")
+                .value_parser(value_parser!(u64).range(1..))
+        )
+        .arg(
+            Arg::new("anchor_3prime")
+                .help("reject primer hits with mismatches in the last N bases")
+                .long_help(
+                    "Rejects primer hits with a mismatch in the N bases at the\n\
+                    3' end of the primer, where mismatches hinder PCR the\n\
+                    most. Mismatches allowed by --mismatch are then only\n\
+                    accepted upstream of these bases"
+                )
+                .long("anchor-3prime")
+                .value_name("N")
+                .value_parser(value_parser!(u64).range(1..))
+        )
+        .arg(
+            Arg::new("cache")
+                .help("cache primer hits of N distinct sequences")
+                .long_help(
+                    "Caches primer hits of up to N distinct sequences so that\n\
+                    identical sequences are only searched once. Useful on\n\
+                    redundant datasets. Each thread has its own cache, so up\n\
+                    to threads × N sequences are kept in memory"
+                )
+                .long("cache")
+                .value_name("N")
+                .value_parser(value_parser!(usize))
+        )
+        .arg(
+            Arg::new("aligner")
+                .help("primer search algorithm")
+                .long_help(
+                    "Specifies the primer search algorithm. `myers` counts all\n\
+                    edits equally and is the fastest. `sw` uses a semi-global\n\
+                    alignment with affine gap costs which better handles\n\
+                    indels, e.g. in homopolymers, at a speed cost. In both cases\n\
+                    a hit is kept if its number of edits is within --mismatch"
+                )
+                .long("aligner")
+                .value_name("STR")
+                .value_parser(["myers", "sw"])
+                .default_value("myers")
+        )
+        .arg(
+            Arg::new("mismatch_penalty")
+                .help("sw mismatch penalty")
+                .long("mismatch-penalty")
+                .value_name("N")
+                .value_parser(value_parser!(i32).range(0..))
+                .default_value("1")
+        )
+        .arg(
+            Arg::new("gap_open")
+                .help("sw gap opening penalty")
+                .long("gap-open")
+                .value_name("N")
+                .value_parser(value_parser!(i32).range(0..))
+                .default_value("2")
+        )
+        .arg(
+            Arg::new("gap_extend")
+                .help("sw gap extension penalty")
+                .long("gap-extend")
+                .value_name("N")
+                .value_parser(value_parser!(i32).range(0..))
+                .default_value("1")
+        )
+        .arg(
+            Arg::new("threads")
+                .help("number of threads")
+                .long_help(
+                    "Specifies the number of threads searching primers. 0 uses\n\
+                    all available cores. With several threads, output order is\n\
+                    set by --keep-order. One thread searches records in turn\n\
+                    without any thread pool"
+                )
+                .short('t')
+                .long("threads")
+                .value_name("N")
+                .value_parser(value_parser!(u64))
                 .default_value("0")
         )
+        .arg(
+            Arg::new("keep_order")
+                .help("write amplicons in input order [default]")
+                .long_help(
+                    "Writes amplicons in the order of the input records when\n\
+                    using several threads. Amplicons of records searched ahead\n\
+                    of a slow record are buffered until it is written. This is\n\
+                    the default behavior"
+                )
+                .long("keep-order")
+                .overrides_with("no_keep_order")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no_keep_order")
+                .help("write amplicons as soon as found")
+                .long_help(
+                    "Writes amplicons as soon as they are extracted when using\n\
+                    several threads, for maximum throughput. Output records\n\
+                    may then not follow the input order, nor be ordered the\n\
+                    same way across runs"
+                )
+                .long("no-keep-order")
+                .overrides_with("keep_order")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("prefix")
                 .help("prefix of output files")
@@ -90,21 +845,321 @@ pub fn build_app() -> Command {
                 .value_name("PATH")
                 .default_value("hyperex_out"),
         )
+        .arg(
+            Arg::new("output_dir")
+                .help("directory of output files")
+                .long_help(
+                    "Writes output files to DIR, created with its parents if\n\
+                    missing. The prefix is then relative to DIR, e.g.\n\
+                    `--output-dir results -p sample` writes\n\
+                    results/sample.fa"
+                )
+                .short('o')
+                .long("output-dir")
+                .value_name("DIR"),
+        )
+        .arg(
+            Arg::new("consensus")
+                .help("write per-region IUPAC consensus")
+                .long_help(
+                    "Writes the column-wise IUPAC consensus of the extracted\n\
+                    amplicons of each region to PREFIX.consensus.fa. No alignment\n\
+                    is performed so a region is only reported when all its\n\
+                    amplicons have the same length"
+                )
+                .long("consensus")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("list_regions")
+                .help("list regions and their primers, then exit")
+                .long_help(
+                    "Prints built-in regions, and regions of --regions-def, with\n\
+                    their primers and secondary structure scores as a TSV table,\n\
+                    then exits. hairpin is the longest hairpin stem of either\n\
+                    primer, self_dimer the longest run of paired bases of a\n\
+                    primer with itself and pair_dimer between both primers.\n\
+                    Hairpins of 4 bp and dimers of 5 bp or more are listed in\n\
+                    the issues column. Such user primers are also reported with\n\
+                    a warning when extracting. The last columns give the names\n\
+                    of built-in primers"
+                )
+                .long("list-regions")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("primer_report")
+                .help("report built-in primers matches instead of extracting")
+                .long_help(
+                    "Reports, for each built-in primer, the number of input\n\
+                    records it matches within --mismatch and the mean edit\n\
+                    distance of its hits to PREFIX.primers.tsv. No region is\n\
+                    extracted. Useful to choose the regions best suited to a\n\
+                    dataset"
+                )
+                .long("primer-report")
+                .conflicts_with_all(["forward_primer", "reverse_primer", "region", "region_file"])
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("concat_regions")
+                .help("also write each record regions concatenated")
+                .long_help(
+                    "Also writes, for each record, the amplicons of all the\n\
+                    requested regions concatenated in the requested order to\n\
+                    PREFIX.concat.fa"
+                )
+                .long("concat-regions")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("concat_spacer")
+                .help("sequence inserted between concatenated regions")
+                .long("concat-spacer")
+                .requires("concat_regions")
+                .value_name("STR")
+                .default_value("")
+                .hide_default_value(true)
+        )
+        .arg(
+            Arg::new("concat_policy")
+                .help("handling of records missing regions [default: skip]")
+                .long_help(
+                    "Specifies how records in which some regions were not\n\
+                    extracted are concatenated. `skip` leaves them out of\n\
+                    PREFIX.concat.fa while `pad` leaves the missing regions\n\
+                    empty, only their spacers being written, and lists them\n\
+                    as missing in the fasta header"
+                )
+                .long("concat-policy")
+                .requires("concat_regions")
+                .value_name("STR")
+                .value_parser(["skip", "pad"])
+                .default_value("skip")
+                .hide_default_value(true)
+        )
+        .arg(
+            Arg::new("presence_only")
+                .help("only write which regions are found in each record")
+                .long_help(
+                    "Writes, instead of the amplicons and their annotations,\n\
+                    a PREFIX.presence.tsv matrix with one row per record and\n\
+                    one column per region telling whether the region was\n\
+                    extracted from the record"
+                )
+                .long("presence-only")
+                .conflicts_with_all(["split_dir", "split_by_region", "append", "compress", "primer_report"])
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("presence_value")
+                .help("values of the presence matrix [default: binary]")
+                .long_help(
+                    "Specifies the values of the presence matrix. `binary`\n\
+                    writes 1 for extracted regions and 0 otherwise while\n\
+                    `mismatch` writes the lowest total number of primer\n\
+                    mismatches of the region amplicons, or NA"
+                )
+                .long("presence-value")
+                .requires("presence_only")
+                .value_name("STR")
+                .value_parser(["binary", "mismatch"])
+                .default_value("binary")
+                .hide_default_value(true)
+        )
+        .arg(
+            Arg::new("split_dir")
+                .help("write each region in its own subdirectory")
+                .long_help(
+                    "Writes the fasta and gff files of each region in a\n\
+                    subdirectory named after the region, next to the prefix.\n\
+                    With `-p out/sample`, v4 amplicons are written to\n\
+                    out/v4/sample.fa and out/v4/sample.gff. Regions without\n\
+                    name are written to the `unknown` subdirectory"
+                )
+                .long("split-dir")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("split_by_region")
+                .help("write each region in its own files")
+                .long_help(
+                    "Writes the fasta and gff files of each region next to the\n\
+                    prefix, named after the region. With `-p out/sample`, v4\n\
+                    amplicons are written to out/sample.v4.fa and\n\
+                    out/sample.v4.gff. Regions without name are written to\n\
+                    out/sample.unknown.fa"
+                )
+                .long("split-by-region")
+                .conflicts_with("split_dir")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("check")
+                .help("check inputs and primers without extracting")
+                .long_help(
+                    "Checks the primers, the mismatch and the first record of\n\
+                    each input file, then prints a summary and exits without\n\
+                    writing or overwriting any output file"
+                )
+                .long("check")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("force")
                 .help("overwrite output")
                 .long("force")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("strict")
+                .help("fail on input without sequences")
+                .long_help(
+                    "Exits with an error, instead of a warning, when the input\n\
+                    has no sequences. Outputs are still written, empty"
+                )
+                .long("strict")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dump_debug")
+                .help("log amplicon slice coordinates")
+                .long_help(
+                    "Logs, for each candidate amplicon, the record id, primer\n\
+                    coordinates and slice bounds before extraction. Useful to\n\
+                    report crashes on specific records"
+                )
+                .long("dump-debug")
+                .hide(true)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("warn_file")
+                .help("write warnings to a file")
+                .long_help(
+                    "Appends warnings to FILE instead of printing them with the\n\
+                    other messages"
+                )
+                .long("warn-file")
+                .value_name("FILE")
+                .global(true)
+        )
+        .arg(
+            Arg::new("log_file")
+                .help("file the messages are logged to")
+                .long("log-file")
+                .value_name("FILE")
+                .default_value("hyperex.log")
+                .global(true)
+        )
+        .arg(
+            Arg::new("no_log_file")
+                .help("do not log messages to a file")
+                .long("no-log-file")
+                .conflicts_with("log_file")
+                .global(true)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("progress")
+                .help("show the number of records read")
+                .long_help(
+                    "Shows the number of records read on stderr while\n\
+                    extracting. Only shown when stderr is a terminal and\n\
+                    without --quiet"
+                )
+                .long("progress")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("quiet")
                 .long_help("decreases program verbosity")
+                .global(true)
                 .short('q')
                 .long("quiet")
                 .action(ArgAction::SetTrue),
         )
 }
 
+/// Parse the command-line arguments, exiting on options that can not be
+/// combined with some values of other options, which clap can not declare
+pub fn get_matches() -> ArgMatches {
+    let mut app = build_app();
+    let matches = app.get_matches_mut();
+    if let Some(message) = value_conflict(&matches) {
+        app.error(ErrorKind::ArgumentConflict, message).exit();
+    }
+
+    matches
+}
+
+// Conflict between an option and a value of another option
+fn value_conflict(matches: &ArgMatches) -> Option<&'static str> {
+    let format = matches.get_one::<String>("format").map(|x| x.as_str());
+    if matches.get_flag("append") && format == Some("json") {
+        return Some("the argument '--append' cannot be used with '--format json', a JSON array can not be appended to");
+    }
+
+    None
+}
+
+// Check that text added to record ids keeps them a single fasta and GFF
+// field
+fn id_affix(value: &str) -> Result<String, String> {
+    if value.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        Err("record ids cannot contain spaces or control characters"
+            .to_string())
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+// Check that the placeholders of a fasta header template are known and that
+// the header stays on one line
+fn header_format(value: &str) -> Result<String, String> {
+    if value.chars().any(|c| c.is_control()) {
+        return Err("headers cannot contain control characters".to_string());
+    }
+    let mut rest = value;
+    while let Some(open) = rest.find('{') {
+        rest = &rest[open + 1..];
+        let close = rest
+            .find('}')
+            .ok_or_else(|| "unclosed { in header template".to_string())?;
+        if !HEADER_FIELDS.contains(&&rest[..close]) {
+            return Err(format!(
+                "unknown placeholder {{{}}}, expected one of {}",
+                &rest[..close],
+                HEADER_FIELDS.join(", ")
+            ));
+        }
+        rest = &rest[close + 1..];
+    }
+
+    Ok(value.to_string())
+}
+
+// Parse the allowed mismatch of the forward and reverse primers, a single
+// value being used for both
+fn mismatch(value: &str) -> Result<(u8, u8), String> {
+    let parse = |x: &str| {
+        x.parse::<u8>()
+            .map_err(|_| format!("{} is not a number of mismatch", x))
+    };
+    match value.split_once(',') {
+        Some((forward, reverse)) => Ok((parse(forward)?, parse(reverse)?)),
+        None => parse(value).map(|x| (x, x)),
+    }
+}
+
+// Parse a fraction between 0 and 1
+fn fraction(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(x) if (0.0..=1.0).contains(&x) => Ok(x),
+        _ => Err(format!("{} is not a number between 0 and 1", value)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -113,4 +1168,67 @@ mod tests {
     fn verify_cmd() {
         build_app().debug_assert();
     }
+
+    #[test]
+    fn test_value_conflict() {
+        let matches = |args: &[&str]| {
+            build_app()
+                .try_get_matches_from(
+                    ["hyperex"].iter().chain(args).collect::<Vec<_>>(),
+                )
+                .unwrap()
+        };
+        assert!(value_conflict(&matches(&["--append", "--format", "json"]))
+            .is_some());
+        assert!(value_conflict(&matches(&["--append", "--format", "bed"]))
+            .is_none());
+        assert!(value_conflict(&matches(&["--format", "json"])).is_none());
+        // Compressed outputs conflict with --append as declared to clap
+        assert_eq!(
+            build_app()
+                .try_get_matches_from([
+                    "hyperex",
+                    "--append",
+                    "--compress",
+                    "gz"
+                ])
+                .unwrap_err()
+                .kind(),
+            ErrorKind::ArgumentConflict
+        );
+    }
+
+    #[test]
+    fn test_fraction() {
+        assert_eq!(fraction("0.25"), Ok(0.25));
+        assert_eq!(fraction("1"), Ok(1.0));
+        assert!(fraction("1.5").is_err());
+        assert!(fraction("-0.1").is_err());
+        assert!(fraction("x").is_err());
+    }
+
+    #[test]
+    fn test_mismatch() {
+        assert_eq!(mismatch("2"), Ok((2, 2)));
+        assert_eq!(mismatch("0,3"), Ok((0, 3)));
+        assert!(mismatch("1,").is_err());
+        assert!(mismatch("1,2,3").is_err());
+        assert!(mismatch("x").is_err());
+    }
+
+    #[test]
+    fn test_header_format() {
+        let template = "region={region};start={start};end={end}";
+        assert_eq!(header_format(template), Ok(template.to_string()));
+        assert!(header_format("{sample}").is_err());
+        assert!(header_format("{region").is_err());
+        assert!(header_format("a\tb").is_err());
+    }
+
+    #[test]
+    fn test_id_affix() {
+        assert_eq!(id_affix("sample1_"), Ok("sample1_".to_string()));
+        assert!(id_affix("sample 1").is_err());
+        assert!(id_affix("sample\t1").is_err());
+    }
 }
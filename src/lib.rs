@@ -0,0 +1,31 @@
+// Copyright 2021-2024 Anicet Ebou.
+// Licensed under the MIT license (http://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+//! Extraction of 16S rRNA hypervariable regions based on primer sequences.
+//!
+//! The hyperex command line tool is built on this crate, which can also be
+//! used to extract regions within a Rust program:
+//!
+//! ```
+//! use hyperex::{extract_regions, region_to_primer};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let fasta = b">seq1\nGTGCCAGCAGCCGCGGTAAACGTACGTACATTAGATACCCTGGTAGTCC\n";
+//! let primers = vec![region_to_primer("v4")?];
+//!
+//! let hits = extract_regions(&fasta[..], &primers, 0)?;
+//! assert_eq!(hits.len(), 1);
+//! assert_eq!(hits[0].id, "seq1");
+//! assert_eq!(hits[0].region, "v4");
+//! assert_eq!((hits[0].start, hits[0].end, hits[0].strand), (0, 49, '+'));
+//! # Ok(())
+//! # }
+//! ```
+
+// Internals of the command line tool, which may change in any release
+#[doc(hidden)]
+pub mod utils;
+
+pub use utils::{extract_regions, region_to_primer, RegionHit};
@@ -4,17 +4,18 @@
 // to those terms.
 
 mod app;
-mod utils;
 
-use bio::io::fasta;
+use anyhow::Context;
 use clap::crate_version;
+use hyperex::utils;
 use log::{error, info, warn};
 
 use std::env;
 use std::fs;
 use std::io::{self, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::thread;
 use std::time::Instant;
 
 fn main() -> anyhow::Result<()> {
@@ -24,77 +25,161 @@ fn main() -> anyhow::Result<()> {
     let mut ehandle = stderr.lock();
 
     // Get command-line arguments (see app.rs)
-    let matches = app::build_app().get_matches_from(env::args_os());
+    let matches = app::get_matches();
 
     // is --quiet option specified by the user?
     let quiet = matches.get_flag("quiet");
-    utils::setup_logging(quiet)?; // Settting up logging
+    // Benchmark results and listed regions are printed to stdout
+    let benchmark = matches.subcommand_matches("benchmark");
+    let list_regions = matches.get_flag("list_regions");
+    let warn_file = matches.get_one::<String>("warn_file").map(Path::new);
+    let log_file = if matches.get_flag("no_log_file") {
+        None
+    } else {
+        matches.get_one::<String>("log_file").map(Path::new)
+    };
+    utils::setup_logging(
+        quiet,
+        benchmark.is_some() || list_regions,
+        warn_file,
+        log_file,
+    )?; // Settting up logging
+
+    // Ranking built-in regions is a run mode of its own
+    if let Some(matches) = benchmark {
+        let infile = matches.get_one::<String>("FILE").unwrap();
+        let options = utils::Options {
+            mismatch: *matches.get_one("mismatch").unwrap(),
+            ..Default::default()
+        };
+        let primers = utils::builtin_regions()
+            .iter()
+            .map(|x| utils::region_to_primer(x).unwrap())
+            .collect::<Vec<_>>();
+        let benchmarks = utils::benchmark_regions(infile, &primers, &options)?;
+        utils::write_benchmark(&mut io::stdout().lock(), &benchmarks)?;
+        return Ok(());
+    }
+
+    // User-defined regions augment the built-in ones
+    let region_defs = match matches.get_one::<String>("regions_def") {
+        Some(file) => utils::load_region_defs(file)
+            .with_context(|| format!("Cannot read regions from {}", file))?,
+        None => utils::RegionDefs::default(),
+    };
+    let custom_regions = &region_defs.primers;
+
+    if list_regions {
+        utils::write_regions(&mut io::stdout().lock(), custom_regions)?;
+        return Ok(());
+    }
 
     // Reading input data
-    // This can be a piped data or a filename
-    // So we match the value to '-' or some other value and read it
-    let infile = match matches.get_one::<String>("FILE") {
-        // Read from file if passed arg is not '-', otherwise read from stdin
-        Some(value) => {
-            if value == "-" {
-                let mut writer = fasta::Writer::to_file("infile.fa")?;
-                let mut records = fasta::Reader::new(io::stdin()).records();
-                while let Some(Ok(record)) = records.next() {
-                    writer.write_record(&record)?;
-                }
-                "infile.fa"
-            } else {
-                value
-            }
-        }
-        // Read from STDIN
-        None => {
-            let mut writer = fasta::Writer::to_file("infile.fa")?;
-            let mut records = fasta::Reader::new(io::stdin()).records();
-            while let Some(Ok(record)) = records.next() {
-                writer.write_record(&record)?;
-            }
-            "infile.fa"
-        }
+    // This can be a piped data or filenames
+    // So we match the values to '-' or some other value and read them.
+    // Piped data is copied, raw bytes being kept for fastq and compressed
+    // data, to a temporary file removed once done
+    let values = matches
+        .get_many::<String>("FILE")
+        .map(|values| values.map(|v| v.as_str()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let stdin_file = if values.is_empty() || values.contains(&"-") {
+        let mut file = tempfile::NamedTempFile::new()
+            .with_context(|| "Cannot create a temporary file for stdin")?;
+        io::copy(&mut io::stdin(), &mut file)?;
+        Some(file)
+    } else {
+        None
+    };
+    let stdin_path = match &stdin_file {
+        Some(file) => Some(
+            file.path()
+                .to_str()
+                .with_context(|| "Temporary file path is not valid UTF-8")?,
+        ),
+        None => None,
     };
+    let infiles = if values.is_empty() {
+        stdin_path.into_iter().collect::<Vec<_>>()
+    } else {
+        values
+            .iter()
+            .map(|&x| if x == "-" { stdin_path.unwrap() } else { x })
+            .collect::<Vec<_>>()
+    };
+    let infile = infiles[0];
 
-    // Check that the supplied file exists
-    if infile != "infile.fa" {
-        match Path::new(infile).exists() {
-            true => (),
-            false => {
-                writeln!(ehandle, "error: No such file or directory. Is the path correct? Do you have permission to read the file?")?;
-                process::exit(1);
-            }
-        }
+    // Check that the supplied files exist
+    if infiles.iter().any(|x| !Path::new(x).exists()) {
+        writeln!(ehandle, "error: No such file or directory. Is the path correct? Do you have permission to read the file?")?;
+        process::exit(1);
+    }
+
+    // Records, or outputs with --split, of the input files are told apart
+    // by their sample name
+    let mut samples = infiles
+        .iter()
+        .map(|x| (utils::sample_name(x), *x))
+        .collect::<Vec<_>>();
+    samples.sort();
+    if let Some(pair) = samples.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        writeln!(
+            ehandle,
+            "error: Input files {} and {} have the same sample name {}",
+            pair[0].1, pair[1].1, pair[0].0
+        )?;
+        process::exit(1);
     }
 
-    // Read prefix for output files
+    // Read prefix for output files, placed in the output directory if any
+    let output_dir = matches.get_one::<String>("output_dir");
     let prefix = matches.get_one::<String>("prefix").unwrap();
+    let prefix = &match output_dir {
+        Some(dir) => Path::new(dir).join(prefix).to_string_lossy().to_string(),
+        None => prefix.clone(),
+    };
     let force = matches.get_flag("force");
-    if !force {
-        if Path::new(format!("{}.fa", prefix).as_str()).exists()
-            || Path::new(format!("{}.gff", prefix).as_str()).exists()
-        {
-            writeln!(std::io::stderr(), "error: file already exists. Please change it using --prefix option or use --force to overwrite it")?;
-            process::exit(1);
-        }
-    } else if force {
-        fs::remove_file(format!("{}.fa", prefix).as_str())?;
-        fs::remove_file(format!("{}.gff", prefix).as_str())?;
+    // Inputs and primers are only checked, nothing being written
+    let check = matches.get_flag("check");
+    let split_dir = matches.get_flag("split_dir");
+    let split_by_region = matches.get_flag("split_by_region");
+    let primer_report = matches.get_flag("primer_report");
+    let report_file = format!("{}.primers.tsv", prefix);
+    if primer_report && infiles.len() > 1 {
+        writeln!(ehandle, "error: --primer-report reads a single input file")?;
+        process::exit(1);
     }
-
+    // With --split, each input file has its own outputs
+    let split = matches.get_flag("split");
+    let prefixes = if split {
+        infiles
+            .iter()
+            .map(|x| format!("{}.{}", prefix, utils::sample_name(x)))
+            .collect::<Vec<_>>()
+    } else {
+        vec![prefix.clone()]
+    };
     // Get primers from command-line as a list of primer can be specified
-    let mut primers: Vec<Vec<String>> = Vec::new();
-    let all = [
-        "v1v2", "v1v3", "v1v9", "v3v4", "v3v5", "v4", "v4v5", "v5v7", "v6v9",
-        "v7v9",
-    ];
+    let mut primers: Vec<Vec<String>>;
+    let all = utils::builtin_regions();
+    // Primer sequences supplied by the user, rather than taken from regions
+    let mut user_primers = false;
+
+    // Environment variables supply primers when none are given on the
+    // command-line. HYPEREX_PRIMERS takes precedence over HYPEREX_REGION
+    let env_primers = env::var("HYPEREX_PRIMERS")
+        .ok()
+        .filter(|x| !x.trim().is_empty());
+    let env_regions = env::var("HYPEREX_REGION")
+        .ok()
+        .filter(|x| !x.trim().is_empty());
 
     // Case the user go for -f and -r options
-    if matches.get_flag("forward_primer") && primers.is_empty() {
+    if matches.contains_id("forward_primer")
+        || matches.contains_id("reverse_primer")
+    {
         // Read supplied forward and reverse primers
-        let first: Vec<&str> = matches
+        let mut first: Vec<&str> = matches
             .get_many::<String>("forward_primer")
             .unwrap_or_default()
             .map(|v| v.as_str())
@@ -105,96 +190,568 @@ fn main() -> anyhow::Result<()> {
             .map(|v| v.as_str())
             .collect::<Vec<_>>();
 
+        // Reverse primers alone define amplicons from the sequence start,
+        // or from --length bases upstream, to the reverse primer
+        if first.is_empty() {
+            first = vec![""; second.len()];
+        }
+
         // Primers should be in pairs!
-        if (first.len() % 2) == 0 && first.len() != second.len() {
+        if first.len() != second.len() {
             writeln!(ehandle,
-                "Supplied forward and reverse primers are not multiple of 2. Please check specified primers"
+                "Supplied forward and reverse primers are not in pairs. Please check specified primers"
             )?;
             process::exit(1);
         }
 
         // Combine both Vec<String> into Vec<Vec<String>>
         primers = utils::combine_vec(first, second);
+        user_primers = true;
 
-    // Case user goes for --region option
-    } else if matches.get_flag("region") {
-        // Get supplied region names which can be multiple
-        let regions: Vec<&str> = matches
-            .get_many::<String>("region")
-            .unwrap_or_default()
-            .map(|v| v.as_str())
-            .collect::<Vec<_>>();
-
-        // Check if its a file that have been supplied or region name
-        if Path::new(&regions[0]).is_file() {
-            // We will consider in this case that the region name is a file
-            primers = utils::file_to_vec(regions[0]).unwrap();
-        // Check that the region name is supported
-        } else if regions.iter().all(|x| all.contains(x)) {
-            primers = regions
-                .iter()
-                .map(|x| utils::region_to_primer(x).unwrap())
-                .collect::<Vec<_>>();
-        } else {
-            writeln!(ehandle, "Supplied region is not a correct file name nor a supported region name")?;
-            process::exit(1);
+    // Case user goes for --region-file option
+    } else if let Some(region_file) = matches.get_one::<String>("region_file") {
+        primers = match matches
+            .get_one::<String>("primers_format")
+            .map(|x| x.as_str())
+        {
+            // Manifest rows name their region, like user-defined regions
+            Some("qiime") => {
+                utils::parse_qiime_primers(region_file).map(|pairs| {
+                    pairs
+                        .into_iter()
+                        .map(|(name, mut pair)| {
+                            pair.push(name);
+                            pair
+                        })
+                        .collect::<Vec<_>>()
+                })
+            }
+            _ => utils::file_to_vec(region_file),
         }
+        .with_context(|| format!("Cannot read primers from {}", region_file))?;
+        user_primers = true;
+
+    // Case user goes for --region option, or for the HYPEREX_REGION
+    // environment variable
+    } else if matches.contains_id("region")
+        || (env_primers.is_none() && env_regions.is_some())
+    {
+        // Get supplied region names which can be multiple. Values are
+        // always considered as region names, even if a file with the same
+        // name exists
+        let regions: Vec<&str> = match matches.get_many::<String>("region") {
+            Some(values) => values.map(|v| v.as_str()).collect::<Vec<_>>(),
+            // Environment regions are separated by commas or spaces
+            None => env_regions
+                .as_deref()
+                .unwrap_or_default()
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|x| !x.is_empty())
+                .collect::<Vec<_>>(),
+        };
+
+        // Check that the region name is supported, user-defined regions
+        // coming before built-in ones
+        let resolved = regions
+            .iter()
+            .map(|x| match custom_regions.get(*x) {
+                Some(region_primers) => Ok(region_primers.clone()),
+                None => utils::region_to_primer(x),
+            })
+            .collect::<anyhow::Result<Vec<_>>>();
+        primers = match resolved {
+            Ok(primers) => primers,
+            Err(e) => {
+                writeln!(
+                    ehandle,
+                    "Supplied region is not a supported region name ({})",
+                    e
+                )?;
+                process::exit(1);
+            }
+        };
+    // Case user goes for the HYPEREX_PRIMERS environment variable
+    } else if let Some(pairs) = &env_primers {
+        primers = utils::parse_primer_pairs(pairs)
+            .with_context(|| "Cannot read primers from HYPEREX_PRIMERS")?;
+        user_primers = true;
     } else {
-        // Case when no region or primer is supplied, all the built-in regions are
-        // extracted
+        // Case when no region or primer is supplied, all the built-in and
+        // user-defined regions are extracted
         primers = all
             .iter()
             .map(|x| utils::region_to_primer(x).unwrap())
+            .chain(custom_regions.values().cloned())
             .collect::<Vec<_>>();
     }
 
-    let mismatch: u8 = *matches.get_one("mismatch").unwrap();
+    // Primers with characters other than uppercase IUPAC bases would
+    // silently match nothing
+    if let Err(e) = utils::validate_primers(&primers) {
+        writeln!(ehandle, "error: {:#}", e)?;
+        process::exit(1);
+    }
+
+    // Reverse primers supplied as found on the sequence are turned back to
+    // their usual orientation, in which they are reverse complemented when
+    // searched
+    if matches.get_flag("reverse_as_is") {
+        if user_primers {
+            for primer_pair in &mut primers {
+                primer_pair[1] = utils::reverse_complement(&primer_pair[1]);
+            }
+        } else {
+            warn!("--reverse-as-is only applies to supplied primer sequences, region primers are used as usual");
+        }
+    }
+
+    // Existing output files are only overwritten with --force, amplicons
+    // being added to fasta and GFF files with --append. Files written in
+    // region subdirectories depend on the primers. Amplicons of FASTQ input
+    // are written to FASTQ files, the format being told by the first record
+    // as when extracting, whatever the file name
+    let append = matches.get_flag("append");
+    let input_format = match matches
+        .get_one::<String>("input_format")
+        .map(|x| x.as_str())
+    {
+        Some("fasta") => utils::InputFormat::Plain,
+        Some("gz") => utils::InputFormat::Gzip,
+        Some("xz") => utils::InputFormat::Xz,
+        Some("bz2") => utils::InputFormat::Bzip2,
+        Some("zstd") => utils::InputFormat::Zstd,
+        _ => utils::InputFormat::Auto,
+    };
+    // Each input file has its own outputs with --split, all amplicons being
+    // written in the format of the first file otherwise
+    let mut seq_exts = Vec::new();
+    for file in if split { &infiles[..] } else { &infiles[..1] } {
+        match utils::is_fastq_input(file, input_format) {
+            Ok(fastq) => seq_exts.push(if fastq { "fq" } else { "fa" }),
+            Err(e) => {
+                writeln!(ehandle, "error: {:#}", e)?;
+                process::exit(1);
+            }
+        }
+    }
+    let format = match matches.get_one::<String>("format").map(|x| x.as_str()) {
+        Some("bed") => utils::AnnotationFormat::Bed,
+        Some("both") => utils::AnnotationFormat::Both,
+        Some("json") => utils::AnnotationFormat::Json,
+        _ => utils::AnnotationFormat::Gff,
+    };
+    let compression =
+        match matches.get_one::<String>("compress").map(|x| x.as_str()) {
+            Some("gz") => utils::OutputCompression::Gzip,
+            Some("xz") => utils::OutputCompression::Xz,
+            Some("bz2") => utils::OutputCompression::Bzip2,
+            _ => utils::OutputCompression::None,
+        };
+    // A presence matrix replaces the amplicon and annotation files
+    let presence = matches.get_flag("presence_only").then(|| {
+        match matches
+            .get_one::<String>("presence_value")
+            .map(|x| x.as_str())
+        {
+            Some("mismatch") => utils::Presence::Mismatch,
+            _ => utils::Presence::Binary,
+        }
+    });
+    let output_exts = |seq_ext: &str| {
+        if presence.is_some() {
+            vec!["presence.tsv".to_string()]
+        } else {
+            std::iter::once(seq_ext)
+                .chain(format.extensions())
+                .map(|ext| match compression.extension() {
+                    Some(compression_ext) => {
+                        format!("{}.{}", ext, compression_ext)
+                    }
+                    None => ext.to_string(),
+                })
+                .collect::<Vec<_>>()
+        }
+    };
+    // Amplicons without primers are written beside the untrimmed ones, in
+    // the same formats
+    let emit_both = matches.get_flag("emit_both");
+    let output_prefixes = prefixes
+        .iter()
+        .zip(&seq_exts)
+        .flat_map(|(prefix, &seq_ext)| {
+            let trimmed = (emit_both && presence.is_none())
+                .then(|| (format!("{}.trimmed", prefix), seq_ext));
+            std::iter::once((prefix.clone(), seq_ext)).chain(trimmed)
+        })
+        .collect::<Vec<_>>();
+    let mut output_files = if primer_report {
+        vec![PathBuf::from(&report_file)]
+    } else if append && presence.is_none() {
+        Vec::new()
+    } else if split_dir || split_by_region {
+        output_prefixes
+            .iter()
+            .flat_map(|(prefix, seq_ext)| {
+                let region_prefixes = if split_dir {
+                    utils::split_dir_prefixes(prefix, &primers)
+                } else {
+                    utils::split_file_prefixes(prefix, &primers)
+                };
+                let exts = output_exts(seq_ext);
+                region_prefixes.into_iter().flat_map(move |region_prefix| {
+                    exts.iter()
+                        .map(|ext| {
+                            PathBuf::from(format!(
+                                "{}.{}",
+                                region_prefix.display(),
+                                ext
+                            ))
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>()
+    } else {
+        output_prefixes
+            .iter()
+            .flat_map(|(prefix, seq_ext)| {
+                output_exts(seq_ext).into_iter().map(move |ext| {
+                    PathBuf::from(format!("{}.{}", prefix, ext))
+                })
+            })
+            .collect()
+    };
+    let sqlite = matches.get_one::<String>("sqlite").map(PathBuf::from);
+    if let (Some(db), false) = (&sqlite, primer_report) {
+        output_files.push(db.clone());
+    }
+    // Files written beside the amplicons are never appended to
+    let mut sidecar_exts = Vec::new();
+    if matches.get_flag("consensus") {
+        sidecar_exts.push("consensus.fa");
+    }
+    if matches.get_flag("concat_regions") {
+        sidecar_exts.push("concat.fa");
+    }
+    if matches.get_flag("emit_primer_sites") {
+        sidecar_exts.push("sites.fa");
+    }
+    if matches.get_flag("stats_per_position") {
+        sidecar_exts.push("bedgraph");
+    }
+    if matches.contains_id("length_histogram") {
+        sidecar_exts.push("lengths.tsv");
+    }
+    if matches.get_flag("stats") {
+        sidecar_exts.push("stats.tsv");
+    }
+    if matches.get_flag("mask") {
+        sidecar_exts.push("masked.fa");
+    }
+    if !primer_report {
+        for (prefix, seq_ext) in prefixes.iter().zip(&seq_exts) {
+            for ext in &sidecar_exts {
+                output_files.push(PathBuf::from(format!("{}.{}", prefix, ext)));
+            }
+            // Amplicons set aside are written in the format of the input
+            if matches.contains_id("max_ambiguous") {
+                output_files.push(PathBuf::from(format!(
+                    "{}.ambiguous.{}",
+                    prefix, seq_ext
+                )));
+            }
+        }
+    }
+    // Existing files are left untouched when only checking
+    if !check {
+        if let Some(dir) = output_dir {
+            if let Err(e) = fs::create_dir_all(dir) {
+                writeln!(
+                    ehandle,
+                    "error: cannot create output directory {}: {}",
+                    dir, e
+                )?;
+                process::exit(1);
+            }
+        }
+        if let Err(e) = utils::handle_output_files(&output_files, force) {
+            writeln!(ehandle, "error: {:#}", e)?;
+            process::exit(1);
+        }
+    }
+
+    // The same primer pair supplied twice would extract the same amplicons
+    // twice
+    let (unique_primers, duplicates) = utils::dedupe_primers(&primers);
+    if !duplicates.is_empty() {
+        for pair in &duplicates {
+            warn!(
+                "Primer pair {}, {} is supplied more than once",
+                pair[0], pair[1]
+            );
+        }
+        if matches.get_flag("dedupe_primers") {
+            primers = unique_primers;
+        }
+    }
+
+    let min_length = matches.get_one::<usize>("min_length").copied();
+    let max_length = matches.get_one::<usize>("max_length").copied();
+    if let (Some(min), Some(max)) = (min_length, max_length) {
+        if min > max {
+            writeln!(
+                ehandle,
+                "error: --min-length {} is greater than --max-length {}",
+                min, max
+            )?;
+            process::exit(1);
+        }
+    }
+
+    let (mismatch, reverse_mismatch): (u8, u8) =
+        *matches.get_one("mismatch").unwrap();
+    let matcher = match matches.get_one::<String>("aligner").map(|x| x.as_str())
+    {
+        Some("sw") => utils::Matcher::SmithWaterman(utils::Penalties {
+            mismatch: *matches.get_one::<i32>("mismatch_penalty").unwrap(),
+            gap_open: *matches.get_one::<i32>("gap_open").unwrap(),
+            gap_extend: *matches.get_one::<i32>("gap_extend").unwrap(),
+        }),
+        _ => utils::Matcher::Myers,
+    };
+    let options = utils::Options {
+        mismatch,
+        reverse_mismatch: Some(reverse_mismatch),
+        consensus: matches.get_flag("consensus"),
+        quality_trim: matches.get_one::<u8>("quality_trim").copied(),
+        dump_debug: matches.get_flag("dump_debug"),
+        cache: matches.get_one::<usize>("cache").copied(),
+        match_seq: matches.get_flag("match_seq"),
+        degenerate_expand: matches.get_flag("degenerate_expand"),
+        degap: matches.get_flag("degap"),
+        only_complete: !matches.get_flag("allow_partial"),
+        group_by_region: matches
+            .get_one::<String>("group_by")
+            .is_some_and(|x| x == "region"),
+        dedup: matches.get_flag("dedup"),
+        matcher,
+        length: matches.get_one::<usize>("length").copied(),
+        split_dir,
+        split_by_region,
+        header_format: matches.get_one::<String>("header_format").cloned(),
+        strict: matches.get_flag("strict"),
+        anchor_3prime: matches
+            .get_one::<u64>("anchor_3prime")
+            .map(|&x| x as usize),
+        concat: matches.get_flag("concat_regions").then(|| utils::Concat {
+            spacer: matches
+                .get_one::<String>("concat_spacer")
+                .unwrap()
+                .as_bytes()
+                .to_vec(),
+            policy: match matches
+                .get_one::<String>("concat_policy")
+                .map(|x| x.as_str())
+            {
+                Some("pad") => utils::ConcatPolicy::Pad,
+                _ => utils::ConcatPolicy::Skip,
+            },
+        }),
+        presence,
+        input_format,
+        compression,
+        min_forward_distance: matches
+            .get_one::<usize>("min_forward_distance")
+            .copied(),
+        min_reverse_distance: matches
+            .get_one::<usize>("min_reverse_distance")
+            .copied(),
+        min_length,
+        length_tolerance: matches.get_one::<f64>("length_tolerance").copied(),
+        max_length,
+        gff_types: region_defs.gff_types,
+        coverage: matches.get_flag("stats_per_position"),
+        mask: matches.get_flag("mask"),
+        primer_sites: matches.get_flag("emit_primer_sites"),
+        max_ambiguous: matches.get_one::<f64>("max_ambiguous").copied(),
+        sqlite,
+        trim: match matches.get_one::<String>("trim").map(|x| x.as_str()) {
+            Some("forward") => utils::Trim::Forward,
+            Some("reverse") => utils::Trim::Reverse,
+            Some("both") => utils::Trim::Both,
+            _ if matches.get_flag("trim_primers") => utils::Trim::Both,
+            // Trimmed amplicons are written beside untrimmed ones
+            _ if emit_both => utils::Trim::Both,
+            _ => utils::Trim::None,
+        },
+        emit_both,
+        resume_from: matches.get_one::<String>("resume_from").cloned(),
+        append,
+        strand_agreement: matches.get_one::<usize>("strand_agreement").copied(),
+        strand: match matches.get_one::<String>("strand").map(|x| x.as_str()) {
+            Some("plus") => utils::Strand::Plus,
+            Some("minus") => utils::Strand::Minus,
+            _ => utils::Strand::Both,
+        },
+        auto_window: matches.get_flag("auto_window"),
+        infer_region: matches.get_flag("infer_region"),
+        collect_errors: matches.get_flag("collect_errors"),
+        all_hits: matches.get_flag("all_hits"),
+        max_regions_per_record: matches
+            .get_one::<u64>("max_regions_per_record")
+            .map(|&x| x as usize),
+        format,
+        id_prefix: matches
+            .get_one::<String>("id_prefix")
+            .cloned()
+            .unwrap_or_default(),
+        id_suffix: matches
+            .get_one::<String>("id_suffix")
+            .cloned()
+            .unwrap_or_default(),
+        length_stats: matches.get_flag("stats"),
+        length_bin: matches
+            .get_one::<u64>("length_histogram")
+            .map(|&x| x as usize),
+        threads: match matches.get_one::<u64>("threads") {
+            Some(0) | None => thread::available_parallelism()
+                .map_or(1, |threads| threads.get()),
+            Some(&threads) => threads as usize,
+        },
+        keep_order: !matches.get_flag("no_keep_order"),
+        progress: matches.get_flag("progress") && !quiet,
+        max_hits: matches.get_one::<u64>("max_hits").map(|&x| x as usize),
+    };
 
     // STARTING CORE PROGRAM ------------------------------------------------
     info!("This is hyperex v{}", crate_version!());
     info!("Written by Anicet Ebou");
     info!("Available at https://github.com/Ebedthan/hyperex.git");
     info!("Localtime is {}", chrono::Local::now().format("%H:%M:%S"));
+    // Results can be traced back to the primers which produced them
+    info!(
+        "Built-in primer set {}: {}",
+        utils::primer_set_version(),
+        utils::PRIMER_SET_SOURCE
+    );
+
+    if options.threads > 1 {
+        info!("Searching primers on {} threads", options.threads);
+    }
 
-    if mismatch != 0 {
+    if mismatch != reverse_mismatch {
+        warn!(
+            "You have allowed {} mismatch in the forward primer sequence and {} in the reverse primer sequence",
+            mismatch, reverse_mismatch
+        );
+    } else if mismatch != 0 {
         warn!(
             "You have allowed {} mismatch in the primer sequence",
             mismatch
         );
     }
 
-    if force {
-        warn!("Overwriting {}.fa and {}.gff files", prefix, prefix);
+    if force && !check {
+        warn!(
+            "Overwriting {}.{{{}}} files",
+            prefix,
+            output_exts(seq_exts[0]).join(",")
+        );
     }
 
-    // Check that required number of mismatch is not greater than
-    // the length of the longest primer
-    let cp_primers = primers.clone();
-    let longest_primer_length =
-        cp_primers.into_iter().flatten().map(|x| x.len()).max();
+    // Check that required number of mismatch is not greater than the
+    // length of the longest forward, or reverse, primer. Forward primers
+    // are empty when only searching reverse primers
+    let longest_primer_length = |index: usize| {
+        primers
+            .iter()
+            .map(|pair| pair[index].len())
+            .filter(|&x| x > 0)
+            .max()
+    };
+    let (forward_length, reverse_length) =
+        (longest_primer_length(0), longest_primer_length(1));
 
-    match longest_primer_length {
-        Some(l) => {
-            if mismatch as usize > l {
-                error!("Supplied mismatch is greater that length of primer");
-                error!("Aborting...");
-                process::exit(1);
-            }
+    if forward_length.is_none() && reverse_length.is_none() {
+        error!("No primer sequence detected");
+        error!("Aborting...");
+        process::exit(1);
+    }
+    if forward_length.is_some_and(|l| mismatch as usize > l)
+        || reverse_length.is_some_and(|l| reverse_mismatch as usize > l)
+    {
+        error!("Supplied mismatch is greater that length of primer");
+        error!("Aborting...");
+        process::exit(1);
+    }
+
+    if !primer_report {
+        utils::check_primer_structure(&primers);
+    }
+
+    if check {
+        let mut stdout = io::stdout().lock();
+        writeln!(stdout, "Primer pairs: {}", primers.len())?;
+        for pair in &primers {
+            let forward = if pair[0].is_empty() { "-" } else { &pair[0] };
+            writeln!(stdout, "  {} {}", forward, pair[1])?;
         }
-        None => {
-            error!("No primer sequence detected");
-            error!("Aborting...");
-            process::exit(1);
+        for file in &infiles {
+            if utils::is_tar_archive(file) {
+                writeln!(stdout, "{}: archive, records not checked", file)?;
+                continue;
+            }
+            match utils::check_input(file, options.input_format) {
+                Ok(input) => writeln!(
+                    stdout,
+                    "{}: {}, first record {} is {}",
+                    file,
+                    if input.fastq { "FASTQ" } else { "FASTA" },
+                    input.id,
+                    match input.alphabet {
+                        utils::Alphabet::Dna => "DNA",
+                        utils::Alphabet::Rna => "RNA",
+                    }
+                )?,
+                Err(e) => {
+                    writeln!(ehandle, "error: {:#}", e)?;
+                    process::exit(1);
+                }
+            }
         }
+        writeln!(stdout, "Check passed, no output written")?;
+        return Ok(());
     }
 
-    utils::get_hypervar_regions(infile, primers, prefix, mismatch)?;
-    info!("Done getting hypervariable regions");
+    if primer_report {
+        utils::primer_report(infile, prefix, &options)?;
+        info!("Primer report written to {}", report_file);
+    } else if split {
+        for (file, prefix) in infiles.iter().zip(&prefixes) {
+            info!("Extracting regions of {} to {}", file, prefix);
+            let stats = utils::get_hypervar_regions(
+                file,
+                primers.clone(),
+                prefix,
+                &options,
+            )?;
+            report_stats(&stats, matches.get_flag("stats"))?;
+        }
+    } else {
+        let stats = if infiles.len() > 1 {
+            utils::get_samples_hypervar_regions(
+                &infiles, primers, prefix, &options,
+            )?
+        } else {
+            utils::get_hypervar_regions(infile, primers, prefix, &options)?
+        };
+        report_stats(&stats, matches.get_flag("stats"))?;
+    }
 
     // FINISHING ------------------------------------------------------------
     // Cleaning around
-    if Path::new("infile.fa").exists() {
-        fs::remove_file("infile.fa")?;
+    if let Some(file) = stdin_file {
+        file.close()?;
     }
     let duration = startime.elapsed();
     let y = 60 * 60 * 1000;
@@ -215,3 +772,46 @@ fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+// Log the summary table of an extraction and the records with errors, and
+// print the statistics on request
+fn report_stats(stats: &utils::ExtractionStats, print: bool) -> io::Result<()> {
+    info!("Done getting hypervariable regions");
+    // Summary table of the extraction counts of each region
+    info!("Records read: {}", stats.records);
+    let width = stats
+        .regions
+        .iter()
+        .map(|(region, _)| region.len())
+        .chain(std::iter::once("region".len()))
+        .max()
+        .unwrap_or_default();
+    info!(
+        "{:<width$}  {:>9}  {:>9}",
+        "region",
+        "extracted",
+        "not found",
+        width = width
+    );
+    for (region, counts) in &stats.regions {
+        info!(
+            "{:<width$}  {:>9}  {:>9}",
+            region,
+            counts.extracted,
+            counts.not_found,
+            width = width
+        );
+    }
+    if !stats.errors.is_empty() {
+        warn!("Records with errors:");
+        for line in stats.errors.lines() {
+            warn!("{}", line);
+        }
+    }
+    // Statistics are printed even when quiet
+    if print {
+        utils::write_stats(&mut io::stderr().lock(), stats)?;
+    }
+
+    Ok(())
+}
@@ -4,16 +4,34 @@
 // to those terms.
 
 use anyhow::{anyhow, Context};
-use bio::io::fasta;
-use bio::pattern_matching::myers::MyersBuilder;
+use bio::alignment::distance::levenshtein;
+use bio::alignment::pairwise::Aligner;
+use bio::alignment::AlignmentOperation;
+use bio::io::{fasta, fastq};
+use bio::pattern_matching::myers::{Myers, MyersBuilder};
 use fern::colors::ColoredLevelConfig;
-use log::{error, info, warn};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, info, warn};
 use phf::phf_map;
+use serde::{Deserialize, Serialize};
 
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, Write};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 
-pub fn setup_logging(quiet: bool) -> anyhow::Result<(), fern::InitError> {
+/// Log messages to the console and, unless `log_file` is `None`, to a file
+pub fn setup_logging(
+    quiet: bool,
+    to_stderr: bool,
+    warn_file: Option<&Path>,
+    log_file: Option<&Path>,
+) -> anyhow::Result<(), fern::InitError> {
     let colors = ColoredLevelConfig::default();
     let mut base_config = fern::Dispatch::new();
 
@@ -27,19 +45,29 @@ pub fn setup_logging(quiet: bool) -> anyhow::Result<(), fern::InitError> {
     };
 
     // Separate file config so we can include year, month and day in file logs
-    let file_config = fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{}[{}][{}] {}",
-                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
-                record.target(),
-                record.level(),
-                message
-            ))
-        })
-        .chain(fern::log_file("hyperex.log")?);
+    if let Some(path) = log_file {
+        base_config = base_config.chain(
+            fern::Dispatch::new()
+                .format(|out, message, record| {
+                    out.finish(format_args!(
+                        "{}[{}][{}] {}",
+                        chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+                        record.target(),
+                        record.level(),
+                        message
+                    ))
+                })
+                .chain(fern::log_file(path)?),
+        );
+    }
 
+    // Console logs go to stderr when stdout carries the results. Warnings
+    // are left out when they have a file of their own
+    let separate_warnings = warn_file.is_some();
     let stdout_config = fern::Dispatch::new()
+        .filter(move |metadata| {
+            !separate_warnings || metadata.level() != log::Level::Warn
+        })
         .format(move |out, message, record| {
             out.finish(format_args!(
                 "[{}][{}] {}",
@@ -48,12 +76,28 @@ pub fn setup_logging(quiet: bool) -> anyhow::Result<(), fern::InitError> {
                 message
             ))
         })
-        .chain(io::stdout());
+        .chain(if to_stderr {
+            fern::Output::from(io::stderr())
+        } else {
+            fern::Output::from(io::stdout())
+        });
 
-    base_config
-        .chain(file_config)
-        .chain(stdout_config)
-        .apply()?;
+    base_config = base_config.chain(stdout_config);
+    if let Some(path) = warn_file {
+        base_config = base_config.chain(
+            fern::Dispatch::new()
+                .filter(|metadata| metadata.level() == log::Level::Warn)
+                .format(|out, message, _| {
+                    out.finish(format_args!(
+                        "{} {}",
+                        chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+                        message
+                    ))
+                })
+                .chain(fern::log_file(path)?),
+        );
+    }
+    base_config.apply()?;
 
     Ok(())
 }
@@ -74,7 +118,12 @@ static PRIMER_TO_REGION: phf::Map<&'static str, &'static str> = phf_map! {
     "GGACTACHVGGGTWTCTAAT" => "v4",
     "CCCCGYCAATTCMTTTRAGT" => "v5",
     "ACGTCATCCCCACCTTCC" => "v7",
-    "TACGGYTACCTTGTTAYGACTT" => "v9"
+    "TACGGYTACCTTGTTAYGACTT" => "v9",
+    "CTTGGTCATTTAGAGGAAGTAA" => "its1",
+    "GCTGCGTTCTTCATCGATGC" => "its1",
+    "TCCTCCGCTTATTGATATGC" => "its2",
+    "CCAGCASCYGCGGTAATTCC" => "18s_v4",
+    "ACTTTCGTTCTTGATYRA" => "18s_v4"
 };
 
 static FORWARD_PRIMERS: phf::Map<&'static str, &'static str> = phf_map! {
@@ -85,6 +134,8 @@ static FORWARD_PRIMERS: phf::Map<&'static str, &'static str> = phf_map! {
     "799F" => "AACMGGATTAGATACCCKG",
     "928F" => "TAAAACTYAAAKGAATTGACGGGG",
     "1100F" => "YAACGAGCGCAACCC",
+    "ITS1F" => "CTTGGTCATTTAGAGGAAGTAA",
+    "TAReuk454FWD1" => "CCAGCASCYGCGGTAATTCC",
 };
 
 static REVERSE_PRIMERS: phf::Map<&'static str, &'static str> = phf_map! {
@@ -96,8 +147,114 @@ static REVERSE_PRIMERS: phf::Map<&'static str, &'static str> = phf_map! {
     "909-928R" => "CCCCGYCAATTCMTTTRAGT",
     "1193R" => "ACGTCATCCCCACCTTCC",
     "1492Rmod" => "TACGGYTACCTTGTTAYGACTT",
+    "ITS2" => "GCTGCGTTCTTCATCGATGC",
+    "ITS4" => "TCCTCCGCTTATTGATATGC",
+    "TAReukREV3" => "ACTTTCGTTCTTGATYRA",
+};
+
+/// Expected 0-based start of the built-in primers binding sites on the 16S
+/// rRNA gene, in Escherichia coli numbering. Reverse primers are given by
+/// the start of their reverse complement
+static PRIMER_POSITIONS: phf::Map<&'static str, usize> = phf_map! {
+    "AGAGTTTGATCMTGGCTCAG" => 7,
+    "CCTACGGGNGGCWGCAG" => 340,
+    "GTGCCAGCMGCCGCGGTAA" => 514,
+    "GTGYCAGCMGCCGCGGTAA" => 514,
+    "AACMGGATTAGATACCCKG" => 798,
+    "TAAAACTYAAAKGAATTGACGGGG" => 904,
+    "YAACGAGCGCAACCC" => 1099,
+    "ACTGCTGCSYCCCGTAGGAGTCT" => 313,
+    "ATTACCGCGGCTGCTGG" => 517,
+    "GACTACHVGGGTATCTAATCC" => 784,
+    "CCGTCAATTYMTTTRAGT" => 908,
+    "GGACTACHVGGGTWTCTAAT" => 786,
+    "CCCCGYCAATTCMTTTRAGT" => 908,
+    "ACGTCATCCCCACCTTCC" => 1175,
+    "TACGGYTACCTTGTTAYGACTT" => 1470
 };
 
+/// Expected length of the built-in 16S rRNA regions, primers included, in
+/// Escherichia coli. Lengths of ITS1 and 18S V4 amplicons vary too much
+/// between taxa to be checked
+static EXPECTED_LENGTHS: phf::Map<&'static str, usize> = phf_map! {
+    "v1v2" => 329,
+    "v1v3" => 527,
+    "v1v9" => 1485,
+    "v3v4" => 465,
+    "v3v5" => 586,
+    "v4" => 292,
+    "v4v5" => 414,
+    "v5v7" => 395,
+    "v6v9" => 588,
+    "v7v9" => 393
+};
+
+/// Number of bases searched on each side of a primer expected binding site
+/// with `auto_window`
+const AUTO_WINDOW_MARGIN: usize = 200;
+
+/// Names of the built-in regions: 16S rRNA regions, the fungal ITS1 and the
+/// V4 region of the eukaryotic 18S rRNA
+pub fn builtin_regions() -> [&'static str; 12] {
+    [
+        "v1v2", "v1v3", "v1v9", "v3v4", "v3v5", "v4", "v4v5", "v5v7", "v6v9",
+        "v7v9", "its1", "18s_v4",
+    ]
+}
+
+/// Built-in primers and their references, as logged with their
+/// [`primer_set_version`]
+pub const PRIMER_SET_SOURCE: &str = "universal 16S rRNA primers, fungal ITS \
+    primers ITS1F, ITS2 and ITS4 and eukaryotic 18S V4 primers TAReuk454FWD1 \
+    and TAReukREV3. References: \
+    27F, 1492R: Lane 1991, Nucleic Acid Techniques in Bacterial Systematics; \
+    341F, 805R: Herlemann et al. 2011, ISME J 5:1571; \
+    515F, 806R: Caporaso et al. 2011, PNAS 108:4516; \
+    515F-Y: Parada et al. 2016, Environ Microbiol 18:1403; \
+    534R: Muyzer et al. 1993, Appl Environ Microbiol 59:695; \
+    799F: Chelius and Triplett 2001, Microb Ecol 41:252; \
+    1193R: Bodenhausen et al. 2013, PLoS ONE 8:e56329; \
+    ITS1F: Gardes and Bruns 1993, Mol Ecol 2:113; \
+    ITS2, ITS4: White et al. 1990, PCR Protocols 315; \
+    TAReuk454FWD1, TAReukREV3: Stoeck et al. 2010, Mol Ecol 19:21";
+
+/// Version of the built-in primers, as a short hash of their names and
+/// sequences and of the primer pair of each built-in region, which changes
+/// whenever a primer is added or edited or a region given other primers.
+/// The hash (64-bit FNV-1a) does not depend on the platform or the Rust
+/// version
+pub fn primer_set_version() -> String {
+    let mut primers = FORWARD_PRIMERS
+        .entries()
+        .chain(REVERSE_PRIMERS.entries())
+        .map(|(name, primer)| format!("{}={}\n", name, primer))
+        .collect::<Vec<_>>();
+    primers.sort_unstable();
+    let regions = builtin_regions()
+        .iter()
+        .map(|region| {
+            let primer_pair = region_to_primer(region).unwrap_or_default();
+            format!("{}={}\n", region, primer_pair.join(","))
+        })
+        .collect::<Vec<_>>();
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for line in primers.into_iter().chain(regions) {
+        for byte in line.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    format!("{:08x}", hash >> 32)
+}
+
+// Regions whose forward and reverse primers are both labelled with the
+// region name
+fn is_single_part_region(region: &str) -> bool {
+    matches!(region, "v4" | "its1" | "18s_v4")
+}
+
 pub fn region_to_primer(region: &str) -> anyhow::Result<Vec<String>> {
     match region {
         "v1v2" => Ok(vec![
@@ -140,20 +297,135 @@ pub fn region_to_primer(region: &str) -> anyhow::Result<Vec<String>> {
             FORWARD_PRIMERS["1100F"].to_string(),
             REVERSE_PRIMERS["1492Rmod"].to_string(),
         ]),
-        _ => Ok(vec!["".to_string()]),
+        "its1" => Ok(vec![
+            FORWARD_PRIMERS["ITS1F"].to_string(),
+            REVERSE_PRIMERS["ITS2"].to_string(),
+        ]),
+        "18s_v4" => Ok(vec![
+            FORWARD_PRIMERS["TAReuk454FWD1"].to_string(),
+            REVERSE_PRIMERS["TAReukREV3"].to_string(),
+        ]),
+        _ => Err(anyhow!("unsupported region: {}", region)),
+    }
+}
+
+/// A region defined by the user, primers being given as sequences or as
+/// built-in primer names. Built-in regions can only be given a GFF type
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegionDef {
+    forward: Option<String>,
+    reverse: Option<String>,
+    gff_type: Option<String>,
+}
+
+/// Regions loaded from a region definition file
+#[derive(Debug, Default, PartialEq)]
+pub struct RegionDefs {
+    /// Primers of each user-defined region, as `[forward, reverse, name]`
+    pub primers: BTreeMap<String, Vec<String>>,
+    /// GFF feature type of user-defined and built-in regions
+    pub gff_types: HashMap<String, String>,
+}
+
+// Resolve a primer given as a built-in primer name or as a sequence
+fn resolve_primer(
+    primer: &str,
+    builtins: &phf::Map<&'static str, &'static str>,
+) -> anyhow::Result<String> {
+    if let Some(sequence) = builtins.get(primer) {
+        return Ok(sequence.to_string());
+    }
+    let sequence = primer.to_ascii_uppercase();
+    if sequence.is_empty() || check_primer(&sequence).is_err() {
+        return Err(anyhow!(
+            "{} is neither a built-in primer name nor a primer sequence",
+            primer
+        ));
+    }
+
+    Ok(sequence)
+}
+
+/// Load user-defined regions from a TOML file of `[name]` tables with
+/// `forward` and `reverse` primers and an optional `gff_type`.
+///
+/// Built-in regions tables can only set the `gff_type` of the region.
+pub fn load_region_defs(filename: &str) -> anyhow::Result<RegionDefs> {
+    let content = fs::read_to_string(filename)?;
+    let defs: BTreeMap<String, RegionDef> = toml::from_str(&content)?;
+    let mut regions = RegionDefs::default();
+
+    for (name, def) in defs {
+        if let Some(gff_type) = def.gff_type {
+            // The type is a single GFF3 column
+            if gff_type.trim().is_empty()
+                || gff_type.contains(|c: char| c.is_control())
+            {
+                return Err(anyhow!("Invalid GFF type of region {}", name));
+            }
+            regions.gff_types.insert(name.clone(), gff_type);
+        }
+
+        let builtin = region_to_primer(&name).is_ok();
+        match (def.forward, def.reverse) {
+            (None, None) if builtin => (),
+            (Some(_), _) | (_, Some(_)) if builtin => {
+                return Err(anyhow!(
+                    "Primers of built-in region {} cannot be redefined",
+                    name
+                ));
+            }
+            (Some(forward), Some(reverse)) => {
+                let primers = vec![
+                    resolve_primer(&forward, &FORWARD_PRIMERS)
+                        .with_context(|| format!("Invalid region {}", name))?,
+                    resolve_primer(&reverse, &REVERSE_PRIMERS)
+                        .with_context(|| format!("Invalid region {}", name))?,
+                    name.clone(),
+                ];
+                regions.primers.insert(name, primers);
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Region {} needs a forward and a reverse primer",
+                    name
+                ));
+            }
+        }
     }
+
+    Ok(regions)
 }
 
 pub fn file_to_vec(filename: &str) -> anyhow::Result<Vec<Vec<String>>> {
     let mut vec: Vec<Vec<String>> = Vec::new();
     let content = fs::read_to_string(filename)?;
-    for line in content.lines() {
+    // Files saved on Windows may start with a byte order mark which would
+    // otherwise end up in the first primer
+    let content = content.strip_prefix('\u{feff}').unwrap_or(&content);
+    for (number, line) in content.lines().enumerate() {
         if line.contains(',') {
-            vec.push(
-                line.split(',')
-                    .map(|s| s.to_string())
-                    .collect::<Vec<String>>(),
-            );
+            let mut fields = line
+                .split(',')
+                .map(|s| s.to_string())
+                .collect::<Vec<String>>();
+            // An optional third column names the region, like user-defined
+            // regions
+            match fields.len() {
+                2 => (),
+                3 if fields[2].is_empty() => {
+                    fields.pop();
+                }
+                3 => (),
+                _ => {
+                    return Err(anyhow!(
+                        "Line {} of the file containing primer sequences has more than 3 columns",
+                        number + 1
+                    ))
+                }
+            }
+            vec.push(fields);
         } else {
             return Err(anyhow!(
                 "File containing primer sequences is not comma separated"
@@ -163,6 +435,76 @@ pub fn file_to_vec(filename: &str) -> anyhow::Result<Vec<Vec<String>>> {
     Ok(vec)
 }
 
+/// Read named primer pairs from a QIIME 2 metadata file.
+///
+/// The file is tab separated, with a header row naming the columns. The
+/// first column identifies each row, here by region name, and the primers
+/// are read from the `forward-primer` and `reverse-primer` columns. Comment
+/// lines and `#q2:types` directives following the header are ignored.
+pub fn parse_qiime_primers(
+    path: &str,
+) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+    let content = fs::read_to_string(path)?;
+    let content = content.strip_prefix('\u{feff}').unwrap_or(&content);
+    let mut lines = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+
+    let header = match lines.next() {
+        Some((_, header)) => {
+            header.split('\t').map(|x| x.trim()).collect::<Vec<_>>()
+        }
+        None => return Err(anyhow!("Primer manifest {} is empty", path)),
+    };
+    let column = |name: &str| {
+        header.iter().position(|x| *x == name).ok_or_else(|| {
+            anyhow!("Primer manifest {} has no {} column", path, name)
+        })
+    };
+    let forward = column("forward-primer")?;
+    let reverse = column("reverse-primer")?;
+
+    let mut pairs = Vec::new();
+    for (number, line) in lines.filter(|(_, line)| !line.starts_with('#')) {
+        let fields = line.split('\t').map(|x| x.trim()).collect::<Vec<_>>();
+        let field = |index: usize| {
+            fields
+                .get(index)
+                .filter(|x| !x.is_empty())
+                .map(|x| x.to_string())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Line {} of primer manifest {} has no {}",
+                        number + 1,
+                        path,
+                        header[index]
+                    )
+                })
+        };
+        pairs.push((field(0)?, vec![field(forward)?, field(reverse)?]));
+    }
+
+    Ok(pairs)
+}
+
+/// Parse primer pairs written as `FORWARD,REVERSE`, pairs being separated by
+/// semicolons or whitespaces
+pub fn parse_primer_pairs(pairs: &str) -> anyhow::Result<Vec<Vec<String>>> {
+    pairs
+        .split(|c: char| c == ';' || c.is_whitespace())
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split(',').collect::<Vec<_>>()[..] {
+            [forward, reverse]
+                if !forward.is_empty() && !reverse.is_empty() =>
+            {
+                Ok(vec![forward.to_string(), reverse.to_string()])
+            }
+            _ => Err(anyhow!("Primer pair {} is not FORWARD,REVERSE", pair)),
+        })
+        .collect()
+}
+
 pub fn combine_vec(first: Vec<&str>, second: Vec<&str>) -> Vec<Vec<String>> {
     first
         .iter()
@@ -171,15 +513,251 @@ pub fn combine_vec(first: Vec<&str>, second: Vec<&str>) -> Vec<Vec<String>> {
         .collect::<Vec<Vec<String>>>()
 }
 
+/// Remove duplicated primer pairs, keeping the first occurrence order.
+///
+/// Returns the unique primer pairs and the pairs found more than once.
+pub fn dedupe_primers(
+    primers: &[Vec<String>],
+) -> (Vec<Vec<String>>, Vec<Vec<String>>) {
+    let mut seen = HashSet::new();
+    let mut unique = Vec::new();
+    let mut duplicates = Vec::new();
+
+    for primer_pair in primers {
+        if seen.insert(primer_pair) {
+            unique.push(primer_pair.clone());
+        } else if !duplicates.contains(primer_pair) {
+            duplicates.push(primer_pair.clone());
+        }
+    }
+
+    (unique, duplicates)
+}
+
+// Symbols of the primers, which are DNA whatever the input alphabet
+const PRIMER_ALPHABET: &str = "ACGTRYSWKMBDHVN";
+
+// Check that a primer only contains uppercase IUPAC DNA symbols
+fn check_primer(primer: &str) -> anyhow::Result<()> {
+    match primer
+        .chars()
+        .enumerate()
+        .find(|(_, c)| !PRIMER_ALPHABET.contains(*c))
+    {
+        Some((position, c)) => Err(anyhow!(
+            "Primer {} contains invalid character '{}' at position {}",
+            primer,
+            c,
+            position + 1
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Check that primers only contain uppercase IUPAC DNA symbols.
+///
+/// The third column of a primer pair is a region name and is not checked,
+/// an empty forward primer standing for reverse-only amplicons.
+pub fn validate_primers(primers: &[Vec<String>]) -> anyhow::Result<()> {
+    for primer in primers.iter().flat_map(|pair| pair.iter().take(2)) {
+        check_primer(primer)?;
+    }
+
+    Ok(())
+}
+
+/// Compression of the input file
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum InputFormat {
+    /// Detected from the first bytes of the file
+    #[default]
+    Auto,
+    /// Uncompressed fasta or fastq
+    Plain,
+    Gzip,
+    Xz,
+    Bzip2,
+    Zstd,
+}
+
+/// Compression of the amplicon and annotation files
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OutputCompression {
+    /// Uncompressed files
+    #[default]
+    None,
+    Gzip,
+    Xz,
+    Bzip2,
+}
+
+impl OutputCompression {
+    /// Extension added to the names of the compressed files
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            OutputCompression::None => None,
+            OutputCompression::Gzip => Some("gz"),
+            OutputCompression::Xz => Some("xz"),
+            OutputCompression::Bzip2 => Some("bz2"),
+        }
+    }
+}
+
 fn read_file(
     filename: &str,
+    format: InputFormat,
 ) -> anyhow::Result<(Box<dyn io::Read>, niffler::compression::Format)> {
     let raw_in = Box::new(io::BufReader::new(File::open(filename)?));
 
-    Ok(niffler::get_reader(raw_in)?)
+    // A forced format bypasses niffler detection
+    Ok(match format {
+        // Files too short to tell their compression, such as empty files,
+        // are read as they are
+        InputFormat::Auto => match niffler::get_reader(raw_in) {
+            Err(niffler::Error::FileTooShort) => (
+                Box::new(io::BufReader::new(File::open(filename)?)),
+                niffler::compression::Format::No,
+            ),
+            reader => reader?,
+        },
+        InputFormat::Plain => (raw_in, niffler::compression::Format::No),
+        InputFormat::Gzip => (
+            Box::new(flate2::read::MultiGzDecoder::new(raw_in)),
+            niffler::compression::Format::Gzip,
+        ),
+        InputFormat::Xz => (
+            Box::new(xz2::read::XzDecoder::new_multi_decoder(raw_in)),
+            niffler::compression::Format::Lzma,
+        ),
+        InputFormat::Bzip2 => (
+            Box::new(bzip2::read::MultiBzDecoder::new(raw_in)),
+            niffler::compression::Format::Bzip,
+        ),
+        InputFormat::Zstd => (
+            Box::new(zstd::stream::read::Decoder::new(raw_in)?),
+            niffler::compression::Format::Zstd,
+        ),
+    })
+}
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// A sequence record read from a FASTA or a FASTQ file
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeqRecord {
+    pub id: String,
+    pub desc: Option<String>,
+    pub seq: Vec<u8>,
+    /// Quality string, only available for FASTQ input
+    pub qual: Option<Vec<u8>>,
+    /// Position in the input sequence of each base, when stray characters
+    /// were removed from it
+    pub positions: Option<Vec<usize>>,
 }
 
-fn primers_to_region(primers: Vec<String>) -> String {
+impl SeqRecord {
+    // Length of the sequence as read
+    fn input_len(&self) -> usize {
+        match &self.positions {
+            Some(positions) => positions.last().map_or(0, |last| last + 1),
+            None => self.seq.len(),
+        }
+    }
+
+    // Coordinates in the input sequence of the end exclusive range
+    // `start..end` of the sequence
+    fn input_range(&self, start: usize, end: usize) -> (usize, usize) {
+        match &self.positions {
+            Some(positions) if start < end => {
+                (positions[start], positions[end - 1] + 1)
+            }
+            _ => (start, end),
+        }
+    }
+}
+
+type SeqRecords<'a> = Box<dyn Iterator<Item = anyhow::Result<SeqRecord>> + 'a>;
+
+// Read FASTA or FASTQ records, the format being detected from the first
+// non-whitespace character of the (decompressed) input
+fn read_records(
+    filename: &str,
+    format: InputFormat,
+) -> anyhow::Result<(SeqRecords<'static>, bool)> {
+    let (reader, _compression) = read_file(filename, format)?;
+
+    records_from_reader(reader)
+}
+
+// Read FASTA or FASTQ records from a decompressed stream. Sequences are
+// uppercased as primers are only matched against uppercase bases
+fn records_from_reader<'a>(
+    reader: Box<dyn io::Read + 'a>,
+) -> anyhow::Result<(SeqRecords<'a>, bool)> {
+    let mut reader = io::BufReader::new(reader);
+
+    // A leading byte order mark would otherwise end up in the first record id
+    if reader.fill_buf()?.starts_with(UTF8_BOM) {
+        reader.consume(UTF8_BOM.len());
+    }
+
+    let is_fastq = loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            break false;
+        }
+        match buffer.iter().position(|x| !x.is_ascii_whitespace()) {
+            Some(i) => break buffer[i] == b'@',
+            None => {
+                let length = buffer.len();
+                reader.consume(length);
+            }
+        }
+    };
+
+    let records: SeqRecords<'a> = if is_fastq {
+        Box::new(fastq::Reader::new(reader).records().map(|record| {
+            let record = record?;
+            Ok(SeqRecord {
+                id: record.id().to_string(),
+                desc: record.desc().map(|x| x.to_string()),
+                seq: record.seq().to_ascii_uppercase(),
+                qual: Some(record.qual().to_vec()),
+                positions: None,
+            })
+        }))
+    } else {
+        Box::new(fasta::Reader::new(reader).records().map(|record| {
+            let record = record?;
+            Ok(SeqRecord {
+                id: record.id().to_string(),
+                desc: record.desc().map(|x| x.to_string()),
+                seq: record.seq().to_ascii_uppercase(),
+                qual: None,
+                positions: None,
+            })
+        }))
+    };
+
+    Ok((records, is_fastq))
+}
+
+/// Length of a read once its low-quality 3' end has been trimmed.
+///
+/// Bases are removed from the 3' end as long as their Phred quality
+/// (Sanger encoding, offset 33) is below `threshold`.
+pub fn quality_trim(qual: &[u8], threshold: u8) -> usize {
+    qual.iter()
+        .rposition(|&q| q.saturating_sub(33) >= threshold)
+        .map_or(0, |i| i + 1)
+}
+
+fn primers_to_region(primers: &[String]) -> String {
+    // User-defined regions carry their name
+    if let Some(name) = primers.get(2).filter(|name| !name.is_empty()) {
+        return name.to_string();
+    }
+
     let mut first_part = "";
     let mut second_part = "";
 
@@ -191,13 +769,63 @@ fn primers_to_region(primers: Vec<String>) -> String {
         second_part = PRIMER_TO_REGION[&primers[1]];
     }
 
-    if first_part == "v4" && second_part == "v4" {
+    if first_part == second_part && is_single_part_region(first_part) {
         first_part.to_string()
     } else {
         format!("{}{}", first_part, second_part)
     }
 }
 
+/// Largest edit distance between a primer and the built-in primer it is
+/// taken for when inferring regions
+const INFER_MAX_DISTANCE: u32 = 2;
+
+// Region of the built-in primer closest to a primer within
+// INFER_MAX_DISTANCE, the first one listed winning ties
+fn closest_primer_region(
+    primer: &str,
+    builtins: &phf::Map<&'static str, &'static str>,
+) -> Option<&'static str> {
+    let mut candidates = builtins
+        .entries()
+        .map(|(name, sequence)| {
+            (
+                levenshtein(primer.as_bytes(), sequence.as_bytes()),
+                *name,
+                *sequence,
+            )
+        })
+        .filter(|&(distance, _, _)| distance <= INFER_MAX_DISTANCE)
+        .collect::<Vec<_>>();
+    candidates.sort();
+
+    candidates
+        .first()
+        .map(|&(_, _, sequence)| PRIMER_TO_REGION[sequence])
+}
+
+/// Region of a primer pair inferred from the closest built-in primers, so
+/// that variants of the built-in primers are labelled
+pub fn infer_region(primers: &[String]) -> Option<String> {
+    let forward = closest_primer_region(&primers[0], &FORWARD_PRIMERS);
+    let reverse = closest_primer_region(&primers[1], &REVERSE_PRIMERS);
+
+    match (forward, reverse) {
+        (Some(forward), Some(reverse))
+            if forward == reverse && is_single_part_region(forward) =>
+        {
+            Some(forward.to_string())
+        }
+        (Some(forward), Some(reverse)) => {
+            Some(format!("{}{}", forward, reverse))
+        }
+        (None, Some(region)) if primers[0].is_empty() => {
+            Some(region.to_string())
+        }
+        _ => None,
+    }
+}
+
 fn to_complement(primer: &str, alphabet: &str) -> String {
     let mut complement = String::new();
 
@@ -254,6 +882,25 @@ fn to_reverse_complement(primer: &str, alphabet: &str) -> String {
     reverse_complement
 }
 
+/// Reverse complement of a DNA primer, IUPAC ambiguities included
+pub fn reverse_complement(primer: &str) -> String {
+    to_reverse_complement(primer, "dna")
+}
+
+// Bases of an RNA sequence read as DNA, primers being DNA
+fn rna_to_dna(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .map(|&x| if x == b'U' { b'T' } else { x })
+        .collect()
+}
+
+// Bases of a DNA sequence written back as RNA
+fn dna_to_rna(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .map(|&x| if x == b'T' { b'U' } else { x })
+        .collect()
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Alphabet {
     Dna,
@@ -264,6 +911,8 @@ pub fn sequence_type(sequence: &str) -> Option<Alphabet> {
     let valid_dna_iupac = "ACGTRYSWKMBDHVN";
     let valid_rna_iupac = "ACGURYSWKMBDHVN";
 
+    let sequence = sequence.to_ascii_uppercase();
+
     if sequence.chars().all(|x| valid_dna_iupac.contains(x)) {
         Some(Alphabet::Dna)
     } else if sequence.chars().all(|x| valid_rna_iupac.contains(x)) {
@@ -273,358 +922,7585 @@ pub fn sequence_type(sequence: &str) -> Option<Alphabet> {
     }
 }
 
-pub fn get_hypervar_regions(
-    file: &str,
-    primers: Vec<Vec<String>>,
-    prefix: &str,
-    mismatch: u8,
-) -> anyhow::Result<()> {
-    let (reader, mut _compression) =
-        read_file(file).with_context(|| "Cannot read file")?;
+/// Settings controlling how hypervariable regions are extracted
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Number of allowed mismatch in primer sequences
+    pub mismatch: u8,
+    /// Number of allowed mismatch in reverse primers, `mismatch` when not
+    /// given
+    pub reverse_mismatch: Option<u8>,
+    /// Write a per-region IUPAC consensus of the extracted amplicons
+    pub consensus: bool,
+    /// Trim FASTQ reads 3' bases with a quality lower than this value
+    pub quality_trim: Option<u8>,
+    /// Log the coordinates of each candidate amplicon before slicing
+    pub dump_debug: bool,
+    /// Number of distinct sequences which primer hits are cached, per
+    /// extraction thread
+    pub cache: Option<usize>,
+    /// Report the sequence matched by each primer in FASTA headers
+    pub match_seq: bool,
+    /// Report the base matched by each primer position in FASTA headers,
+    /// resolving the primers ambiguities
+    pub degenerate_expand: bool,
+    /// Remove alignment gaps from sequences before primer search
+    pub degap: bool,
+    /// Only extract amplicons for which both primers were found
+    pub only_complete: bool,
+    /// Write amplicons grouped by region, in the order of the primers
+    pub group_by_region: bool,
+    /// Leave out amplicons of a region identical to one already written
+    pub dedup: bool,
+    /// Algorithm used to search primers
+    pub matcher: Matcher,
+    /// Amplicon length upstream of the reverse primer end when no forward
+    /// primer is supplied, the sequence start being used otherwise
+    pub length: Option<usize>,
+    /// Stop searching a primer after this number of hits (Myers only)
+    pub max_hits: Option<usize>,
+    /// Write each region outputs in its own subdirectory
+    pub split_dir: bool,
+    /// Write each region outputs in its own files next to the prefix
+    pub split_by_region: bool,
+    /// Template of the amplicon fasta headers, with [`HEADER_FIELDS`]
+    /// placeholders
+    pub header_format: Option<String>,
+    /// Fail instead of warning when the input has no sequences
+    pub strict: bool,
+    /// Number of bases at the 3' end of primers which must match without
+    /// mismatch
+    pub anchor_3prime: Option<usize>,
+    /// Also write, per record, the amplicons of all regions concatenated
+    pub concat: Option<Concat>,
+    /// Only write which regions were extracted from each record, instead of
+    /// the amplicons and their annotations
+    pub presence: Option<Presence>,
+    /// Compression of the input, detected by default
+    pub input_format: InputFormat,
+    /// Compression of the amplicon and annotation files
+    pub compression: OutputCompression,
+    /// Minimum distance from the forward primer start to the reverse primer
+    /// start
+    pub min_forward_distance: Option<usize>,
+    /// Minimum number of bases between the forward primer end and the
+    /// reverse primer start
+    pub min_reverse_distance: Option<usize>,
+    /// Minimum length of the extracted amplicons
+    pub min_length: Option<usize>,
+    /// Warn about amplicons of built-in regions whose length differs from
+    /// the expected one by more than this percentage
+    pub length_tolerance: Option<f64>,
+    /// Maximum length of the extracted amplicons
+    pub max_length: Option<usize>,
+    /// GFF feature type of regions, `region` being used otherwise
+    pub gff_types: HashMap<String, String>,
+    /// Write the number of amplicons covering each position of the records
+    pub coverage: bool,
+    /// Write the records with their extracted regions in lowercase
+    pub mask: bool,
+    /// Write the primer binding sites of each amplicon
+    pub primer_sites: bool,
+    /// Set aside amplicons with a higher fraction of ambiguous bases
+    pub max_ambiguous: Option<f64>,
+    /// Bin size of the per-region histogram of amplicon lengths
+    pub length_bin: Option<usize>,
+    /// Write the count, min, max, mean and median amplicon length of each
+    /// region
+    pub length_stats: bool,
+    /// Primers removed from the extracted amplicons
+    pub trim: Trim,
+    /// Write untrimmed amplicons to the main output and amplicons trimmed
+    /// as set by `trim` to separate outputs of the same formats
+    pub emit_both: bool,
+    /// Only extract amplicons whose primers are found at the same place, up
+    /// to this number of bases, when searched on both strands
+    pub strand_agreement: Option<usize>,
+    /// Strands searched for primers
+    pub strand: Strand,
+    /// Skip the records preceding the record with this id
+    pub resume_from: Option<String>,
+    /// Add amplicons to existing FASTA and GFF outputs
+    pub append: bool,
+    /// Only search built-in primers around their expected binding site
+    pub auto_window: bool,
+    /// Label amplicons of primers close to built-in ones with the region of
+    /// the built-in primers
+    pub infer_region: bool,
+    /// Text added before the record ids in outputs
+    pub id_prefix: String,
+    /// Text added after the record ids in outputs
+    pub id_suffix: String,
+    /// Collect record errors in a summary instead of logging each of them
+    pub collect_errors: bool,
+    /// Annotation files of the amplicon coordinates
+    pub format: AnnotationFormat,
+    /// Extract the amplicons of all primer hits instead of the best ones
+    pub all_hits: bool,
+    /// Largest number of regions written per record
+    pub max_regions_per_record: Option<usize>,
+    /// SQLite database the amplicons are also written to
+    pub sqlite: Option<PathBuf>,
+    /// Number of threads searching primers
+    pub threads: usize,
+    /// Write amplicons in the order of the input records when using several
+    /// threads
+    pub keep_order: bool,
+    /// Show the number of records read on stderr, when it is a terminal
+    pub progress: bool,
+}
 
-    let mut records = fasta::Reader::new(reader).records();
+/// Algorithm used to search primers in sequences
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Matcher {
+    /// Myers bit-parallel search, all edits cost the same
+    #[default]
+    Myers,
+    /// Semi-global alignment with affine gap costs, slower but better suited
+    /// to indel-prone sequences such as homopolymer regions
+    SmithWaterman(Penalties),
+}
 
-    let mut fasta_writer = fasta::Writer::to_file(format!("{}.fa", prefix))?;
-    let gff_file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(format!("{}.gff", prefix))?;
-    let mut gff_writer = io::BufWriter::new(gff_file);
-    gff_writer.write_all(b"##gff-version 3\n")?;
+/// Penalties of the Smith-Waterman primer aligner, a match scoring 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct Penalties {
+    pub mismatch: i32,
+    pub gap_open: i32,
+    pub gap_extend: i32,
+}
 
-    // Build Myers with IUPAC ambiguities in patterns
-    let ambigs = [
-        (b'M', &b"AC"[..]),
-        (b'R', &b"AG"[..]),
-        (b'W', &b"AT"[..]),
-        (b'S', &b"CG"[..]),
-        (b'Y', &b"CT"[..]),
-        (b'K', &b"GT"[..]),
-        (b'V', &b"ACGMRS"[..]),
-        (b'H', &b"ACTMWY"[..]),
-        (b'D', &b"AGTRWK"[..]),
-        (b'B', &b"CGTSYK"[..]),
-        (b'N', &b"ACGTMRWSYKVHDB"[..]),
-    ];
+/// Strands of the records searched for primers
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Strand {
+    /// Records as read only
+    Plus,
+    /// Reverse complement of the records only
+    Minus,
+    /// Reverse complement of the records whose primers are not found in
+    /// order as read
+    #[default]
+    Both,
+}
 
-    let mut builder = MyersBuilder::new();
+/// Primers binding sites removed from the extracted amplicons
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Trim {
+    /// Both primers are kept
+    #[default]
+    None,
+    Forward,
+    Reverse,
+    Both,
+}
 
-    for &(base, equivalents) in &ambigs {
-        builder.ambig(base, equivalents);
+impl Trim {
+    fn forward(self) -> bool {
+        matches!(self, Trim::Forward | Trim::Both)
     }
 
-    while let Some(Ok(record)) = records.next() {
-        let seq = record.seq();
-        let mut alphabet = "";
-        match sequence_type(std::str::from_utf8(seq)?) {
-            Some(alp) => {
-                if alp == Alphabet::Dna {
-                    info!("Sequence type is DNA");
-                    alphabet = "dna";
-                } else if alp == Alphabet::Rna {
-                    info!("Sequence type is RNA");
-                    alphabet = "rna";
-                }
-            }
-            None => error!("Sequence type is not recognized as DNA or RNA"),
-        }
-        if seq.len() <= 1500 {
-            warn!("Sequence length is less than 1500 bp. We may not be able to find some regions");
-        }
-
-        for primer_pair in primers.iter() {
-            let region = primers_to_region(primer_pair.to_vec());
-
-            let mut forward_myers = builder.build_64(primer_pair[0].as_bytes());
-            let mut reverse_myers = builder.build_64(
-                to_reverse_complement(&primer_pair[1], alphabet).as_bytes(),
-            );
+    fn reverse(self) -> bool {
+        matches!(self, Trim::Reverse | Trim::Both)
+    }
 
-            let mut forward_matches =
-                forward_myers.find_all_lazy(seq, mismatch);
-            let mut reverse_matches =
-                reverse_myers.find_all_lazy(seq, mismatch);
-
-            // Get the best hit
-            let forward_best_hit =
-                forward_matches.by_ref().min_by_key(|&(_, dist)| dist);
-            let reverse_best_hit =
-                reverse_matches.by_ref().min_by_key(|&(_, dist)| dist);
-
-            match forward_best_hit {
-                Some((forward_best_hit_end, _)) => {
-                    match reverse_best_hit {
-                        Some((reverse_best_hit_end, _)) => {
-                            // Get match start position of forward primer
-                            let (forward_start, _) = forward_matches
-                                .hit_at(forward_best_hit_end)
-                                .unwrap();
-                            // Get match start position of reverse primer
-                            let (reverse_start, _) = reverse_matches
-                                .hit_at(reverse_best_hit_end)
-                                .unwrap();
-
-                            if !region.is_empty() {
-                                fasta_writer.write_record(
-                                    &fasta::Record::with_attrs(
-                                        record.id(),
-                                        Some(
-                                            format!(
-                                            "region={} forward={} reverse={}",
-                                            region,
-                                            primer_pair[0],
-                                            primer_pair[1]
-                                        )
-                                            .as_str(),
-                                        ),
-                                        &seq[forward_start
-                                            ..reverse_start
-                                                + primer_pair[1].len()],
-                                    ),
-                                )?;
-                            } else {
-                                fasta_writer.write_record(
-                                    &fasta::Record::with_attrs(
-                                        record.id(),
-                                        Some(
-                                            format!(
-                                                "forward={} reverse={}",
-                                                primer_pair[0], primer_pair[1]
-                                            )
-                                            .as_str(),
-                                        ),
-                                        &seq[forward_start
-                                            ..reverse_start
-                                                + primer_pair[1].len()],
-                                    ),
-                                )?;
-                            }
-                            // Write region to GFF3 file
-                            gff_writer.write_all(format!("{}\thyperex\tregion\t{}\t{}\t.\t.\t.\tNote Hypervariable region {}\n", record.id(), forward_start, reverse_start + primer_pair[1].len(), region).as_bytes())?;
-                        }
-                        None => {
-                            warn!("Region {} not found because primer {} was not found in the sequence", region, primer_pair[1])
-                        }
-                    }
-                }
-                None => match reverse_best_hit {
-                    Some((_, _)) => {
-                        warn!("Region {} not found because primer {} was not found in the sequence", region, primer_pair[0]);
-                    }
-                    None => {
-                        warn!("Region {} not found because primers {}, {} was not found in the sequence", region, primer_pair[0], primer_pair[1])
-                    }
-                },
-            }
+    // Value of the `primers` note of FASTA headers
+    fn note(self) -> &'static str {
+        match self {
+            Trim::None => "kept",
+            Trim::Forward => "forward_trimmed",
+            Trim::Reverse => "reverse_trimmed",
+            Trim::Both => "trimmed",
         }
     }
-
-    Ok(())
 }
 
-// Tests --------------------------------------------------------------------
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use std::io::Write;
-    use tempfile::NamedTempFile;
+/// Files the amplicon coordinates are written to
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AnnotationFormat {
+    #[default]
+    Gff,
+    Bed,
+    Both,
+    /// JSON array of the amplicons, for programmatic consumption
+    Json,
+}
 
-    #[test]
-    fn test_primers_to_region_ok() {
-        assert_eq!(
-            primers_to_region(vec![
-                "CCTACGGGNGGCWGCAG".to_string(),
-                "GTGCCAGCMGCCGCGGTAA".to_string()
-            ]),
-            "v3v4".to_string()
-        );
+impl AnnotationFormat {
+    fn gff(self) -> bool {
+        matches!(self, AnnotationFormat::Gff | AnnotationFormat::Both)
     }
 
-    #[test]
-    fn test_primers_to_region_ok2() {
-        assert_eq!(
-            primers_to_region(vec![
-                "GTGCCAGCMGCCGCGGTAA".to_string(),
-                "GTGCCAGCMGCCGCGGTAA".to_string()
-            ]),
-            "v4".to_string()
-        );
+    fn bed(self) -> bool {
+        matches!(self, AnnotationFormat::Bed | AnnotationFormat::Both)
     }
 
-    #[test]
-    fn test_primers_to_region_empty() {
-        assert_eq!(
-            primers_to_region(vec!["ZZZZZ".to_string(), "AAAAAA".to_string()]),
-            "".to_string()
-        );
+    fn json(self) -> bool {
+        self == AnnotationFormat::Json
     }
 
-    #[test]
-    fn test_complement_dna() {
-        assert_eq!(
-            to_complement("ATCGATCGATCGATCGRYKBVDHX", "dna"),
-            String::from("TAGCTAGCTAGCTAGCYRMVBHDX")
-        );
+    /// Extensions of the annotation files written in this format
+    pub fn extensions(self) -> Vec<&'static str> {
+        let mut extensions = Vec::new();
+        if self.gff() {
+            extensions.push("gff");
+        }
+        if self.bed() {
+            extensions.push("bed");
+        }
+        if self.json() {
+            extensions.push("json");
+        }
+        extensions
     }
+}
 
-    #[test]
-    fn test_complement_rna() {
-        assert_eq!(
-            to_complement("AUCGAUCGAUCGAUCGRYKBVDHMXN", "rna"),
-            String::from("UAGCUAGCUAGCUAGCYRMVBHDKXN")
-        );
-    }
+/// Settings of the per-record concatenation of amplicons
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Concat {
+    /// Sequence inserted between two consecutive regions
+    pub spacer: Vec<u8>,
+    /// What to do with records missing some regions
+    pub policy: ConcatPolicy,
+}
 
-    #[test]
-    fn test_reverse_complement() {
-        assert_eq!(
-            to_reverse_complement("GTGCCAGCMGCCGCGGTAAN", "dna"),
-            "NTTACCGCGGCKGCTGGCAC"
-        );
-    }
+/// Handling of records in which some regions were not extracted
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ConcatPolicy {
+    /// Records missing a region are not concatenated
+    #[default]
+    Skip,
+    /// Missing regions are left empty, only their spacers being written
+    Pad,
+}
 
-    #[test]
-    fn test_sequence_type_dna_ok() {
-        assert_eq!(sequence_type("ATCGATCGATCG"), Some(Alphabet::Dna));
+/// Values of the per-record presence matrix of regions
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Presence {
+    /// 1 when the region was extracted, 0 otherwise
+    #[default]
+    Binary,
+    /// Lowest total edit distance of the primers of the region amplicons,
+    /// `NA` when the region was not extracted
+    Mismatch,
+}
+
+impl Options {
+    /// Id of a record in the outputs. The same prefix and suffix being added
+    /// to all ids, distinct ids stay distinct
+    pub fn output_id(&self, id: &str) -> String {
+        format!("{}{}{}", self.id_prefix, id, self.id_suffix)
     }
+}
 
-    #[test]
-    fn test_sequence_type_dna_iupac_ok() {
-        assert_eq!(sequence_type("ATCGMTGCAATCG"), Some(Alphabet::Dna));
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            mismatch: 0,
+            reverse_mismatch: None,
+            consensus: false,
+            quality_trim: None,
+            dump_debug: false,
+            cache: None,
+            match_seq: false,
+            degenerate_expand: false,
+            degap: false,
+            only_complete: true,
+            group_by_region: false,
+            dedup: false,
+            matcher: Matcher::Myers,
+            length: None,
+            max_hits: None,
+            split_dir: false,
+            split_by_region: false,
+            header_format: None,
+            strict: false,
+            anchor_3prime: None,
+            concat: None,
+            presence: None,
+            input_format: InputFormat::Auto,
+            compression: OutputCompression::None,
+            min_forward_distance: None,
+            min_reverse_distance: None,
+            min_length: None,
+            length_tolerance: None,
+            max_length: None,
+            gff_types: HashMap::new(),
+            coverage: false,
+            mask: false,
+            primer_sites: false,
+            max_ambiguous: None,
+            length_bin: None,
+            length_stats: false,
+            sqlite: None,
+            trim: Trim::None,
+            emit_both: false,
+            strand_agreement: None,
+            strand: Strand::Both,
+            resume_from: None,
+            append: false,
+            auto_window: false,
+            infer_region: false,
+            id_prefix: String::new(),
+            id_suffix: String::new(),
+            collect_errors: false,
+            format: AnnotationFormat::Gff,
+            all_hits: false,
+            max_regions_per_record: None,
+            threads: 1,
+            keep_order: true,
+            progress: false,
+        }
+    }
+}
+
+impl Options {
+    // Number of allowed mismatch in forward or reverse primers
+    fn primer_mismatch(&self, reverse: bool) -> u8 {
+        match self.reverse_mismatch {
+            Some(mismatch) if reverse => mismatch,
+            _ => self.mismatch,
+        }
+    }
+}
+
+/// Best match of a primer in a sequence
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PrimerHit {
+    /// 0-based start of the match
+    start: usize,
+    /// 0-based exclusive end of the match
+    end: usize,
+    /// Edit distance between the primer and the match
+    dist: u8,
+}
+
+/// Forward and reverse primer best hits of a primer pair
+type PairHits = (Option<PrimerHit>, Option<PrimerHit>);
+
+// Get the best (lowest edit distance) hit of a pattern in a sequence among
+// the first `max_hits` hits, or all hits when unbounded. Equally good hits
+// are resolved to the leftmost one, or to the rightmost one when `rightmost`
+fn best_hit(
+    myers: &mut Myers<u64>,
+    seq: &[u8],
+    mismatch: u8,
+    max_hits: Option<usize>,
+    rightmost: bool,
+) -> Option<PrimerHit> {
+    let mut matches = myers.find_all_lazy(seq, mismatch);
+    let hits = matches.by_ref().take(max_hits.unwrap_or(usize::MAX));
+    // Hits come in the order of the sequence and the last maximum is kept
+    let (end, dist) = if rightmost {
+        hits.max_by_key(|&(_, dist)| std::cmp::Reverse(dist))?
+    } else {
+        hits.min_by_key(|&(_, dist)| dist)?
+    };
+    let (start, _) = matches.hit_at(end)?;
+
+    Some(PrimerHit {
+        start,
+        end: end + 1,
+        dist,
+    })
+}
+
+// Get all the hits of a pattern in a sequence, in the order of the sequence.
+// Hits overlapping a better one are left out
+fn all_hits(
+    myers: &mut Myers<u64>,
+    seq: &[u8],
+    mismatch: u8,
+) -> Vec<PrimerHit> {
+    let mut matches = myers.find_all_lazy(seq, mismatch);
+    let mut ends = matches.by_ref().collect::<Vec<_>>();
+    ends.sort_by_key(|&(end, dist)| (dist, end));
+
+    let mut hits: Vec<PrimerHit> = Vec::new();
+    for (end, dist) in ends {
+        let (start, _) = match matches.hit_at(end) {
+            Some(hit) => hit,
+            None => continue,
+        };
+        let overlaps = hits
+            .iter()
+            .any(|hit| start < hit.end && hit.start < end + 1);
+        if !overlaps {
+            hits.push(PrimerHit {
+                start,
+                end: end + 1,
+                dist,
+            });
+        }
+    }
+    hits.sort_by_key(|hit| hit.start);
+
+    hits
+}
+
+/// Largest amplicon paired with all primer hits, unless a maximum length is
+/// given
+const ALL_HITS_MAX_LENGTH: usize = 2000;
+
+// Pair each forward primer hit with the nearest reverse primer hit
+// downstream, unless another forward primer hit is closer to it. Amplicons
+// longer than the maximum length are left out
+fn pair_all_hits(
+    matchers: &Matchers,
+    seq: &[u8],
+    primer_pair: &[String],
+    alphabet: &str,
+    options: &Options,
+) -> Vec<PairHits> {
+    let reverse = to_reverse_complement(&primer_pair[1], alphabet);
+    let forward_hits = matchers.search(primer_pair[0].as_bytes(), |myers| {
+        all_hits(myers, seq, options.primer_mismatch(false))
+    });
+    let reverse_hits = matchers.search(reverse.as_bytes(), |myers| {
+        all_hits(myers, seq, options.primer_mismatch(true))
+    });
+    let anchored = |hits: Vec<PrimerHit>, pattern: &[u8], complemented| {
+        hits.into_iter()
+            .filter(|&hit| {
+                anchored_hit(Some(hit), pattern, seq, complemented, options)
+                    .is_some()
+            })
+            .collect::<Vec<_>>()
+    };
+    let forward_hits = anchored(forward_hits, primer_pair[0].as_bytes(), false);
+    let reverse_hits = anchored(reverse_hits, reverse.as_bytes(), true);
+    let max_length = options.max_length.unwrap_or(ALL_HITS_MAX_LENGTH);
+
+    forward_hits
+        .iter()
+        .enumerate()
+        .filter_map(|(i, forward)| {
+            let reverse = reverse_hits
+                .iter()
+                .find(|reverse| reverse.start > forward.start)?;
+            let next_forward = forward_hits.get(i + 1);
+            if next_forward.is_some_and(|next| next.start < reverse.start)
+                || reverse.end - forward.start > max_length
+            {
+                return None;
+            }
+            Some((Some(*forward), Some(*reverse)))
+        })
+        .collect()
+}
+
+// Get the best hit of a pattern in a sequence using an affine gap cost
+// semi-global alignment. The hit is kept when the number of edits of the
+// alignment is within the allowed mismatch
+fn best_hit_sw(
+    pattern: &[u8],
+    seq: &[u8],
+    mismatch: u8,
+    penalties: &Penalties,
+) -> Option<PrimerHit> {
+    let mismatch_score = -penalties.mismatch;
+    let score = |a: u8, b: u8| {
+        if base_to_bits(a) & base_to_bits(b) != 0 {
+            1
+        } else {
+            mismatch_score
+        }
+    };
+    let mut aligner = Aligner::with_capacity(
+        pattern.len(),
+        seq.len(),
+        -penalties.gap_open,
+        -penalties.gap_extend,
+        score,
+    );
+    let alignment = aligner.semiglobal(pattern, seq);
+
+    // Alignment operations flag IUPAC matches as substitutions so edits are
+    // counted by comparing the aligned bases
+    let (mut x, mut y, mut edits) = (alignment.xstart, alignment.ystart, 0);
+    for op in &alignment.operations {
+        match op {
+            AlignmentOperation::Match | AlignmentOperation::Subst => {
+                if base_to_bits(pattern[x]) & base_to_bits(seq[y]) == 0 {
+                    edits += 1;
+                }
+                x += 1;
+                y += 1;
+            }
+            AlignmentOperation::Del => {
+                edits += 1;
+                y += 1;
+            }
+            AlignmentOperation::Ins => {
+                edits += 1;
+                x += 1;
+            }
+            _ => (),
+        }
+    }
+
+    if edits > mismatch as usize || alignment.ystart >= alignment.yend {
+        return None;
+    }
+
+    Some(PrimerHit {
+        start: alignment.ystart,
+        end: alignment.yend,
+        dist: edits as u8,
+    })
+}
+
+// Bases of a primer binding site aligned to each primer position, which
+// resolves the primer ambiguities. Primer positions without a base in the
+// site are written as gaps and extra bases of the site are left out
+fn resolved_match(primer: &[u8], site: &[u8]) -> Vec<u8> {
+    let score = |a: u8, b: u8| {
+        if base_to_bits(a) & base_to_bits(b) != 0 {
+            1
+        } else {
+            -1
+        }
+    };
+    let mut aligner =
+        Aligner::with_capacity(primer.len(), site.len(), -2, -1, score);
+    let alignment = aligner.global(primer, site);
+
+    let mut y = alignment.ystart;
+    let mut resolved = Vec::with_capacity(primer.len());
+    for op in &alignment.operations {
+        match op {
+            AlignmentOperation::Match | AlignmentOperation::Subst => {
+                resolved.push(site[y]);
+                y += 1;
+            }
+            AlignmentOperation::Del => y += 1,
+            AlignmentOperation::Ins => resolved.push(b'-'),
+            _ => (),
+        }
+    }
+
+    resolved
+}
+
+// Keep a primer hit only if the bases at the 3' end of the primer, as
+// requested with anchor_3prime, match the binding site without mismatch.
+// Primers searched as reverse complement have their 3' end first
+fn anchored_hit(
+    hit: Option<PrimerHit>,
+    pattern: &[u8],
+    seq: &[u8],
+    complemented: bool,
+    options: &Options,
+) -> Option<PrimerHit> {
+    let anchor = match options.anchor_3prime {
+        Some(anchor) => anchor,
+        None => return hit,
+    };
+    hit.filter(|hit| {
+        let resolved = resolved_match(pattern, &seq[hit.start..hit.end]);
+        let positions = pattern.iter().zip(&resolved);
+        let matches = |(&primer, &site): (&u8, &u8)| {
+            site != b'-' && base_to_bits(primer) & base_to_bits(site) != 0
+        };
+        if complemented {
+            positions.take(anchor).all(matches)
+        } else {
+            positions.rev().take(anchor).all(matches)
+        }
+    })
+}
+
+// Build Myers with IUPAC ambiguities in patterns
+fn iupac_myers_builder() -> MyersBuilder {
+    let ambigs = [
+        (b'M', &b"AC"[..]),
+        (b'R', &b"AG"[..]),
+        (b'W', &b"AT"[..]),
+        (b'S', &b"CG"[..]),
+        (b'Y', &b"CT"[..]),
+        (b'K', &b"GT"[..]),
+        (b'V', &b"ACGMRS"[..]),
+        (b'H', &b"ACTMWY"[..]),
+        (b'D', &b"AGTRWK"[..]),
+        (b'B', &b"CGTSYK"[..]),
+        (b'N', &b"ACGTMRWSYKVHDB"[..]),
+    ];
+
+    let mut builder = MyersBuilder::new();
+
+    for &(base, equivalents) in &ambigs {
+        builder.ambig(base, equivalents);
+    }
+
+    builder
+}
+
+// Myers matchers of the searched patterns. Matchers of the primers are built
+// once before reading the records, other patterns (primers searched on the
+// minus strand or as given) when first searched. Searching needs a mutable
+// matcher so each thread has its own clone
+#[derive(Clone)]
+struct Matchers {
+    builder: MyersBuilder,
+    built: RefCell<HashMap<Vec<u8>, Myers<u64>>>,
+}
+
+impl Matchers {
+    fn new(primers: &[Vec<String>]) -> Self {
+        let builder = iupac_myers_builder();
+        let mut built = HashMap::new();
+        for primer_pair in primers {
+            // Primers are searched as DNA, RNA being converted beforehand
+            let reverse = to_reverse_complement(&primer_pair[1], "dna");
+            for pattern in [primer_pair[0].as_bytes(), reverse.as_bytes()] {
+                if !pattern.is_empty() && !built.contains_key(pattern) {
+                    built.insert(pattern.to_vec(), builder.build_64(pattern));
+                }
+            }
+        }
+
+        Matchers {
+            builder,
+            built: RefCell::new(built),
+        }
+    }
+
+    // Run a search with the matcher of a pattern
+    fn search<T>(
+        &self,
+        pattern: &[u8],
+        search: impl FnOnce(&mut Myers<u64>) -> T,
+    ) -> T {
+        let mut built = self.built.borrow_mut();
+        if !built.contains_key(pattern) {
+            built.insert(pattern.to_vec(), self.builder.build_64(pattern));
+        }
+        search(built.get_mut(pattern).unwrap())
+    }
+}
+
+// Search a primer in a sequence with the requested algorithm. Reverse
+// primers are allowed their own mismatch and their ties are resolved to the
+// rightmost hit, as in best_hit with Myers
+fn primer_hit(
+    matchers: &Matchers,
+    pattern: &[u8],
+    seq: &[u8],
+    reverse: bool,
+    options: &Options,
+) -> Option<PrimerHit> {
+    let mismatch = options.primer_mismatch(reverse);
+    match &options.matcher {
+        Matcher::Myers => matchers.search(pattern, |myers| {
+            best_hit(myers, seq, mismatch, options.max_hits, reverse)
+        }),
+        Matcher::SmithWaterman(penalties) => {
+            best_hit_sw(pattern, seq, mismatch, penalties)
+        }
+    }
+}
+
+// Part of a sequence searched for a primer, the whole sequence unless the
+// primer has a known binding site and windows are enabled
+fn primer_window(
+    primer: &str,
+    seq_len: usize,
+    options: &Options,
+) -> std::ops::Range<usize> {
+    match PRIMER_POSITIONS.get(primer) {
+        Some(&position) if options.auto_window => {
+            let start = position.saturating_sub(AUTO_WINDOW_MARGIN);
+            let end = position + primer.len() + AUTO_WINDOW_MARGIN;
+            start.min(seq_len)..end.min(seq_len)
+        }
+        _ => 0..seq_len,
+    }
+}
+
+// Search a primer in a window of a sequence, hits being given relative to
+// the whole sequence
+fn primer_hit_in(
+    matchers: &Matchers,
+    pattern: &[u8],
+    seq: &[u8],
+    window: std::ops::Range<usize>,
+    reverse: bool,
+    options: &Options,
+) -> Option<PrimerHit> {
+    let offset = window.start;
+    let hit = primer_hit(matchers, pattern, &seq[window], reverse, options);
+    hit.map(|hit| PrimerHit {
+        start: hit.start + offset,
+        end: hit.end + offset,
+        dist: hit.dist,
+    })
+}
+
+// Search the forward and reverse primers of each primer pair in a sequence.
+// Equally good hits are resolved to the leftmost forward primer hit and the
+// rightmost reverse primer hit, spurious hits being more likely inside the
+// amplicon
+fn find_primer_hits(
+    matchers: &Matchers,
+    seq: &[u8],
+    primers: &[Vec<String>],
+    alphabet: &str,
+    options: &Options,
+) -> Vec<PairHits> {
+    primers
+        .iter()
+        .map(|primer_pair| {
+            let forward = primer_pair[0].as_bytes();
+            let reverse = to_reverse_complement(&primer_pair[1], alphabet);
+
+            // An empty forward primer is not searched (reverse-only mode)
+            let forward_hit = if forward.is_empty() {
+                None
+            } else {
+                let window = primer_window(&primer_pair[0], seq.len(), options);
+                let hit = primer_hit_in(
+                    matchers, forward, seq, window, false, options,
+                );
+                anchored_hit(hit, forward, seq, false, options)
+            };
+            let window = primer_window(&primer_pair[1], seq.len(), options);
+            let reverse_hit = primer_hit_in(
+                matchers,
+                reverse.as_bytes(),
+                seq,
+                window,
+                true,
+                options,
+            );
+
+            (
+                forward_hit,
+                anchored_hit(
+                    reverse_hit,
+                    reverse.as_bytes(),
+                    seq,
+                    true,
+                    options,
+                ),
+            )
+        })
+        .collect()
+}
+
+// Search the primers of each primer pair on the minus strand of a sequence,
+// hits being given in plus strand coordinates. Ties are resolved to the
+// other end than on the plus strand so that primers with several equally
+// good hits disagree
+fn minus_strand_hits(
+    matchers: &Matchers,
+    seq: &[u8],
+    primers: &[Vec<String>],
+    alphabet: &str,
+    options: &Options,
+) -> anyhow::Result<Vec<PairHits>> {
+    let minus = to_reverse_complement(std::str::from_utf8(seq)?, alphabet);
+    let to_plus = |hit: PrimerHit| PrimerHit {
+        start: seq.len() - hit.end,
+        end: seq.len() - hit.start,
+        dist: hit.dist,
+    };
+
+    Ok(primers
+        .iter()
+        .map(|primer_pair| {
+            let forward_hit = if primer_pair[0].is_empty() {
+                None
+            } else {
+                let forward = to_reverse_complement(&primer_pair[0], alphabet);
+                let hit = primer_hit(
+                    matchers,
+                    forward.as_bytes(),
+                    minus.as_bytes(),
+                    false,
+                    options,
+                );
+                anchored_hit(
+                    hit,
+                    forward.as_bytes(),
+                    minus.as_bytes(),
+                    true,
+                    options,
+                )
+            };
+            let reverse_hit = primer_hit(
+                matchers,
+                primer_pair[1].as_bytes(),
+                minus.as_bytes(),
+                true,
+                options,
+            );
+            let reverse_hit = anchored_hit(
+                reverse_hit,
+                primer_pair[1].as_bytes(),
+                minus.as_bytes(),
+                false,
+                options,
+            );
+
+            (forward_hit.map(to_plus), reverse_hit.map(to_plus))
+        })
+        .collect())
+}
+
+/// Number of extra mismatch allowed when looking for the near misses of
+/// primers which were not found
+const NEAR_MISS_MARGIN: u8 = 2;
+
+// Warn about a primer not found within the allowed mismatch when a few more
+// mismatch would have found it, suggesting the mismatch to use. This search
+// only runs for missing primers
+fn report_near_miss(
+    matchers: &Matchers,
+    seq: &[u8],
+    primer_pair: &[String],
+    reverse: bool,
+    alphabet: &str,
+    (region, id): (&str, &str),
+    options: &Options,
+) {
+    if options.collect_errors {
+        return;
+    }
+    let pattern = if reverse {
+        to_reverse_complement(&primer_pair[1], alphabet)
+    } else {
+        primer_pair[0].clone()
+    };
+    let allowed = options.primer_mismatch(reverse);
+    let hit = matchers.search(pattern.as_bytes(), |myers| {
+        best_hit(
+            myers,
+            seq,
+            allowed.saturating_add(NEAR_MISS_MARGIN),
+            None,
+            reverse,
+        )
+    });
+    if let Some(hit) = hit.filter(|hit| hit.dist > allowed) {
+        let (forward, reverse_mismatch) = if reverse {
+            (options.primer_mismatch(false), hit.dist)
+        } else {
+            (hit.dist, options.primer_mismatch(true))
+        };
+        let suggestion = if forward == reverse_mismatch {
+            forward.to_string()
+        } else {
+            format!("{},{}", forward, reverse_mismatch)
+        };
+        warn!(
+            "{} {} primer best match in {} had {} mismatches; try --mismatch {}",
+            if region.is_empty() {
+                &primer_pair[reverse as usize]
+            } else {
+                region
+            },
+            if reverse { "reverse" } else { "forward" },
+            id,
+            hit.dist,
+            suggestion
+        );
+    }
+}
+
+// Warn about a reverse primer not found on a sequence but found as given,
+// without being reverse complemented, the sequence being then likely stored
+// on the opposite strand
+fn report_opposite_strand(
+    matchers: &Matchers,
+    seq: &[u8],
+    primer_pair: &[String],
+    (region, id): (&str, &str),
+    options: &Options,
+) {
+    if options.collect_errors {
+        return;
+    }
+    let hit = matchers.search(primer_pair[1].as_bytes(), |myers| {
+        best_hit(myers, seq, options.primer_mismatch(true), None, false)
+    });
+    if hit.is_some() {
+        warn!(
+            "Reverse primer {} of {} is found in {} as given instead of reverse complemented, the sequence may be on the opposite strand (see --strand)",
+            primer_pair[1],
+            if region.is_empty() { "the primer pair" } else { region },
+            id
+        );
+    }
+}
+
+// Whether the primers of a pair are found with the forward primer upstream
+// of the reverse one, or the reverse primer alone without forward primer
+fn primers_in_order(hits: PairHits, primer_pair: &[String]) -> bool {
+    match hits {
+        (Some(forward), Some(reverse)) => forward.start < reverse.end,
+        (None, Some(_)) => primer_pair[0].is_empty(),
+        _ => false,
+    }
+}
+
+// Whether hits of a primer on both strands are at the same place, up to
+// `tolerance` bases
+fn strands_agree(
+    plus: Option<PrimerHit>,
+    minus: Option<PrimerHit>,
+    tolerance: usize,
+) -> bool {
+    match (plus, minus) {
+        (Some(plus), Some(minus)) => {
+            plus.start.abs_diff(minus.start) <= tolerance
+                && plus.end.abs_diff(minus.end) <= tolerance
+        }
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Least recently used cache of primer hits keyed by sequence hash.
+///
+/// Primers and mismatch are fixed for a run so the hits only depend on the
+/// sequence. The sequence is kept alongside the hits so that hash collisions
+/// are not mistaken for cache hits.
+struct HitCache {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<u64, (Vec<u8>, Vec<PairHits>, u64)>,
+    // Keys of the entries by their last use, the least recently used first
+    recency: BTreeMap<u64, u64>,
+}
+
+impl HitCache {
+    fn new(capacity: usize) -> Self {
+        HitCache {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    fn hash(seq: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        seq.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn get(&mut self, seq: &[u8]) -> Option<Vec<PairHits>> {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(&Self::hash(seq)) {
+            Some((cached_seq, hits, last_used)) if cached_seq == seq => {
+                let key = self.recency.remove(last_used)?;
+                self.recency.insert(tick, key);
+                *last_used = tick;
+                Some(hits.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, seq: &[u8], hits: Vec<PairHits>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = Self::hash(seq);
+        if let Some((_, _, last_used)) = self.entries.get(&key) {
+            self.recency.remove(last_used);
+        } else if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key);
+        self.entries.insert(key, (seq.to_vec(), hits, self.tick));
+    }
+}
+
+// Bit encoding of nucleotides used to build IUPAC codes: A=1, C=2, G=4, T=8
+fn base_to_bits(base: u8) -> u8 {
+    match base.to_ascii_uppercase() {
+        b'A' => 0b0001,
+        b'C' => 0b0010,
+        b'G' => 0b0100,
+        b'T' | b'U' => 0b1000,
+        b'M' => 0b0011,
+        b'R' => 0b0101,
+        b'W' => 0b1001,
+        b'S' => 0b0110,
+        b'Y' => 0b1010,
+        b'K' => 0b1100,
+        b'V' => 0b0111,
+        b'H' => 0b1011,
+        b'D' => 0b1101,
+        b'B' => 0b1110,
+        _ => 0b1111,
+    }
+}
+
+fn bits_to_iupac(bits: u8) -> u8 {
+    b"NACMGRSVTWYHKDBN"[bits as usize]
+}
+
+/// Compute the column-wise IUPAC consensus of a set of sequences.
+///
+/// All sequences must have the same length as no alignment is performed.
+/// Returns None when the set is empty or lengths differ.
+pub fn iupac_consensus(sequences: &[Vec<u8>]) -> Option<Vec<u8>> {
+    let length = sequences.first()?.len();
+    if sequences.iter().any(|s| s.len() != length) {
+        return None;
+    }
+
+    Some(
+        (0..length)
+            .map(|i| {
+                bits_to_iupac(
+                    sequences.iter().fold(0, |acc, s| acc | base_to_bits(s[i])),
+                )
+            })
+            .collect(),
+    )
+}
+
+fn write_consensus(
+    prefix: &str,
+    amplicons: &[(String, Vec<Vec<u8>>)],
+) -> anyhow::Result<()> {
+    let mut writer =
+        fasta::Writer::to_file(format!("{}.consensus.fa", prefix))?;
+
+    for (region, sequences) in amplicons {
+        match iupac_consensus(sequences) {
+            Some(consensus) => writer.write_record(
+                &fasta::Record::with_attrs(
+                    region,
+                    Some(format!("amplicons={}", sequences.len()).as_str()),
+                    &consensus,
+                ),
+            )?,
+            None => warn!(
+                "No consensus computed for region {} as extracted amplicons are not of identical length",
+                region
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+// Concatenate the amplicons extracted from a record in the order of the
+// primers. None is returned when a region is missing and records missing
+// regions are skipped
+fn concat_amplicons(
+    id: &str,
+    labels: &[String],
+    parts: &[Option<Vec<u8>>],
+    concat: &Concat,
+) -> Option<fasta::Record> {
+    let missing = labels
+        .iter()
+        .zip(parts)
+        .filter(|(_, part)| part.is_none())
+        .map(|(label, _)| label.as_str())
+        .collect::<Vec<_>>();
+
+    if missing.len() == parts.len()
+        || (!missing.is_empty() && concat.policy == ConcatPolicy::Skip)
+    {
+        return None;
+    }
+
+    let seq = parts
+        .iter()
+        .map(|part| part.as_deref().unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join(&concat.spacer[..]);
+    let mut desc = format!("regions={}", labels.join(","));
+    if !missing.is_empty() {
+        desc.push_str(&format!(" missing={}", missing.join(",")));
+    }
+
+    Some(fasta::Record::with_attrs(id, Some(desc.as_str()), &seq))
+}
+
+/// Check that output files can be written before extracting anything.
+///
+/// Existing files are an error unless `force` is set, in which case they are
+/// removed. Missing files are skipped so that removal cannot fail on them.
+pub fn handle_output_files(
+    files: &[PathBuf],
+    force: bool,
+) -> anyhow::Result<()> {
+    for file in files {
+        if !file.exists() {
+            continue;
+        }
+        if !force {
+            return Err(anyhow!("file {} already exists. Please change it using --prefix option or use --force to overwrite it", file.display()));
+        }
+        fs::remove_file(file).with_context(|| {
+            format!("cannot overwrite existing file {}", file.display())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Prefix of the output files of a region written in its own subdirectory.
+///
+/// The subdirectory is named after the region and created next to the
+/// prefix, e.g. `out/sample` gives `out/v4/sample` for the v4 region.
+/// Regions without name are written in an `unknown` subdirectory.
+pub fn region_dir_prefix(prefix: &str, region: &str) -> PathBuf {
+    let prefix = Path::new(prefix);
+    let region = if region.is_empty() { "unknown" } else { region };
+
+    prefix
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(region)
+        .join(prefix.file_name().unwrap_or_default())
+}
+
+/// Prefixes of the per-region output files of a run writing each region in
+/// its own subdirectory
+pub fn split_dir_prefixes(
+    prefix: &str,
+    primers: &[Vec<String>],
+) -> Vec<PathBuf> {
+    let mut prefixes = primers
+        .iter()
+        .map(|primer_pair| {
+            region_dir_prefix(prefix, &primers_to_region(primer_pair))
+        })
+        .collect::<Vec<_>>();
+    prefixes.dedup();
+
+    prefixes
+}
+
+/// Prefix of the output files of a region written next to the prefix.
+///
+/// The region is added to the prefix, e.g. `out/sample` gives
+/// `out/sample.v4` for the v4 region. Regions without name are written with
+/// `unknown` instead.
+pub fn region_file_prefix(prefix: &str, region: &str) -> PathBuf {
+    let region = if region.is_empty() { "unknown" } else { region };
+
+    PathBuf::from(format!("{}.{}", prefix, region))
+}
+
+/// Prefixes of the per-region output files of a run writing each region in
+/// its own files next to the prefix
+pub fn split_file_prefixes(
+    prefix: &str,
+    primers: &[Vec<String>],
+) -> Vec<PathBuf> {
+    let mut prefixes = primers
+        .iter()
+        .map(|primer_pair| {
+            region_file_prefix(prefix, &primers_to_region(primer_pair))
+        })
+        .collect::<Vec<_>>();
+    prefixes.dedup();
+
+    prefixes
+}
+
+// Output file, compressed when requested. Compressed streams are finished
+// explicitly, as errors writing their end are lost when they are dropped
+enum OutputFile {
+    Plain(File),
+    Gzip(flate2::write::GzEncoder<File>),
+    Xz(xz2::write::XzEncoder<File>),
+    Bzip2(bzip2::write::BzEncoder<File>),
+}
+
+impl OutputFile {
+    fn new(file: File, compression: OutputCompression) -> Self {
+        match compression {
+            OutputCompression::None => OutputFile::Plain(file),
+            OutputCompression::Gzip => {
+                OutputFile::Gzip(flate2::write::GzEncoder::new(
+                    file,
+                    flate2::Compression::new(6),
+                ))
+            }
+            OutputCompression::Xz => {
+                OutputFile::Xz(xz2::write::XzEncoder::new(file, 6))
+            }
+            OutputCompression::Bzip2 => OutputFile::Bzip2(
+                bzip2::write::BzEncoder::new(file, bzip2::Compression::new(6)),
+            ),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            OutputFile::Plain(file) => file,
+            OutputFile::Gzip(encoder) => encoder.finish()?,
+            OutputFile::Xz(encoder) => encoder.finish()?,
+            OutputFile::Bzip2(encoder) => encoder.finish()?,
+        };
+        file.flush()
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputFile::Plain(file) => file.write(buf),
+            OutputFile::Gzip(encoder) => encoder.write(buf),
+            OutputFile::Xz(encoder) => encoder.write(buf),
+            OutputFile::Bzip2(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(file) => file.flush(),
+            OutputFile::Gzip(encoder) => encoder.flush(),
+            OutputFile::Xz(encoder) => encoder.flush(),
+            OutputFile::Bzip2(encoder) => encoder.flush(),
+        }
+    }
+}
+
+// Flush a buffered output file and finish its compression
+fn finish_output(writer: io::BufWriter<OutputFile>) -> io::Result<()> {
+    writer
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?
+        .finish()
+}
+
+// Amplicon and annotation writers of a prefix
+struct RegionWriters {
+    seq: SeqWriter,
+    gff: Option<io::BufWriter<OutputFile>>,
+    bed: Option<io::BufWriter<OutputFile>>,
+    json: Option<JsonWriter>,
+}
+
+impl RegionWriters {
+    fn finish(self) -> io::Result<()> {
+        self.seq.finish()?;
+        if let Some(gff_writer) = self.gff {
+            finish_output(gff_writer)?;
+        }
+        if let Some(bed_writer) = self.bed {
+            finish_output(bed_writer)?;
+        }
+        if let Some(json_writer) = self.json {
+            json_writer.finish()?;
+        }
+
+        Ok(())
+    }
+}
+
+// Writer of the JSON array of amplicons, closed once all are written
+struct JsonWriter {
+    writer: io::BufWriter<OutputFile>,
+    entries: usize,
+}
+
+impl JsonWriter {
+    fn new(file: OutputFile) -> io::Result<Self> {
+        let mut writer = io::BufWriter::new(file);
+        writer.write_all(b"[")?;
+
+        Ok(JsonWriter { writer, entries: 0 })
+    }
+
+    fn write(&mut self, entry: &str) -> io::Result<()> {
+        let separator = if self.entries == 0 { "" } else { "," };
+        write!(self.writer, "{}\n  {}", separator, entry)?;
+        self.entries += 1;
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        if self.entries > 0 {
+            self.writer.write_all(b"\n")?;
+        }
+        self.writer.write_all(b"]\n")?;
+        finish_output(self.writer)
+    }
+}
+
+// Writer of the amplicons, FASTQ when the input has qualities. Records are
+// written as by the bio writers, which can not give back their file to be
+// finished
+struct SeqWriter {
+    writer: io::BufWriter<OutputFile>,
+    fastq: bool,
+}
+
+impl SeqWriter {
+    fn new(file: OutputFile, fastq: bool) -> Self {
+        SeqWriter {
+            writer: io::BufWriter::new(file),
+            fastq,
+        }
+    }
+
+    fn write(
+        &mut self,
+        record: &fasta::Record,
+        qual: Option<&[u8]>,
+    ) -> anyhow::Result<()> {
+        let header = match record.desc() {
+            Some(desc) => format!("{} {}", record.id(), desc),
+            None => record.id().to_string(),
+        };
+        match (self.fastq, qual) {
+            (false, _) => {
+                writeln!(self.writer, ">{}", header)?;
+                self.writer.write_all(record.seq())?;
+                self.writer.write_all(b"\n")?;
+            }
+            (true, Some(qual)) => {
+                writeln!(self.writer, "@{}", header)?;
+                self.writer.write_all(record.seq())?;
+                self.writer.write_all(b"\n+\n")?;
+                self.writer.write_all(qual)?;
+                self.writer.write_all(b"\n")?;
+            }
+            (true, None) => {
+                return Err(anyhow!("No qualities for record {}", record.id()))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<()> {
+        finish_output(self.writer)
+    }
+}
+
+// FASTA, GFF and BED writers of a run, a single set of files or one set per
+// region when splitting output by region
+struct Outputs {
+    prefix: String,
+    // Prefix of the outputs of a region when splitting output by region
+    split: Option<fn(&str, &str) -> PathBuf>,
+    // Add to existing files instead of replacing them
+    append: bool,
+    // Write amplicons as FASTQ instead of FASTA
+    fastq: bool,
+    format: AnnotationFormat,
+    compression: OutputCompression,
+    writers: HashMap<String, RegionWriters>,
+    // Records given a sequence-region pragma, per GFF file
+    described: HashMap<String, HashSet<String>>,
+}
+
+// Open the FASTA, or FASTQ, and annotation files of a prefix. The GFF
+// header is only written to files without content
+fn open_outputs(
+    prefix: &str,
+    append: bool,
+    fastq: bool,
+    format: AnnotationFormat,
+    compression: OutputCompression,
+) -> anyhow::Result<RegionWriters> {
+    // Compressed files are given the extension of their compression
+    let path = |ext: &str| match compression.extension() {
+        Some(compression_ext) => {
+            format!("{}.{}.{}", prefix, ext, compression_ext)
+        }
+        None => format!("{}.{}", prefix, ext),
+    };
+    if append && compression != OutputCompression::None {
+        return Err(anyhow!(
+            "Compressed output can not be appended to {}",
+            path(if fastq { "fq" } else { "fa" })
+        ));
+    }
+    let open = |ext: &str| {
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path(ext))
+    };
+    let compress = |file: File| OutputFile::new(file, compression);
+    let seq_writer =
+        SeqWriter::new(compress(open(if fastq { "fq" } else { "fa" })?), fastq);
+    let gff_writer = if format.gff() {
+        let gff_file = open("gff")?;
+        let is_empty = gff_file.metadata()?.len() == 0;
+        let mut gff_writer = io::BufWriter::new(compress(gff_file));
+        if is_empty {
+            gff_writer.write_all(b"##gff-version 3\n")?;
+        }
+        Some(gff_writer)
+    } else {
+        None
+    };
+    let bed_writer = if format.bed() {
+        Some(io::BufWriter::new(compress(open("bed")?)))
+    } else {
+        None
+    };
+    // A JSON array can not be extended by appending to its file
+    let json_writer = if format.json() {
+        if append {
+            return Err(anyhow!(
+                "JSON output can not be appended to {}.json",
+                prefix
+            ));
+        }
+        Some(JsonWriter::new(compress(open("json")?))?)
+    } else {
+        None
+    };
+
+    Ok(RegionWriters {
+        seq: seq_writer,
+        gff: gff_writer,
+        bed: bed_writer,
+        json: json_writer,
+    })
+}
+
+impl Outputs {
+    fn new(
+        prefix: &str,
+        split: Option<fn(&str, &str) -> PathBuf>,
+        append: bool,
+        fastq: bool,
+        format: AnnotationFormat,
+        compression: OutputCompression,
+    ) -> anyhow::Result<Self> {
+        let mut outputs = Outputs {
+            prefix: prefix.to_string(),
+            split,
+            append,
+            fastq,
+            format,
+            compression,
+            writers: HashMap::new(),
+            described: HashMap::new(),
+        };
+        // Without splitting, output files are created even if no region is
+        // found
+        if split.is_none() {
+            outputs.writers.insert(
+                String::new(),
+                open_outputs(prefix, append, fastq, format, compression)?,
+            );
+        }
+
+        Ok(outputs)
+    }
+
+    // Writers of a region, all regions sharing the same writers unless
+    // output is split
+    fn key<'a>(&self, region: &'a str) -> &'a str {
+        if self.split.is_some() {
+            region
+        } else {
+            ""
+        }
+    }
+
+    fn writers(&mut self, region: &str) -> anyhow::Result<&mut RegionWriters> {
+        let key = self.key(region);
+
+        if !self.writers.contains_key(key) {
+            let prefix = match self.split {
+                Some(split) => split(&self.prefix, region),
+                None => PathBuf::from(&self.prefix),
+            };
+            if let Some(dir) = prefix.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let writers = open_outputs(
+                &prefix.to_string_lossy(),
+                self.append,
+                self.fastq,
+                self.format,
+                self.compression,
+            )?;
+            self.writers.insert(key.to_string(), writers);
+        }
+
+        Ok(self.writers.get_mut(key).unwrap())
+    }
+
+    fn write(&mut self, amplicon: &Amplicon) -> anyhow::Result<()> {
+        let (region, record) = (&amplicon.region, &amplicon.record);
+        let key = self.key(region).to_string();
+        // Records are described before their first feature of each file
+        let described = self
+            .described
+            .entry(key)
+            .or_default()
+            .insert(amplicon.seq_id.clone());
+        let writers = self.writers(region)?;
+        writers.seq.write(record, amplicon.qual.as_deref())?;
+        if let Some(gff_writer) = writers.gff.as_mut() {
+            if described {
+                writeln!(
+                    gff_writer,
+                    "##sequence-region {} 1 {}",
+                    amplicon.seq_id, amplicon.record_len
+                )?;
+            }
+            gff_writer.write_all(amplicon.gff_line.as_bytes())?;
+        }
+        if let Some(bed_writer) = writers.bed.as_mut() {
+            bed_writer.write_all(amplicon.bed_line.as_bytes())?;
+        }
+        if let Some(json_writer) = writers.json.as_mut() {
+            json_writer.write(&amplicon.json_entry)?;
+        }
+
+        Ok(())
+    }
+
+    // Close the JSON arrays and finish the files of every region
+    fn finish(self) -> anyhow::Result<()> {
+        for writers in self.writers.into_values() {
+            writers.finish()?;
+        }
+
+        Ok(())
+    }
+}
+
+// Entry of an amplicon in the JSON output, coordinates being 0-based and end
+// exclusive as in BED files
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    id: &'a str,
+    region: &'a str,
+    start: usize,
+    end: usize,
+    strand: char,
+    forward_primer: &'a str,
+    reverse_primer: &'a str,
+    fwd_mismatch: Option<u8>,
+    rev_mismatch: Option<u8>,
+    sequence: &'a str,
+}
+
+// An amplicon extracted from a record by a primer pair
+struct Amplicon {
+    pair_index: usize,
+    region: String,
+    // Id of the record in the outputs, amplicons ids being numbered when
+    // extracting all primer hits
+    seq_id: String,
+    record: fasta::Record,
+    // Qualities of the amplicon bases, for FASTQ input
+    qual: Option<Vec<u8>>,
+    gff_line: String,
+    bed_line: String,
+    // Object of the amplicon in the JSON output
+    json_entry: String,
+    // Coordinates of the amplicon in the record, 0-based and end exclusive
+    start: usize,
+    end: usize,
+    // Strand the primers were found in order on
+    strand: char,
+    // Length of the record, for the GFF sequence-region pragma
+    record_len: usize,
+    // Total edit distance of the primers hits
+    mismatch: usize,
+    partial: bool,
+    // More ambiguous bases than allowed
+    ambiguous: bool,
+    // Forward and reverse primer binding sites, when requested
+    sites: Vec<fasta::Record>,
+    // Primer-trimmed amplicon, when written alongside the untrimmed one
+    trimmed: Option<Box<Amplicon>>,
+}
+
+/// Placeholders of the amplicon fasta header templates, written as
+/// `{field}`
+pub const HEADER_FIELDS: [&str; 8] = [
+    "id",
+    "region",
+    "fwd",
+    "rev",
+    "start",
+    "end",
+    "fwd_mismatch",
+    "rev_mismatch",
+];
+
+// Fasta header of an amplicon from a template, each placeholder being
+// replaced by its value
+fn render_header(template: &str, values: &[(&str, String)]) -> String {
+    let mut header = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        header.push_str(&rest[..open]);
+        rest = &rest[open..];
+        let value = rest.find('}').and_then(|close| {
+            let (_, value) =
+                values.iter().find(|(field, _)| *field == &rest[1..close])?;
+            Some((value, close))
+        });
+        match value {
+            Some((value, close)) => {
+                header.push_str(value);
+                rest = &rest[close + 1..];
+            }
+            None => {
+                header.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    header.push_str(rest);
+
+    header
+}
+
+/// Fraction of the bases of a sequence which are not A, C, G, T or U
+pub fn ambiguous_fraction(seq: &[u8]) -> f64 {
+    if seq.is_empty() {
+        return 0.0;
+    }
+    let ambiguous = seq
+        .iter()
+        .filter(|base| !b"ACGTUacgtu".contains(base))
+        .count();
+
+    ambiguous as f64 / seq.len() as f64
+}
+
+/// Problem preventing amplicons to be extracted from a record
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RecordError {
+    /// Sequence not recognized as DNA or RNA
+    Alphabet,
+    /// Primers of a region not found
+    NotFound,
+    /// Region found but not extracted
+    Skipped,
+}
+
+impl RecordError {
+    fn describe(self) -> &'static str {
+        match self {
+            RecordError::Alphabet => "sequence type not recognized",
+            RecordError::NotFound => "region not found",
+            RecordError::Skipped => "region not extracted",
+        }
+    }
+}
+
+// Keep a record error, of a primer pair when given, for the statistics. It
+// is also logged unless collecting errors for the summary
+fn report_error(
+    options: &Options,
+    errors: &mut Vec<(RecordError, Option<usize>)>,
+    error: RecordError,
+    pair_index: Option<usize>,
+    message: std::fmt::Arguments,
+) {
+    errors.push((error, pair_index));
+    if !options.collect_errors {
+        warn!("{}", message);
+    }
+}
+
+/// Number of example record ids kept per error in the summary
+const ERROR_EXAMPLES: usize = 3;
+
+/// Records with errors, counted by error with a few example ids
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ErrorSummary {
+    errors: BTreeMap<RecordError, (usize, Vec<String>)>,
+}
+
+impl ErrorSummary {
+    // Count a record once per error it had
+    fn add(&mut self, id: &str, errors: &[(RecordError, Option<usize>)]) {
+        let mut seen = Vec::new();
+        for &(error, _) in errors {
+            if seen.contains(&error) {
+                continue;
+            }
+            seen.push(error);
+            let (count, examples) = self.errors.entry(error).or_default();
+            *count += 1;
+            if examples.len() < ERROR_EXAMPLES {
+                examples.push(id.to_string());
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// One line per error with its number of records and example ids
+    pub fn lines(&self) -> Vec<String> {
+        self.errors
+            .iter()
+            .map(|(error, (count, examples))| {
+                format!(
+                    "{}: {} record{} (e.g. {})",
+                    error.describe(),
+                    count,
+                    if *count == 1 { "" } else { "s" },
+                    examples.join(", ")
+                )
+            })
+            .collect()
+    }
+}
+
+// Primer of a pair found alone, for partial amplicons extending to the
+// sequence end. Amplicons missing a primer are not found unless partial
+// amplicons are allowed, a reverse-only pair only needing its reverse primer
+fn classify_hits(
+    hits: PairHits,
+    reverse_only: bool,
+    only_complete: bool,
+) -> Result<Option<&'static str>, RecordError> {
+    match hits {
+        (Some(_), Some(_)) => Ok(None),
+        (None, Some(_)) if reverse_only => Ok(None),
+        (Some(_), None) if !only_complete => Ok(Some("forward")),
+        (None, Some(_)) if !only_complete => Ok(Some("reverse")),
+        _ => Err(RecordError::NotFound),
+    }
+}
+
+// Slice of an amplicon on the searched strand, 0-based and end exclusive
+struct AmpliconBounds {
+    start: usize,
+    end: usize,
+    // Primer-trimmed slice, when written alongside the untrimmed one
+    trimmed: Option<std::ops::Range<usize>>,
+}
+
+// A primer pair searched on a strand of a record
+struct PairSearch<'a> {
+    record: &'a SeqRecord,
+    matchers: &'a Matchers,
+    options: &'a Options,
+    // Searched strand, as DNA, and its qualities
+    seq: &'a [u8],
+    qual: Option<&'a [u8]>,
+    // Whether the searched strand is the reverse complement of the record
+    minus: bool,
+    // Whether amplicons are written back as RNA
+    rna: bool,
+    alphabet: &'a str,
+    pair_index: usize,
+    primer_pair: &'a [String],
+    region: String,
+    output_id: &'a str,
+}
+
+impl PairSearch<'_> {
+    // Coordinates on the strand of the record as read
+    fn input_range(&self, start: usize, end: usize) -> (usize, usize) {
+        if self.minus {
+            self.record
+                .input_range(self.seq.len() - end, self.seq.len() - start)
+        } else {
+            self.record.input_range(start, end)
+        }
+    }
+
+    fn output_seq(&self, bases: &[u8]) -> Vec<u8> {
+        if self.rna {
+            dna_to_rna(bases)
+        } else {
+            bases.to_vec()
+        }
+    }
+
+    fn report(
+        &self,
+        errors: &mut Vec<(RecordError, Option<usize>)>,
+        error: RecordError,
+        message: std::fmt::Arguments,
+    ) {
+        report_error(
+            self.options,
+            errors,
+            error,
+            Some(self.pair_index),
+            message,
+        );
+    }
+
+    fn near_miss(&self, reverse: bool) {
+        report_near_miss(
+            self.matchers,
+            self.seq,
+            self.primer_pair,
+            reverse,
+            self.alphabet,
+            (&self.region, &self.record.id),
+            self.options,
+        );
+    }
+
+    // Primer hits to extract amplicons from, numbered when all primer hits
+    // are paired. The best hits are used when no primer hits can be paired
+    fn pairings(
+        &self,
+        best: PairHits,
+        reverse_only: bool,
+    ) -> (Vec<PairHits>, bool) {
+        let mut pairings = Vec::new();
+        if self.options.all_hits && !reverse_only {
+            pairings = pair_all_hits(
+                self.matchers,
+                self.seq,
+                self.primer_pair,
+                self.alphabet,
+                self.options,
+            );
+        }
+        let numbered = !pairings.is_empty();
+        if !numbered {
+            pairings.push(best);
+        }
+        (pairings, numbered)
+    }
+
+    // Report the missing primers of a pair, with their near misses
+    fn report_missing(
+        &self,
+        (forward_hit, reverse_hit): PairHits,
+        reverse_only: bool,
+        errors: &mut Vec<(RecordError, Option<usize>)>,
+    ) {
+        let (region, primer_pair) = (&self.region, self.primer_pair);
+        match (forward_hit, reverse_hit) {
+            (Some(_), None) => {
+                self.report(
+                    errors,
+                    RecordError::NotFound,
+                    format_args!("Region {} not found because primer {} was not found in the sequence", region, primer_pair[1]),
+                );
+                self.near_miss(true);
+            }
+            (None, Some(_)) => {
+                self.report(
+                    errors,
+                    RecordError::NotFound,
+                    format_args!("Region {} not found because primer {} was not found in the sequence", region, primer_pair[0]),
+                );
+                self.near_miss(false);
+                return;
+            }
+            _ => {
+                self.report(
+                    errors,
+                    RecordError::NotFound,
+                    format_args!("Region {} not found because primers {}, {} was not found in the sequence", region, primer_pair[0], primer_pair[1]),
+                );
+                if !reverse_only {
+                    self.near_miss(false);
+                }
+                self.near_miss(true);
+            }
+        }
+        report_opposite_strand(
+            self.matchers,
+            self.seq,
+            primer_pair,
+            (region, &self.record.id),
+            self.options,
+        );
+    }
+
+    // Slice of the amplicon of primer hits, unless the hits are misplaced
+    // or give an amplicon of implausible length
+    fn bounds(
+        &self,
+        (forward_hit, reverse_hit): PairHits,
+        errors: &mut Vec<(RecordError, Option<usize>)>,
+    ) -> Option<AmpliconBounds> {
+        let PairSearch {
+            record,
+            options,
+            seq,
+            primer_pair,
+            region,
+            ..
+        } = self;
+        let reverse_only = primer_pair[0].is_empty();
+
+        // A reverse primer match shortened by a deletion can end before
+        // the primer length
+        let region_end = reverse_hit
+            .map_or(seq.len(), |hit| hit.start + primer_pair[1].len())
+            .min(seq.len());
+        let forward_start = match (forward_hit, options.length) {
+            (Some(hit), _) => hit.start,
+            (None, Some(length)) if reverse_only => {
+                region_end.saturating_sub(length)
+            }
+            (None, _) => 0,
+        };
+
+        if options.dump_debug {
+            let start_of = |hit: Option<PrimerHit>| {
+                hit.map_or("-".to_string(), |hit| hit.start.to_string())
+            };
+            debug!(
+                "record={} region={} forward_start={} reverse_start={} reverse_primer_length={} seq_length={} slice={}..{}",
+                record.id,
+                region,
+                start_of(forward_hit),
+                start_of(reverse_hit),
+                primer_pair[1].len(),
+                seq.len(),
+                forward_start,
+                region_end
+            );
+        }
+
+        if forward_start >= region_end {
+            self.report(
+                errors,
+                RecordError::Skipped,
+                format_args!("Region {} not extracted from {} because primer {} was found downstream of primer {}", region, record.id, primer_pair[0], primer_pair[1]),
+            );
+            return None;
+        }
+
+        // Primers found too close to each other are likely spurious hits
+        if let (Some(forward), Some(reverse)) = (forward_hit, reverse_hit) {
+            let distance = reverse.start.saturating_sub(forward.start);
+            if let Some(min) =
+                options.min_forward_distance.filter(|&min| distance < min)
+            {
+                self.report(
+                    errors,
+                    RecordError::Skipped,
+                    format_args!("Region {} not extracted from {} because primer {} starts {} bases upstream of primer {}, less than {}", region, record.id, primer_pair[0], distance, primer_pair[1], min),
+                );
+                return None;
+            }
+            let gap = reverse.start.saturating_sub(forward.end);
+            if let Some(min) =
+                options.min_reverse_distance.filter(|&min| gap < min)
+            {
+                self.report(
+                    errors,
+                    RecordError::Skipped,
+                    format_args!("Region {} not extracted from {} because primers {}, {} are {} bases apart, less than {}", region, record.id, primer_pair[0], primer_pair[1], gap, min),
+                );
+                return None;
+            }
+        }
+
+        // Primers binding sites are left out of the amplicon on request
+        let start = match forward_hit {
+            Some(hit) if options.trim.forward() => hit.end,
+            _ => forward_start,
+        };
+        let end = match reverse_hit {
+            Some(hit) if options.trim.reverse() => hit.start.min(region_end),
+            _ => region_end,
+        };
+        if start >= end {
+            self.report(
+                errors,
+                RecordError::Skipped,
+                format_args!("Region {} not extracted from {} because nothing is left between its trimmed primers", region, record.id),
+            );
+            return None;
+        }
+        // With both outputs, the main one is left untrimmed
+        let trimmed = options.emit_both.then_some(start..end);
+        let (start, end) = if options.emit_both {
+            (forward_start, region_end)
+        } else {
+            (start, end)
+        };
+
+        // Spurious primer hits give implausibly short or long amplicons
+        let length = end - start;
+        if let Some(min) = options.min_length.filter(|&min| length < min) {
+            self.report(
+                errors,
+                RecordError::Skipped,
+                format_args!("Region {} not extracted from {} because its length {} is less than {}", region, record.id, length, min),
+            );
+            return None;
+        }
+        if let Some(max) = options.max_length.filter(|&max| length > max) {
+            self.report(
+                errors,
+                RecordError::Skipped,
+                format_args!("Region {} not extracted from {} because its length {} is greater than {}", region, record.id, length, max),
+            );
+            return None;
+        }
+        // Mispriming can give amplicons which are plausible but not of
+        // the expected length
+        if let (Some(tolerance), Some(&expected), Some(_), Some(_)) = (
+            options.length_tolerance,
+            EXPECTED_LENGTHS.get(region.as_str()),
+            forward_hit,
+            reverse_hit,
+        ) {
+            let length = region_end - forward_start;
+            let deviation = (length as f64 - expected as f64).abs()
+                / expected as f64
+                * 100.0;
+            if deviation > tolerance {
+                warn!("Region {} of {} is {} bases long, {:.1}% off its expected length of {} bases", region, record.id, length, deviation, expected);
+            }
+        }
+
+        Some(AmpliconBounds {
+            start,
+            end,
+            trimmed,
+        })
+    }
+
+    // Amplicon of primer hits, with its annotations
+    fn amplicon(
+        &self,
+        amplicon_id: &str,
+        (forward_hit, reverse_hit): PairHits,
+        partial: Option<&str>,
+        AmpliconBounds {
+            start,
+            end,
+            trimmed,
+        }: AmpliconBounds,
+    ) -> anyhow::Result<Amplicon> {
+        let PairSearch {
+            record,
+            options,
+            seq,
+            qual,
+            alphabet,
+            pair_index,
+            primer_pair,
+            region,
+            output_id,
+            ..
+        } = self;
+        let reverse_only = primer_pair[0].is_empty();
+
+        let mut desc = if !region.is_empty() {
+            format!("region={} ", region)
+        } else {
+            String::new()
+        };
+        // Primers which are not all built-in may be variants of them
+        if options.infer_region
+            && !primer_pair[..2]
+                .iter()
+                .all(|primer| PRIMER_TO_REGION.contains_key(primer.as_str()))
+        {
+            if let Some(inferred) =
+                infer_region(primer_pair).filter(|inferred| inferred != region)
+            {
+                desc.push_str(&format!("region_inferred={} ", inferred));
+            }
+        }
+        if !reverse_only {
+            desc.push_str(&format!("forward={} ", primer_pair[0]));
+        }
+        desc.push_str(&format!("reverse={}", primer_pair[1]));
+        // Edit distances of the primers hits, to tell apart weak matches
+        let mut mismatches = Vec::new();
+        if let Some(hit) = forward_hit {
+            mismatches.push(format!("fwd_mismatch={}", hit.dist));
+        }
+        if let Some(hit) = reverse_hit {
+            mismatches.push(format!("rev_mismatch={}", hit.dist));
+        }
+        for mismatch in &mismatches {
+            desc.push_str(&format!(" {}", mismatch));
+        }
+        if let Some(found) = partial {
+            desc.push_str(&format!(" partial={}", found));
+        }
+        if options.match_seq {
+            // Both matches are reported as found on the sequence strand
+            if let Some(hit) = forward_hit {
+                desc.push_str(&format!(
+                    " fwd_match_seq={}",
+                    String::from_utf8_lossy(
+                        &self.output_seq(&seq[hit.start..hit.end])
+                    )
+                ));
+            }
+            if let Some(hit) = reverse_hit {
+                desc.push_str(&format!(
+                    " rev_match_seq={}",
+                    String::from_utf8_lossy(
+                        &self.output_seq(&seq[hit.start..hit.end])
+                    )
+                ));
+            }
+        }
+        if options.degenerate_expand {
+            // Matches are reported in the orientation of the primers
+            if let Some(hit) = forward_hit {
+                let resolved = resolved_match(
+                    primer_pair[0].as_bytes(),
+                    &seq[hit.start..hit.end],
+                );
+                desc.push_str(&format!(
+                    " fwd_match={}",
+                    String::from_utf8_lossy(&self.output_seq(&resolved))
+                ));
+            }
+            if let Some(hit) = reverse_hit {
+                let resolved = resolved_match(
+                    to_reverse_complement(&primer_pair[1], alphabet).as_bytes(),
+                    &seq[hit.start..hit.end],
+                );
+                let resolved = to_reverse_complement(
+                    std::str::from_utf8(&resolved)?,
+                    alphabet,
+                );
+                desc.push_str(&format!(
+                    " rev_match={}",
+                    String::from_utf8_lossy(
+                        &self.output_seq(resolved.as_bytes())
+                    )
+                ));
+            }
+        }
+        // Amplicons with too many ambiguous bases are set aside
+        let ambiguous = match options.max_ambiguous {
+            Some(max) => {
+                let fraction = ambiguous_fraction(&seq[start..end]);
+                if fraction > max {
+                    desc.push_str(&format!(
+                        " ambiguous_fraction={:.3}",
+                        fraction
+                    ));
+                    warn!("Region {} of {} has {:.1}% ambiguous bases and is written to the ambiguous amplicons file", region, record.id, fraction * 100.0);
+                }
+                fraction > max
+            }
+            None => false,
+        };
+        // Headers tell whether primers are part of the amplicon
+        let main_trim = if options.emit_both {
+            Trim::None
+        } else {
+            options.trim
+        };
+        let trimmed_desc = format!("{} primers={}", desc, options.trim.note());
+        desc.push_str(&format!(" primers={}", main_trim.note()));
+        // Region line of the GFF3 file
+        let gff_type = options.gff_types.get(region).map_or("region", |x| x);
+        let strand = if self.minus { '-' } else { '+' };
+        // Primer binding sites, with the same coordinates as the region
+        let mut binding_sites = Vec::new();
+        for (prefix, hit) in [("fwd", forward_hit), ("rev", reverse_hit)] {
+            if let Some(hit) = hit {
+                let (site_start, site_end) =
+                    self.input_range(hit.start, hit.end);
+                binding_sites.push(format!(
+                    "{}_start={}",
+                    prefix,
+                    site_start + 1
+                ));
+                binding_sites.push(format!("{}_end={}", prefix, site_end));
+            }
+        }
+        // GFF3 attributes are tag=value pairs, an empty column being a dot
+        let note = (!region.is_empty())
+            .then(|| format!("Note=Hypervariable region {}", region));
+        let attributes = note
+            .into_iter()
+            .chain(mismatches)
+            .chain(binding_sites)
+            .collect::<Vec<_>>()
+            .join(";");
+        let attributes = if attributes.is_empty() {
+            ".".to_string()
+        } else {
+            attributes
+        };
+        // BED coordinates are 0-based and end exclusive
+        let name = if region.is_empty() {
+            format!("{}_{}", primer_pair[0], primer_pair[1])
+        } else {
+            region.clone()
+        };
+        // Amplicon of a slice of the searched strand, annotated with the
+        // coordinates of the slice
+        let annotated = |range: std::ops::Range<usize>,
+                         desc: &str|
+         -> anyhow::Result<Amplicon> {
+            let (input_start, input_end) =
+                self.input_range(range.start, range.end);
+            // A header template replaces the whole description
+            let desc = match &options.header_format {
+                Some(template) => {
+                    let mismatch = |hit: Option<PrimerHit>| {
+                        hit.map_or("NA".to_string(), |hit| hit.dist.to_string())
+                    };
+                    render_header(
+                        template,
+                        &[
+                            ("id", output_id.to_string()),
+                            ("region", region.clone()),
+                            ("fwd", primer_pair[0].clone()),
+                            ("rev", primer_pair[1].clone()),
+                            ("start", (input_start + 1).to_string()),
+                            ("end", input_end.to_string()),
+                            ("fwd_mismatch", mismatch(forward_hit)),
+                            ("rev_mismatch", mismatch(reverse_hit)),
+                        ],
+                    )
+                }
+                None => desc.to_string(),
+            };
+            let fasta_record = fasta::Record::with_attrs(
+                amplicon_id,
+                Some(desc.as_str()),
+                &self.output_seq(&seq[range.clone()]),
+            );
+            // GFF3 coordinates are 1-based and inclusive
+            let gff_line = format!(
+                "{}\thyperex\t{}\t{}\t{}\t.\t{}\t.\t{}\n",
+                output_id,
+                gff_type,
+                input_start + 1,
+                input_end,
+                strand,
+                attributes
+            );
+            let bed_line = format!(
+                "{}\t{}\t{}\t{}\t0\t{}\n",
+                output_id, input_start, input_end, name, strand
+            );
+            let json_entry = serde_json::to_string(&JsonEntry {
+                id: amplicon_id,
+                region,
+                start: input_start,
+                end: input_end,
+                strand,
+                forward_primer: &primer_pair[0],
+                reverse_primer: &primer_pair[1],
+                fwd_mismatch: forward_hit.map(|hit| hit.dist),
+                rev_mismatch: reverse_hit.map(|hit| hit.dist),
+                sequence: &String::from_utf8_lossy(fasta_record.seq()),
+            })?;
+
+            Ok(Amplicon {
+                pair_index: *pair_index,
+                region: region.clone(),
+                seq_id: output_id.to_string(),
+                record: fasta_record,
+                qual: qual.map(|qual| qual[range].to_vec()),
+                gff_line,
+                bed_line,
+                json_entry,
+                start: input_start,
+                end: input_end,
+                strand,
+                record_len: record.input_len(),
+                mismatch: [forward_hit, reverse_hit]
+                    .iter()
+                    .flatten()
+                    .map(|hit| hit.dist as usize)
+                    .sum(),
+                partial: partial.is_some(),
+                ambiguous,
+                sites: Vec::new(),
+                trimmed: None,
+            })
+        };
+        let mut amplicon = annotated(start..end, &desc)?;
+
+        // Binding sites are reported as found on the sequence strand
+        if options.primer_sites {
+            for (suffix, hit, primer) in [
+                ("fwd", forward_hit, &primer_pair[0]),
+                ("rev", reverse_hit, &primer_pair[1]),
+            ] {
+                if let Some(hit) = hit {
+                    let (site_start, site_end) =
+                        self.input_range(hit.start, hit.end);
+                    let desc = format!(
+                        "region={} primer={} start={} end={}",
+                        region,
+                        primer,
+                        site_start + 1,
+                        site_end
+                    );
+                    amplicon.sites.push(fasta::Record::with_attrs(
+                        &format!("{}_{}", amplicon_id, suffix),
+                        Some(desc.as_str()),
+                        &self.output_seq(&seq[hit.start..hit.end]),
+                    ));
+                }
+            }
+        }
+        amplicon.trimmed = trimmed
+            .map(|range| annotated(range, &trimmed_desc))
+            .transpose()?
+            .map(Box::new);
+
+        Ok(amplicon)
+    }
+}
+
+// Extract the amplicons of all primer pairs from a record
+fn extract_amplicons(
+    record: &SeqRecord,
+    primers: &[Vec<String>],
+    matchers: &Matchers,
+    cache: Option<&mut HitCache>,
+    options: &Options,
+    errors: &mut Vec<(RecordError, Option<usize>)>,
+) -> anyhow::Result<Vec<Amplicon>> {
+    let mut seq = &record.seq[..];
+    // Only the 3' end is trimmed so coordinates stay relative to the
+    // start of the untrimmed read
+    if let (Some(threshold), Some(qual)) = (options.quality_trim, &record.qual)
+    {
+        seq = &seq[..quality_trim(qual, threshold)];
+    }
+    let mut alphabet = "";
+    match sequence_type(std::str::from_utf8(seq)?) {
+        Some(alp) => {
+            if alp == Alphabet::Dna {
+                info!("Sequence type is DNA");
+                alphabet = "dna";
+            } else if alp == Alphabet::Rna {
+                info!("Sequence type is RNA");
+                alphabet = "rna";
+            }
+        }
+        None => {
+            // Primers cannot be complemented without an alphabet. Without
+            // any invalid character, the sequence mixes T and U
+            let reason = match seq.iter().position(|base| {
+                !b"ACGTURYSWKMBDHVN".contains(&base.to_ascii_uppercase())
+            }) {
+                Some(i) => format!(
+                    "invalid character '{}' at position {}",
+                    seq[i] as char,
+                    record.input_range(i, i + 1).0 + 1
+                ),
+                None => "both T and U bases".to_string(),
+            };
+            report_error(
+                options,
+                errors,
+                RecordError::Alphabet,
+                None,
+                format_args!(
+                    "Sequence of {} has {}, skipping it",
+                    record.id, reason
+                ),
+            );
+            return Ok(Vec::new());
+        }
+    }
+
+    // RNA is searched as DNA so that DNA primers match it, amplicons being
+    // written back as RNA
+    let rna = alphabet == "rna";
+    let dna_seq;
+    if rna {
+        dna_seq = rna_to_dna(seq);
+        seq = &dna_seq;
+        alphabet = "dna";
+    }
+
+    // Hits of the primers on a strand, cached when requested
+    let mut cache = cache;
+    let mut search = |seq: &[u8]| match cache.as_deref_mut() {
+        Some(cache) => match cache.get(seq) {
+            Some(hits) => hits,
+            None => {
+                let hits =
+                    find_primer_hits(matchers, seq, primers, alphabet, options);
+                cache.insert(seq, hits.clone());
+                hits
+            }
+        },
+        None => find_primer_hits(matchers, seq, primers, alphabet, options),
+    };
+    let hits = if options.strand == Strand::Minus {
+        Vec::new()
+    } else {
+        search(seq)
+    };
+
+    // Sequences stored on the other strand only have their primers in order
+    // on their reverse complement
+    let minus_seq = match options.strand {
+        Strand::Plus => None,
+        Strand::Both
+            if primers.iter().zip(&hits).all(|(primer_pair, &hit)| {
+                primers_in_order(hit, primer_pair)
+            }) =>
+        {
+            None
+        }
+        _ => Some(to_reverse_complement(std::str::from_utf8(seq)?, alphabet)),
+    };
+    let minus_hits = match &minus_seq {
+        Some(minus_seq) => search(minus_seq.as_bytes()),
+        None => Vec::new(),
+    };
+    let minus_qual = match (&minus_seq, &record.qual) {
+        (Some(_), Some(qual)) => {
+            Some(qual[..seq.len()].iter().rev().copied().collect::<Vec<_>>())
+        }
+        _ => None,
+    };
+
+    let output_id = options.output_id(&record.id);
+    let agreement_hits = |seq: &[u8]| match options.strand_agreement {
+        Some(_) => minus_strand_hits(matchers, seq, primers, alphabet, options),
+        None => Ok(Vec::new()),
+    };
+    let plus_agreement = agreement_hits(seq)?;
+    let minus_agreement = match &minus_seq {
+        Some(minus_seq) => agreement_hits(minus_seq.as_bytes())?,
+        None => Vec::new(),
+    };
+
+    let mut amplicons = Vec::new();
+
+    for (pair_index, primer_pair) in primers.iter().enumerate() {
+        let minus = match options.strand {
+            Strand::Plus => false,
+            Strand::Minus => true,
+            Strand::Both => {
+                minus_seq.is_some()
+                    && !primers_in_order(hits[pair_index], primer_pair)
+                    && primers_in_order(minus_hits[pair_index], primer_pair)
+            }
+        };
+        let (seq, (forward_hit, reverse_hit), agreement) = match &minus_seq {
+            Some(minus_seq) if minus => (
+                minus_seq.as_bytes(),
+                minus_hits[pair_index],
+                &minus_agreement,
+            ),
+            _ => (seq, hits[pair_index], &plus_agreement),
+        };
+        let search = PairSearch {
+            record,
+            matchers,
+            options,
+            seq,
+            qual: if minus {
+                minus_qual.as_deref()
+            } else {
+                record.qual.as_deref()
+            },
+            minus,
+            rna,
+            alphabet,
+            pair_index,
+            primer_pair,
+            region: primers_to_region(primer_pair),
+            output_id: &output_id,
+        };
+
+        // Primers with several equally good hits can be placed differently
+        // when searched on each strand
+        if let Some(tolerance) = options.strand_agreement {
+            let (minus_forward, minus_reverse) = agreement[pair_index];
+            if !strands_agree(forward_hit, minus_forward, tolerance)
+                || !strands_agree(reverse_hit, minus_reverse, tolerance)
+            {
+                search.report(
+                    errors,
+                    RecordError::Skipped,
+                    format_args!("Region {} not extracted from {} because primers {}, {} were not found at the same place on both strands", search.region, record.id, primer_pair[0], primer_pair[1]),
+                );
+                continue;
+            }
+        }
+
+        // Without forward primer, the amplicon is only defined by the
+        // reverse primer hit
+        let reverse_only = primer_pair[0].is_empty();
+
+        // All amplicons of a record are numbered, the best hits being used
+        // when no primer hits can be paired
+        let (pairings, numbered) =
+            search.pairings((forward_hit, reverse_hit), reverse_only);
+        for (number, pair_hits) in pairings.into_iter().enumerate() {
+            let amplicon_id = if numbered {
+                format!("{}_{}", output_id, number + 1)
+            } else {
+                output_id.clone()
+            };
+
+            let partial = match classify_hits(
+                pair_hits,
+                reverse_only,
+                options.only_complete,
+            ) {
+                Ok(partial) => partial,
+                Err(_) => {
+                    search.report_missing(pair_hits, reverse_only, errors);
+                    continue;
+                }
+            };
+            let bounds = match search.bounds(pair_hits, errors) {
+                Some(bounds) => bounds,
+                None => continue,
+            };
+            amplicons.push(search.amplicon(
+                &amplicon_id,
+                pair_hits,
+                partial,
+                bounds,
+            )?);
+        }
+    }
+
+    // Malformed records, such as concatenated sequences, could yield a
+    // runaway number of amplicons
+    if let Some(max) = options.max_regions_per_record {
+        if amplicons.len() > max {
+            warn!(
+                "{} has {} regions, only the first {} are written (see --max-regions-per-record)",
+                record.id,
+                amplicons.len(),
+                max
+            );
+            amplicons.truncate(max);
+        }
+    }
+
+    Ok(amplicons)
+}
+
+// Number of intervals covering each position, as maximal runs of positions
+// covered by the same number of intervals. Intervals are end exclusive and
+// uncovered positions are not reported
+fn coverage(intervals: &[(usize, usize)]) -> Vec<(usize, usize, usize)> {
+    let mut events = intervals
+        .iter()
+        .flat_map(|&(start, end)| [(start, 1), (end, -1)])
+        .collect::<Vec<(usize, i64)>>();
+    events.sort_unstable();
+
+    let mut runs = Vec::new();
+    let mut depth = 0;
+    let mut previous = 0;
+    for (position, change) in events {
+        if position > previous && depth > 0 {
+            match runs.last_mut() {
+                Some((_, end, d))
+                    if *end == previous && *d == depth as usize =>
+                {
+                    *end = position
+                }
+                _ => runs.push((previous, position, depth as usize)),
+            }
+        }
+        depth += change;
+        previous = position;
+    }
+
+    runs
+}
+
+/// Histogram of lengths as bins of `bin` lengths, from the bin of the
+/// shortest length to the bin of the longest one. Bins are given by their
+/// first length with their count, empty bins included
+fn length_histogram(lengths: &[usize], bin: usize) -> Vec<(usize, usize)> {
+    let (min, max) = match (lengths.iter().min(), lengths.iter().max()) {
+        (Some(min), Some(max)) => (min / bin, max / bin),
+        _ => return Vec::new(),
+    };
+    let mut counts = vec![0; max - min + 1];
+    for length in lengths {
+        counts[length / bin - min] += 1;
+    }
+
+    counts
+        .into_iter()
+        .enumerate()
+        .map(|(i, count)| ((min + i) * bin, count))
+        .collect()
+}
+
+fn write_length_histograms(
+    prefix: &str,
+    labels: &[String],
+    lengths: &[Vec<usize>],
+    bin: usize,
+) -> anyhow::Result<()> {
+    let mut writer =
+        io::BufWriter::new(File::create(format!("{}.lengths.tsv", prefix))?);
+    writeln!(writer, "region\tbin_start\tbin_end\tcount")?;
+    for (label, lengths) in labels.iter().zip(lengths) {
+        for (start, count) in length_histogram(lengths, bin) {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}",
+                label,
+                start,
+                start + bin - 1,
+                count
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Summary of amplicon lengths
+#[derive(Debug, PartialEq)]
+struct LengthSummary {
+    count: usize,
+    min: usize,
+    max: usize,
+    mean: f64,
+    median: f64,
+}
+
+// Count, extremes, mean and median of lengths, None without lengths
+fn length_summary(lengths: &[usize]) -> Option<LengthSummary> {
+    let mut sorted = lengths.to_vec();
+    sorted.sort_unstable();
+    let count = sorted.len();
+    let (&min, &max) = (sorted.first()?, sorted.last()?);
+    let median = if count.is_multiple_of(2) {
+        (sorted[count / 2 - 1] + sorted[count / 2]) as f64 / 2.0
+    } else {
+        sorted[count / 2] as f64
+    };
+
+    Some(LengthSummary {
+        count,
+        min,
+        max,
+        mean: sorted.iter().sum::<usize>() as f64 / count as f64,
+        median,
+    })
+}
+
+fn write_length_stats(
+    prefix: &str,
+    labels: &[String],
+    lengths: &[Vec<usize>],
+) -> anyhow::Result<()> {
+    let mut writer =
+        io::BufWriter::new(File::create(format!("{}.stats.tsv", prefix))?);
+    writeln!(writer, "region\tcount\tmin\tmax\tmean\tmedian")?;
+    for (label, lengths) in labels.iter().zip(lengths) {
+        match length_summary(lengths) {
+            Some(summary) => writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{:.2}\t{}",
+                label,
+                summary.count,
+                summary.min,
+                summary.max,
+                summary.mean,
+                summary.median
+            )?,
+            None => writeln!(writer, "{}\t0\tNA\tNA\tNA\tNA", label)?,
+        }
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Number of amplicons inserted in the SQLite database per transaction
+#[cfg(feature = "sqlite")]
+const SQLITE_BATCH: usize = 10_000;
+
+/// Amplicons written to the `hits` table of an SQLite database, in batched
+/// transactions
+#[cfg(feature = "sqlite")]
+struct SqliteWriter {
+    connection: rusqlite::Connection,
+    // Amplicons inserted in the current transaction
+    pending: usize,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteWriter {
+    fn new(path: &Path) -> anyhow::Result<Self> {
+        let connection =
+            rusqlite::Connection::open(path).with_context(|| {
+                format!("Cannot open SQLite database {}", path.display())
+            })?;
+        connection.execute_batch(
+            "CREATE TABLE hits (
+                record_id TEXT NOT NULL,
+                region TEXT NOT NULL,
+                start INTEGER NOT NULL,
+                end INTEGER NOT NULL,
+                strand TEXT NOT NULL,
+                sequence TEXT NOT NULL,
+                mismatches INTEGER NOT NULL
+            );
+            BEGIN;",
+        )?;
+
+        Ok(SqliteWriter {
+            connection,
+            pending: 0,
+        })
+    }
+
+    fn insert(
+        &mut self,
+        id: &str,
+        region: &str,
+        amplicon: &Amplicon,
+    ) -> anyhow::Result<()> {
+        self.connection
+            .prepare_cached(
+                "INSERT INTO hits VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?
+            .execute(rusqlite::params![
+                id,
+                region,
+                amplicon.start as i64,
+                amplicon.end as i64,
+                amplicon.strand.to_string(),
+                String::from_utf8_lossy(amplicon.record.seq()),
+                amplicon.mismatch as i64,
+            ])?;
+        self.pending += 1;
+        if self.pending == SQLITE_BATCH {
+            self.connection.execute_batch("COMMIT; BEGIN;")?;
+            self.pending = 0;
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        self.connection.execute_batch("COMMIT;")?;
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+struct SqliteWriter;
+
+#[cfg(not(feature = "sqlite"))]
+impl SqliteWriter {
+    fn new(path: &Path) -> anyhow::Result<Self> {
+        Err(anyhow!(
+            "Cannot write {}: hyperex was built without SQLite support",
+            path.display()
+        ))
+    }
+
+    fn insert(
+        &mut self,
+        _id: &str,
+        _region: &str,
+        _amplicon: &Amplicon,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Extraction counts of a region
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RegionStats {
+    /// Number of amplicons written
+    pub extracted: usize,
+    /// Number of records in which a primer of the region was not found
+    pub not_found: usize,
+}
+
+/// Summary of an extraction run
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtractionStats {
+    /// Number of records read
+    pub records: usize,
+    /// Counts per region, in the order of the primers
+    pub regions: Vec<(String, RegionStats)>,
+    /// Record errors, only collected with `collect_errors`
+    pub errors: ErrorSummary,
+}
+
+/// Write extraction statistics as lines prefixed by `STATS`, so that they
+/// can be told apart from log messages
+pub fn write_stats<W: Write>(
+    writer: &mut W,
+    stats: &ExtractionStats,
+) -> io::Result<()> {
+    writeln!(writer, "STATS\trecords\t{}", stats.records)?;
+    for (region, counts) in &stats.regions {
+        writeln!(writer, "STATS\tregion\t{}\t{}", region, counts.extracted)?;
+    }
+
+    Ok(())
+}
+
+// Add the archive member a record comes from to its fasta header
+fn with_source(record: &fasta::Record, source: &str) -> fasta::Record {
+    let desc =
+        format!("{} source={}", record.desc().unwrap_or_default(), source);
+    fasta::Record::with_attrs(
+        record.id(),
+        Some(desc.trim_start()),
+        record.seq(),
+    )
+}
+
+// Spinner counting the records read, shown on terminals only
+fn progress_bar(options: &Options) -> Option<ProgressBar> {
+    if !options.progress || !io::stderr().is_terminal() {
+        return None;
+    }
+    let progress = ProgressBar::new_spinner();
+    progress.set_style(
+        ProgressStyle::with_template(
+            "{spinner} {human_pos} records read ({per_sec}, {elapsed})",
+        )
+        .ok()?,
+    );
+
+    Some(progress)
+}
+
+// Sequence of a record with the bases of the given input ranges, end
+// exclusive, in lowercase and the other ones in uppercase
+fn masked_seq(record: &SeqRecord, ranges: &[(usize, usize)]) -> Vec<u8> {
+    record
+        .seq
+        .iter()
+        .enumerate()
+        .map(|(i, base)| {
+            let position = record.positions.as_ref().map_or(i, |x| x[i]);
+            if ranges
+                .iter()
+                .any(|&(start, end)| (start..end).contains(&position))
+            {
+                base.to_ascii_lowercase()
+            } else {
+                base.to_ascii_uppercase()
+            }
+        })
+        .collect()
+}
+
+// Destination of the extracted amplicons: output files, buffers of grouped
+// amplicons and of the consensus, concatenated amplicons
+struct Sink<'a> {
+    // Amplicon and annotation files, not written for a presence matrix
+    outputs: Option<Outputs>,
+    // Same files for the primer-trimmed amplicons, when written alongside
+    // the untrimmed ones
+    trimmed_outputs: Option<Outputs>,
+    options: &'a Options,
+    // Amplicons are labelled by region, or by primers when the region is
+    // unknown
+    labels: Vec<String>,
+    // Extracted amplicons kept per region for the consensus pass, in the
+    // order of the supplied primers
+    amplicons: Vec<(String, Vec<Vec<u8>>)>,
+    amplicon_index: HashMap<String, usize>,
+    concat_writer: Option<fasta::Writer<File>>,
+    sites_writer: Option<fasta::Writer<File>>,
+    // Amplicons set aside, in the format of the main output
+    ambiguous_writer: Option<SeqWriter>,
+    coverage_writer: Option<io::BufWriter<File>>,
+    presence_writer: Option<io::BufWriter<File>>,
+    mask_writer: Option<fasta::Writer<File>>,
+    sqlite_writer: Option<SqliteWriter>,
+    // Lengths of the written amplicons per primer pair, for the histogram
+    lengths: Vec<Vec<usize>>,
+    // Number of records read, of amplicons written and of records missing
+    // primers per primer pair
+    records: usize,
+    extracted: Vec<usize>,
+    not_found: Vec<usize>,
+    // Amplicons buffered per primer pair when grouping output by region
+    grouped: Vec<Vec<Amplicon>>,
+    // Sequences written per primer pair and number of duplicates left out,
+    // when deduplicating
+    written: HashSet<(usize, Vec<u8>)>,
+    duplicates: usize,
+    // Archive member the records come from, added to the fasta headers
+    source: Option<String>,
+    // Sample the records come from, prefixed to their ids when several
+    // input files are merged
+    sample: Option<String>,
+    // Whether amplicons are written as FASTQ
+    fastq: bool,
+    // Whether aligned input was already reported, for archives
+    gaps_warned: bool,
+    // Whether a short sequence was already reported
+    short_warned: bool,
+    errors: ErrorSummary,
+    // Counter of the records read, records being streamed so that their
+    // total is unknown
+    progress: Option<ProgressBar>,
+}
+
+impl<'a> Sink<'a> {
+    fn new(
+        prefix: &str,
+        primers: &[Vec<String>],
+        options: &'a Options,
+        fastq: bool,
+    ) -> anyhow::Result<Self> {
+        let labels = primers
+            .iter()
+            .map(|primer_pair| match primers_to_region(primer_pair) {
+                region if region.is_empty() => {
+                    format!("{}_{}", primer_pair[0], primer_pair[1])
+                }
+                region => region,
+            })
+            .collect::<Vec<_>>();
+        let concat_writer = match options.concat {
+            Some(_) => {
+                Some(fasta::Writer::to_file(format!("{}.concat.fa", prefix))?)
+            }
+            None => None,
+        };
+        let ambiguous_writer = match options.max_ambiguous {
+            Some(_) => Some(SeqWriter::new(
+                OutputFile::Plain(File::create(format!(
+                    "{}.ambiguous.{}",
+                    prefix,
+                    if fastq { "fq" } else { "fa" }
+                ))?),
+                fastq,
+            )),
+            None => None,
+        };
+        let coverage_writer = if options.coverage {
+            let mut writer = io::BufWriter::new(File::create(format!(
+                "{}.bedgraph",
+                prefix
+            ))?);
+            writeln!(writer, "track type=bedGraph name=hyperex")?;
+            Some(writer)
+        } else {
+            None
+        };
+        let presence_writer = if options.presence.is_some() {
+            let mut writer = io::BufWriter::new(File::create(format!(
+                "{}.presence.tsv",
+                prefix
+            ))?);
+            writeln!(writer, "id\t{}", labels.join("\t"))?;
+            Some(writer)
+        } else {
+            None
+        };
+        let mask_writer = if options.mask {
+            Some(fasta::Writer::to_file(format!("{}.masked.fa", prefix))?)
+        } else {
+            None
+        };
+        let sites_writer = if options.primer_sites {
+            Some(fasta::Writer::to_file(format!("{}.sites.fa", prefix))?)
+        } else {
+            None
+        };
+
+        let sqlite_writer = match &options.sqlite {
+            Some(path) => Some(SqliteWriter::new(path)?),
+            None => None,
+        };
+
+        let split: Option<fn(&str, &str) -> PathBuf> = if options.split_dir {
+            Some(region_dir_prefix)
+        } else if options.split_by_region {
+            Some(region_file_prefix)
+        } else {
+            None
+        };
+        let open = |prefix: &str| {
+            Outputs::new(
+                prefix,
+                split,
+                options.append,
+                fastq,
+                options.format,
+                options.compression,
+            )
+        };
+        let outputs = if options.presence.is_some() {
+            None
+        } else {
+            Some(open(prefix)?)
+        };
+        let trimmed_outputs = if options.emit_both && outputs.is_some() {
+            Some(open(&format!("{}.trimmed", prefix))?)
+        } else {
+            None
+        };
+
+        Ok(Sink {
+            outputs,
+            trimmed_outputs,
+            options,
+            labels,
+            amplicons: Vec::new(),
+            amplicon_index: HashMap::new(),
+            concat_writer,
+            sites_writer,
+            ambiguous_writer,
+            coverage_writer,
+            presence_writer,
+            mask_writer,
+            sqlite_writer,
+            lengths: vec![Vec::new(); primers.len()],
+            records: 0,
+            extracted: vec![0; primers.len()],
+            not_found: vec![0; primers.len()],
+            grouped: (0..primers.len()).map(|_| Vec::new()).collect(),
+            written: HashSet::new(),
+            duplicates: 0,
+            source: None,
+            sample: None,
+            fastq,
+            gaps_warned: false,
+            short_warned: false,
+            errors: ErrorSummary::default(),
+            progress: progress_bar(options),
+        })
+    }
+
+    fn write(
+        &mut self,
+        record: &SeqRecord,
+        amplicons: Vec<Amplicon>,
+        errors: &[(RecordError, Option<usize>)],
+    ) -> anyhow::Result<()> {
+        let id = &self.options.output_id(&record.id);
+        if self.options.collect_errors {
+            self.errors.add(id, errors);
+        }
+        for &(error, pair_index) in errors {
+            if let (RecordError::NotFound, Some(pair_index)) =
+                (error, pair_index)
+            {
+                self.not_found[pair_index] += 1;
+            }
+        }
+        // Inputs of amplicons, ITS or 18S sequences are all short, so that
+        // only the first short sequence is reported
+        if !self.short_warned && record.seq.len() <= 1500 {
+            warn!("Sequence of {} is less than 1500 bp long, some regions may not be found. Other short sequences are not reported", record.id);
+            self.short_warned = true;
+        }
+        let mut concat_parts: Vec<Option<Vec<u8>>> =
+            vec![None; self.labels.len()];
+        let mut covered = Vec::new();
+        // Lowest edit distance of the amplicons of each primer pair
+        let mut presence: Vec<Option<usize>> = vec![None; self.labels.len()];
+        self.records += 1;
+        if let Some(progress) = &self.progress {
+            progress.inc(1);
+        }
+
+        for mut amplicon in amplicons {
+            if let Some(source) = &self.source {
+                amplicon.record = with_source(&amplicon.record, source);
+                if let Some(trimmed) = amplicon.trimmed.as_mut() {
+                    trimmed.record = with_source(&trimmed.record, source);
+                }
+            }
+            // Ambiguous amplicons are left out of all other outputs
+            if amplicon.ambiguous {
+                if let Some(writer) = self.ambiguous_writer.as_mut() {
+                    writer.write(&amplicon.record, amplicon.qual.as_deref())?;
+                }
+                continue;
+            }
+            if let Some(writer) = self.sqlite_writer.as_mut() {
+                writer.insert(
+                    id,
+                    &self.labels[amplicon.pair_index],
+                    &amplicon,
+                )?;
+            }
+            if let Some(writer) = self.sites_writer.as_mut() {
+                for site in &amplicon.sites {
+                    writer.write_record(site)?;
+                }
+            }
+            if self.options.consensus && !amplicon.partial {
+                let key = &self.labels[amplicon.pair_index];
+                let amplicons = &mut self.amplicons;
+                let index = *self
+                    .amplicon_index
+                    .entry(key.clone())
+                    .or_insert_with(|| {
+                        amplicons.push((key.clone(), Vec::new()));
+                        amplicons.len() - 1
+                    });
+                amplicons[index].1.push(amplicon.record.seq().to_vec());
+            }
+            if self.options.concat.is_some() {
+                concat_parts[amplicon.pair_index] =
+                    Some(amplicon.record.seq().to_vec());
+            }
+
+            covered.push((amplicon.start, amplicon.end));
+            let distance = &mut presence[amplicon.pair_index];
+            *distance = Some(
+                distance
+                    .map_or(amplicon.mismatch, |d| d.min(amplicon.mismatch)),
+            );
+            self.extracted[amplicon.pair_index] += 1;
+            if self.options.length_bin.is_some() || self.options.length_stats {
+                self.lengths[amplicon.pair_index]
+                    .push(amplicon.record.seq().len());
+            }
+
+            if self.options.group_by_region {
+                self.grouped[amplicon.pair_index].push(amplicon);
+            } else {
+                self.write_amplicon(&amplicon)?;
+            }
+        }
+
+        if let Some(writer) = self.coverage_writer.as_mut() {
+            for (start, end, depth) in coverage(&covered) {
+                writeln!(writer, "{}\t{}\t{}\t{}", id, start, end, depth)?;
+            }
+        }
+
+        if let (Some(values), Some(writer)) =
+            (self.options.presence, self.presence_writer.as_mut())
+        {
+            let row = presence
+                .iter()
+                .map(|distance| match (values, distance) {
+                    (Presence::Binary, Some(_)) => "1".to_string(),
+                    (Presence::Binary, None) => "0".to_string(),
+                    (Presence::Mismatch, Some(distance)) => {
+                        distance.to_string()
+                    }
+                    (Presence::Mismatch, None) => "NA".to_string(),
+                })
+                .collect::<Vec<_>>();
+            writeln!(writer, "{}\t{}", id, row.join("\t"))?;
+        }
+
+        if let Some(writer) = self.mask_writer.as_mut() {
+            writer.write(
+                id,
+                record.desc.as_deref(),
+                &masked_seq(record, &covered),
+            )?;
+        }
+
+        if let (Some(concat), Some(writer)) =
+            (&self.options.concat, self.concat_writer.as_mut())
+        {
+            match concat_amplicons(id, &self.labels, &concat_parts, concat) {
+                Some(concat_record) => writer.write_record(&concat_record)?,
+                None => warn!(
+                    "Regions of {} not concatenated as some were not extracted",
+                    id
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Write an amplicon to the main outputs
+    fn write_amplicon(&mut self, amplicon: &Amplicon) -> anyhow::Result<()> {
+        if self.options.dedup
+            && !self
+                .written
+                .insert((amplicon.pair_index, amplicon.record.seq().to_vec()))
+        {
+            self.duplicates += 1;
+            return Ok(());
+        }
+        if let Some(outputs) = self.outputs.as_mut() {
+            outputs.write(amplicon)?;
+        }
+        if let (Some(outputs), Some(trimmed)) =
+            (self.trimmed_outputs.as_mut(), &amplicon.trimmed)
+        {
+            outputs.write(trimmed)?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self, prefix: &str) -> anyhow::Result<ExtractionStats> {
+        // Buffered amplicons are written in the order of the supplied primers
+        for amplicon in std::mem::take(&mut self.grouped).iter().flatten() {
+            self.write_amplicon(amplicon)?;
+        }
+        if let Some(outputs) = self.outputs.take() {
+            outputs.finish()?;
+        }
+        if let Some(outputs) = self.trimmed_outputs.take() {
+            outputs.finish()?;
+        }
+        if self.options.dedup {
+            info!("{} duplicated amplicons were not written", self.duplicates);
+        }
+        if let Some(progress) = &self.progress {
+            progress.finish_and_clear();
+        }
+
+        if self.options.consensus {
+            write_consensus(prefix, &self.amplicons)?;
+        }
+
+        if let Some(bin) = self.options.length_bin {
+            write_length_histograms(prefix, &self.labels, &self.lengths, bin)?;
+        }
+
+        if self.options.length_stats {
+            write_length_stats(prefix, &self.labels, &self.lengths)?;
+        }
+
+        // Side outputs are flushed here for their errors to be reported
+        for writer in [
+            self.concat_writer.as_mut(),
+            self.sites_writer.as_mut(),
+            self.mask_writer.as_mut(),
+        ]
+        .iter_mut()
+        .flatten()
+        {
+            writer.flush()?;
+        }
+        for writer in
+            [self.coverage_writer.as_mut(), self.presence_writer.as_mut()]
+                .iter_mut()
+                .flatten()
+        {
+            writer.flush()?;
+        }
+        if let Some(writer) = self.ambiguous_writer {
+            writer.finish()?;
+        }
+        if let Some(writer) = self.sqlite_writer {
+            writer.finish()?;
+        }
+
+        Ok(ExtractionStats {
+            records: self.records,
+            regions: self
+                .labels
+                .into_iter()
+                .zip(self.extracted.into_iter().zip(self.not_found))
+                .map(|(label, (extracted, not_found))| {
+                    (
+                        label,
+                        RegionStats {
+                            extracted,
+                            not_found,
+                        },
+                    )
+                })
+                .collect(),
+            errors: self.errors,
+        })
+    }
+}
+
+// Extract amplicons of records on several threads. Records are searched in
+// any order and, when keeping order, their amplicons are buffered until all
+// the previous records were written. Records are only read while less than
+// `threads * 16` are waiting to be written, so that a slow record does not
+// let the buffer grow with the input
+fn extract_parallel(
+    records: SeqRecords<'_>,
+    primers: &[Vec<String>],
+    matchers: &Matchers,
+    options: &Options,
+    sink: &mut Sink,
+) -> anyhow::Result<()> {
+    thread::scope(|scope| {
+        let in_flight = options.threads * 16;
+        let (job_tx, job_rx) =
+            mpsc::sync_channel::<(usize, SeqRecord)>(in_flight);
+        // A slot is taken for each record read and freed once it is written
+        let (slot_tx, slot_rx) = mpsc::sync_channel::<()>(in_flight);
+        // Receiver is dropped with the last worker so that reading stops
+        // if writing failed
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let (result_tx, result_rx) = mpsc::channel();
+
+        for _ in 0..options.threads {
+            let job_rx = Arc::clone(&job_rx);
+            let result_tx = result_tx.clone();
+            // Each worker has its own matchers and cache, caches holding
+            // up to threads × N sequences in all
+            let matchers = matchers.clone();
+            scope.spawn(move || {
+                let mut cache = options.cache.map(HitCache::new);
+                loop {
+                    let job = job_rx.lock().unwrap().recv();
+                    let (index, record) = match job {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    let mut errors = Vec::new();
+                    let extracted = extract_amplicons(
+                        &record,
+                        primers,
+                        &matchers,
+                        cache.as_mut(),
+                        options,
+                        &mut errors,
+                    )
+                    .map(|amplicons| (record, amplicons, errors));
+                    if result_tx.send((index, extracted)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(job_rx);
+        drop(result_tx);
+
+        let writer = scope.spawn(move || -> anyhow::Result<()> {
+            // Reorder buffer of amplicons of records read after a record
+            // not yet written
+            let mut pending = BTreeMap::new();
+            let mut next = 0;
+            for (index, extracted) in result_rx {
+                if !options.keep_order {
+                    let (record, amplicons, errors) = extracted?;
+                    sink.write(&record, amplicons, &errors)?;
+                    slot_rx.recv().ok();
+                    continue;
+                }
+                pending.insert(index, extracted);
+                while let Some(extracted) = pending.remove(&next) {
+                    let (record, amplicons, errors) = extracted?;
+                    sink.write(&record, amplicons, &errors)?;
+                    slot_rx.recv().ok();
+                    next += 1;
+                }
+            }
+
+            Ok(())
+        });
+
+        // Reading stops at the first unreadable record, whose error is
+        // returned once the records read before it are written
+        let mut read_error = None;
+        for (index, record) in records.enumerate() {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    read_error = Some(e);
+                    break;
+                }
+            };
+            if slot_tx.send(()).is_err()
+                || job_tx.send((index, record)).is_err()
+            {
+                break;
+            }
+        }
+        drop(job_tx);
+
+        writer.join().unwrap()?;
+        match read_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    })
+}
+
+// Extract amplicons of all records, on several threads if requested
+fn extract_records(
+    mut records: SeqRecords<'_>,
+    primers: &[Vec<String>],
+    matchers: &Matchers,
+    options: &Options,
+    sink: &mut Sink,
+) -> anyhow::Result<()> {
+    // When resuming, records are skipped until the first processed one
+    if let (Some(id), 0) = (&options.resume_from, sink.records) {
+        let id = id.clone();
+        records = Box::new(records.skip_while(
+            move |record| matches!(record, Ok(record) if record.id != id),
+        ));
+    }
+
+    // Ids of records from different samples could collide
+    if let Some(sample) = sink.sample.clone() {
+        records = Box::new(records.map(move |record| {
+            record.map(|mut record| {
+                record.id = format!("{}_{}", sample, record.id);
+                record
+            })
+        }));
+    }
+
+    if options.threads > 1 {
+        return extract_parallel(records, primers, matchers, options, sink);
+    }
+
+    let mut cache = options.cache.map(HitCache::new);
+
+    for record in records {
+        let record = record?;
+        let mut errors = Vec::new();
+        let amplicons = extract_amplicons(
+            &record,
+            primers,
+            matchers,
+            cache.as_mut(),
+            options,
+            &mut errors,
+        )?;
+        sink.write(&record, amplicons, &errors)?;
+    }
+
+    Ok(())
+}
+
+/// Whether a file is a tar archive, possibly compressed, from its name
+pub fn is_tar_archive(filename: &str) -> bool {
+    [".tar", ".tar.gz", ".tgz", ".tar.xz", ".tar.bz2", ".tar.zst"]
+        .iter()
+        .any(|ext| filename.ends_with(ext))
+}
+
+// File name without its compression extension
+fn strip_compression(name: &str) -> &str {
+    [".gz", ".xz", ".bz2", ".zst"]
+        .iter()
+        .find_map(|ext| name.strip_suffix(ext))
+        .unwrap_or(name)
+}
+
+/// Whether a file is a FASTQ file from its name, possibly compressed
+pub fn is_fastq_file(filename: &str) -> bool {
+    let name = strip_compression(filename);
+    name.ends_with(".fq") || name.ends_with(".fastq")
+}
+
+/// Whether the amplicons of an input file are written as FASTQ, as told by
+/// its first record. Archive members are always written as FASTA
+pub fn is_fastq_input(file: &str, format: InputFormat) -> anyhow::Result<bool> {
+    if is_tar_archive(file) {
+        return Ok(false);
+    }
+    let (_, fastq) = read_records(file, format)
+        .with_context(|| format!("Cannot read file {}", file))?;
+
+    Ok(fastq)
+}
+
+/// Name of the sample of an input file, the file name without its
+/// directory, compression and sequence or archive extensions
+pub fn sample_name(filename: &str) -> String {
+    let name = Path::new(filename)
+        .file_name()
+        .map_or(filename.to_string(), |name| {
+            name.to_string_lossy().to_string()
+        });
+    let name = strip_compression(&name);
+
+    [
+        ".tar", ".tgz", ".fa", ".fasta", ".fna", ".fas", ".ffn", ".fq",
+        ".fastq",
+    ]
+    .iter()
+    .find_map(|ext| name.strip_suffix(ext))
+    .unwrap_or(name)
+    .to_string()
+}
+
+// Whether a tar archive member is a sequence file from its name, possibly
+// compressed
+#[cfg(feature = "tar")]
+fn is_sequence_file(name: &str) -> bool {
+    let name = strip_compression(name);
+
+    [".fa", ".fasta", ".fna", ".fas", ".ffn", ".fq", ".fastq"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
+
+// Extract amplicons of the sequence files of a tar archive, amplicons being
+// tagged with the name of the member they come from
+#[cfg(feature = "tar")]
+fn extract_tar(
+    file: &str,
+    primers: &[Vec<String>],
+    matchers: &Matchers,
+    options: &Options,
+    sink: &mut Sink,
+) -> anyhow::Result<()> {
+    let (reader, _compression) = read_file(file, options.input_format)?;
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        if !entry.header().entry_type().is_file() || !is_sequence_file(&name) {
+            continue;
+        }
+        info!("Reading {} from {}", name, file);
+
+        let (reader, _compression) = niffler::get_reader(Box::new(entry))?;
+        let (records, is_fastq) = records_from_reader(reader)
+            .with_context(|| format!("Cannot read {} from {}", name, file))?;
+        if options.quality_trim.is_some() && !is_fastq {
+            warn!("{} is not FASTQ, quality trimming is ignored", name);
+        }
+
+        let records = check_gaps(
+            sanitize_records(records),
+            options,
+            &mut sink.gaps_warned,
+        );
+        sink.source = Some(name);
+        extract_records(records, primers, matchers, options, sink)?;
+    }
+    sink.source = None;
+
+    Ok(())
+}
+
+/// Number of records looked at for alignment gaps
+const GAP_CHECK_RECORDS: usize = 10;
+
+fn is_gap(base: &u8) -> bool {
+    *base == b'-' || *base == b'.'
+}
+
+// Remove alignment gaps from records, along with their qualities
+fn degap_records(records: SeqRecords<'_>) -> SeqRecords<'_> {
+    Box::new(records.map(|record| {
+        record.map(|mut record| {
+            if let Some(qual) = record.qual.as_mut() {
+                let mut bases = record.seq.iter();
+                qual.retain(|_| !bases.next().is_some_and(is_gap));
+            }
+            record.seq.retain(|base| !is_gap(base));
+            record
+        })
+    }))
+}
+
+// Whether a character is a letter or an alignment gap. Letters which are not
+// IUPAC bases are kept so that their record is reported and skipped
+fn is_sequence_char(c: &u8) -> bool {
+    c.is_ascii_alphabetic() || is_gap(c)
+}
+
+// Remove stray characters, such as the spaces and position numbers of
+// pasted alignments, from record sequences. The input position of the
+// remaining characters is kept so that coordinates refer to the input
+// sequences
+fn sanitize_records(records: SeqRecords<'_>) -> SeqRecords<'_> {
+    Box::new(records.map(|record| {
+        record.map(|mut record| {
+            if record.seq.iter().all(is_sequence_char) {
+                return record;
+            }
+
+            let positions = (0..record.seq.len())
+                .filter(|&i| is_sequence_char(&record.seq[i]))
+                .collect::<Vec<_>>();
+            warn!(
+                "Removed {} characters which are neither letters nor gaps from {}, coordinates refer to the sequence as read",
+                record.seq.len() - positions.len(),
+                record.id
+            );
+            record.seq = positions.iter().map(|&i| record.seq[i]).collect();
+            if let Some(qual) = record.qual.as_mut() {
+                *qual = positions.iter().map(|&i| qual[i]).collect();
+            }
+            record.positions = Some(positions);
+            record
+        })
+    }))
+}
+
+// Gaps of aligned sequences shift the reported coordinates. Warn once if
+// the first records contain some, unless gaps are removed
+fn check_gaps<'a>(
+    mut records: SeqRecords<'a>,
+    options: &Options,
+    warned: &mut bool,
+) -> SeqRecords<'a> {
+    if options.degap {
+        return degap_records(records);
+    }
+    if *warned {
+        return records;
+    }
+
+    let head = records.by_ref().take(GAP_CHECK_RECORDS).collect::<Vec<_>>();
+    if head
+        .iter()
+        .flatten()
+        .any(|record| record.seq.iter().any(is_gap))
+    {
+        warn!("Input sequences contain gap characters and may be aligned. Use --degap to remove gaps before primer search");
+        *warned = true;
+    }
+
+    Box::new(head.into_iter().chain(records))
+}
+
+#[cfg(not(feature = "tar"))]
+fn extract_tar(
+    file: &str,
+    _primers: &[Vec<String>],
+    _matchers: &Matchers,
+    _options: &Options,
+    _sink: &mut Sink,
+) -> anyhow::Result<()> {
+    Err(anyhow!(
+        "Cannot read {}: hyperex was built without tar archive support",
+        file
+    ))
+}
+
+pub fn get_hypervar_regions(
+    file: &str,
+    primers: Vec<Vec<String>>,
+    prefix: &str,
+    options: &Options,
+) -> anyhow::Result<ExtractionStats> {
+    extract_files(&[file], &primers, prefix, options, false)
+}
+
+/// Extract hypervariable regions of several input files to the same
+/// outputs. Record ids are prefixed with the [`sample_name`] of their file,
+/// files having to be all FASTA or all FASTQ
+pub fn get_samples_hypervar_regions(
+    files: &[&str],
+    primers: Vec<Vec<String>>,
+    prefix: &str,
+    options: &Options,
+) -> anyhow::Result<ExtractionStats> {
+    extract_files(files, &primers, prefix, options, true)
+}
+
+// Sink of the amplicons of all input files, created for the first file
+fn shared_sink<'s, 'a>(
+    sink: &'s mut Option<Sink<'a>>,
+    prefix: &str,
+    primers: &[Vec<String>],
+    options: &'a Options,
+    fastq: bool,
+    file: &str,
+) -> anyhow::Result<&'s mut Sink<'a>> {
+    if sink.is_none() {
+        *sink = Some(Sink::new(prefix, primers, options, fastq)?);
+    }
+    let sink = sink.as_mut().unwrap();
+    if sink.fastq != fastq {
+        return Err(anyhow!(
+            "{} is not in the format of the previous input files, FASTA and FASTQ amplicons cannot be written together",
+            file
+        ));
+    }
+
+    Ok(sink)
+}
+
+fn extract_files(
+    files: &[&str],
+    primers: &[Vec<String>],
+    prefix: &str,
+    options: &Options,
+    samples: bool,
+) -> anyhow::Result<ExtractionStats> {
+    let matchers = Matchers::new(primers);
+    let mut sink = None;
+
+    for &file in files {
+        let sample = samples.then(|| sample_name(file));
+        // Amplicons of archive members are always written as FASTA
+        if is_tar_archive(file) {
+            let sink =
+                shared_sink(&mut sink, prefix, primers, options, false, file)?;
+            sink.sample = sample;
+            extract_tar(file, primers, &matchers, options, sink)?;
+        } else {
+            let (records, is_fastq) = read_records(file, options.input_format)
+                .with_context(|| format!("Cannot read file {}", file))?;
+            let sink = shared_sink(
+                &mut sink, prefix, primers, options, is_fastq, file,
+            )?;
+            sink.sample = sample;
+
+            if options.quality_trim.is_some() && !is_fastq {
+                warn!("Input is not FASTQ, quality trimming is ignored");
+            }
+
+            let records = check_gaps(
+                sanitize_records(records),
+                options,
+                &mut sink.gaps_warned,
+            );
+            extract_records(records, primers, &matchers, options, sink)?;
+        }
+    }
+
+    let sink = match sink {
+        Some(sink) => sink,
+        None => Sink::new(prefix, primers, options, false)?,
+    };
+    let stats = sink.finish(prefix)?;
+    if let (Some(id), 0) = (&options.resume_from, stats.records) {
+        warn!(
+            "Record {} to resume from was not found, no record was processed",
+            id
+        );
+    } else if stats.records == 0 {
+        // Empty outputs of an empty input could otherwise go unnoticed
+        if options.strict {
+            return Err(anyhow!("No sequences found in input"));
+        }
+        warn!("No sequences found in input");
+    }
+
+    Ok(stats)
+}
+
+/// First record of an input file, read to check the file before a run
+#[derive(Debug, PartialEq)]
+pub struct InputCheck {
+    /// Id of the first record
+    pub id: String,
+    /// Sequence type of the first record
+    pub alphabet: Alphabet,
+    /// Whether the file is FASTQ
+    pub fastq: bool,
+}
+
+/// Check that the first record of a file, possibly compressed, can be read
+/// and is recognized as DNA or RNA, without extracting anything
+pub fn check_input(
+    file: &str,
+    format: InputFormat,
+) -> anyhow::Result<InputCheck> {
+    let (records, fastq) = read_records(file, format)
+        .with_context(|| format!("Cannot read file {}", file))?;
+    let record = match sanitize_records(records).next() {
+        Some(record) => {
+            record.with_context(|| format!("Cannot read file {}", file))?
+        }
+        None => return Err(anyhow!("File {} has no record", file)),
+    };
+    match sequence_type(&String::from_utf8_lossy(&record.seq)) {
+        Some(alphabet) => Ok(InputCheck {
+            id: record.id,
+            alphabet,
+            fastq,
+        }),
+        None => Err(anyhow!(
+            "Sequence type of {} in {} is not recognized as DNA or RNA",
+            record.id,
+            file
+        )),
+    }
+}
+
+/// A region extracted by [`extract_regions`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionHit {
+    /// Id of the record the region was extracted from
+    pub id: String,
+    /// Region name, or the primers joined by `_` for other primers
+    pub region: String,
+    /// Start of the region in the record, 0-based
+    pub start: usize,
+    /// End of the region in the record, exclusive
+    pub end: usize,
+    /// `+`, or `-` when the region was found on the reverse complement of
+    /// the record
+    pub strand: char,
+    /// Sequence of the region, on the strand it was found on
+    pub sequence: Vec<u8>,
+}
+
+/// Extract the regions of primer pairs from FASTA or FASTQ records,
+/// possibly compressed, allowing `mismatch` edits in the primers.
+///
+/// Regions are returned in the order of the records, then of the primers.
+/// Records which are not DNA or RNA are skipped.
+pub fn extract_regions<'a, R: io::Read + 'a>(
+    reader: R,
+    primers: &[Vec<String>],
+    mismatch: u8,
+) -> anyhow::Result<Vec<RegionHit>> {
+    let (reader, _compression) = niffler::get_reader(Box::new(reader))?;
+    let (records, _) = records_from_reader(reader)?;
+    let matchers = Matchers::new(primers);
+    let options = Options {
+        mismatch,
+        collect_errors: true,
+        ..Default::default()
+    };
+
+    let mut hits = Vec::new();
+    for record in sanitize_records(records) {
+        let record = record?;
+        let amplicons = extract_amplicons(
+            &record,
+            primers,
+            &matchers,
+            None,
+            &options,
+            &mut Vec::new(),
+        )?;
+        hits.extend(amplicons.into_iter().map(|amplicon| {
+            let primer_pair = &primers[amplicon.pair_index];
+            RegionHit {
+                id: record.id.clone(),
+                region: match amplicon.region.as_str() {
+                    "" => format!("{}_{}", primer_pair[0], primer_pair[1]),
+                    region => region.to_string(),
+                },
+                start: amplicon.start,
+                end: amplicon.end,
+                strand: amplicon.strand,
+                sequence: amplicon.record.seq().to_vec(),
+            }
+        }));
+    }
+
+    Ok(hits)
+}
+
+/// Extraction statistics of a region over a dataset
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionBenchmark {
+    pub region: String,
+    /// Number of records the region was extracted from
+    pub extracted: usize,
+    pub records: usize,
+    pub mean_length: Option<f64>,
+    /// Mean total edit distance of the primers hits
+    pub mean_mismatch: Option<f64>,
+}
+
+/// Extract regions from all records without writing them and rank regions
+/// by the number of records they were extracted from, then by their mean
+/// mismatch. Partial amplicons are not counted
+pub fn benchmark_regions(
+    file: &str,
+    primers: &[Vec<String>],
+    options: &Options,
+) -> anyhow::Result<Vec<RegionBenchmark>> {
+    let (records, _) = read_records(file, options.input_format)
+        .with_context(|| "Cannot read file")?;
+    let matchers = Matchers::new(primers);
+
+    // Number of extracted amplicons, sums of their lengths and mismatches
+    let mut tallies = vec![(0, 0, 0); primers.len()];
+    let mut total = 0;
+
+    for record in records {
+        let record = record?;
+        total += 1;
+        for amplicon in extract_amplicons(
+            &record,
+            primers,
+            &matchers,
+            None,
+            options,
+            &mut Vec::new(),
+        )? {
+            if amplicon.partial {
+                continue;
+            }
+            let tally = &mut tallies[amplicon.pair_index];
+            tally.0 += 1;
+            tally.1 += amplicon.end - amplicon.start;
+            tally.2 += amplicon.mismatch;
+        }
+    }
+
+    let mean = |sum: usize, count: usize| {
+        (count > 0).then(|| sum as f64 / count as f64)
+    };
+    let mut benchmarks = primers
+        .iter()
+        .zip(tallies)
+        .map(|(primer_pair, (extracted, lengths, mismatches))| {
+            RegionBenchmark {
+                region: match primers_to_region(primer_pair) {
+                    region if region.is_empty() => {
+                        format!("{}_{}", primer_pair[0], primer_pair[1])
+                    }
+                    region => region,
+                },
+                extracted,
+                records: total,
+                mean_length: mean(lengths, extracted),
+                mean_mismatch: mean(mismatches, extracted),
+            }
+        })
+        .collect::<Vec<_>>();
+    // Stable sort keeps the order of the primers on ties
+    benchmarks.sort_by(|a, b| {
+        b.extracted.cmp(&a.extracted).then(
+            a.mean_mismatch
+                .unwrap_or(f64::MAX)
+                .total_cmp(&b.mean_mismatch.unwrap_or(f64::MAX)),
+        )
+    });
+
+    Ok(benchmarks)
+}
+
+/// Write region benchmarks as a ranked TSV table
+pub fn write_benchmark<W: Write>(
+    writer: &mut W,
+    benchmarks: &[RegionBenchmark],
+) -> io::Result<()> {
+    let format_mean = |mean: Option<f64>| {
+        mean.map_or("NA".to_string(), |x| format!("{:.2}", x))
+    };
+    writeln!(
+        writer,
+        "rank\tregion\textracted\trecords\tfraction\tmean_length\tmean_mismatch"
+    )?;
+    for (rank, benchmark) in benchmarks.iter().enumerate() {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{:.3}\t{}\t{}",
+            rank + 1,
+            benchmark.region,
+            benchmark.extracted,
+            benchmark.records,
+            if benchmark.records == 0 {
+                0.0
+            } else {
+                benchmark.extracted as f64 / benchmark.records as f64
+            },
+            format_mean(benchmark.mean_length),
+            format_mean(benchmark.mean_mismatch)
+        )?;
+    }
+
+    Ok(())
+}
+
+// Built-in primers sorted by position on the 16S rRNA gene, forward primers
+// first
+fn builtin_primers() -> Vec<(&'static str, &'static str, &'static str)> {
+    // Primers not named after their 16S position, such as ITS primers,
+    // come last
+    let position = |name: &str| -> usize {
+        name.chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect::<String>()
+            .parse()
+            .unwrap_or(usize::MAX)
+    };
+    let sorted = |map: &phf::Map<&'static str, &'static str>| {
+        let mut entries =
+            map.entries().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+        entries.sort_by_key(|&(name, _)| (position(name), name));
+        entries
+    };
+
+    sorted(&FORWARD_PRIMERS)
+        .into_iter()
+        .map(|(name, primer)| (name, "forward", primer))
+        .chain(
+            sorted(&REVERSE_PRIMERS)
+                .into_iter()
+                .map(|(name, primer)| (name, "reverse", primer)),
+        )
+        .collect()
+}
+
+/// Shortest hairpin stem, in base pairs, reported as secondary structure
+pub const HAIRPIN_MIN_STEM: usize = 4;
+/// Shortest run of paired bases reported as primer dimer
+pub const DIMER_MIN_RUN: usize = 5;
+/// Fewest unpaired bases closing a hairpin loop
+const HAIRPIN_MIN_LOOP: usize = 3;
+
+// Whether two bases form a Watson-Crick pair. Ambiguous bases are not
+// counted as paired
+fn pairs(a: u8, b: u8) -> bool {
+    matches!(
+        (a.to_ascii_uppercase(), b.to_ascii_uppercase()),
+        (b'A', b'T')
+            | (b'T', b'A')
+            | (b'A', b'U')
+            | (b'U', b'A')
+            | (b'C', b'G')
+            | (b'G', b'C')
+    )
+}
+
+/// Longest hairpin stem a primer can fold into, in base pairs
+pub fn hairpin_score(primer: &str) -> usize {
+    let primer = primer.as_bytes();
+    let mut best = 0;
+
+    // Stems closing on the loop primer[i + 1..j]
+    for i in 0..primer.len() {
+        for j in (i + HAIRPIN_MIN_LOOP + 1)..primer.len() {
+            let stem = (0..=i)
+                .take_while(|&k| j + k < primer.len())
+                .take_while(|&k| pairs(primer[i - k], primer[j + k]))
+                .count();
+            best = best.max(stem);
+        }
+    }
+
+    best
+}
+
+/// Longest run of consecutive paired bases when two primers anneal
+/// antiparallel to each other. A primer against itself gives its
+/// self-dimer score
+pub fn dimer_score(a: &str, b: &str) -> usize {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let mut best = 0;
+
+    // a[i] faces b[b.len() - 1 - (i - shift)] for each shift of b along a
+    for shift in -(b.len() as isize)..(a.len() as isize) {
+        let mut run = 0;
+        for (i, &base) in a.iter().enumerate() {
+            let j = i as isize - shift;
+            if j < 0 || j >= b.len() as isize {
+                run = 0;
+                continue;
+            }
+            if pairs(base, b[b.len() - 1 - j as usize]) {
+                run += 1;
+                best = best.max(run);
+            } else {
+                run = 0;
+            }
+        }
+    }
+
+    best
+}
+
+/// Secondary structure scores of a primer pair
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrimerStructure {
+    /// Longest hairpin stem of either primer
+    pub hairpin: usize,
+    /// Longest self-dimer of either primer
+    pub self_dimer: usize,
+    /// Longest dimer between the forward and the reverse primer
+    pub pair_dimer: usize,
+}
+
+impl PrimerStructure {
+    pub fn of(forward: &str, reverse: &str) -> Self {
+        PrimerStructure {
+            hairpin: hairpin_score(forward).max(hairpin_score(reverse)),
+            self_dimer: dimer_score(forward, forward)
+                .max(dimer_score(reverse, reverse)),
+            pair_dimer: dimer_score(forward, reverse),
+        }
+    }
+
+    /// Kinds of secondary structure strong enough to be reported
+    pub fn issues(&self) -> Vec<&'static str> {
+        let mut issues = Vec::new();
+        if self.hairpin >= HAIRPIN_MIN_STEM {
+            issues.push("hairpin");
+        }
+        if self.self_dimer >= DIMER_MIN_RUN {
+            issues.push("self_dimer");
+        }
+        if self.pair_dimer >= DIMER_MIN_RUN {
+            issues.push("pair_dimer");
+        }
+        issues
+    }
+}
+
+/// Warn about user primer pairs prone to secondary structure. Built-in
+/// primers are listed with their scores by `--list-regions`
+pub fn check_primer_structure(primers: &[Vec<String>]) {
+    for primer_pair in primers {
+        if PRIMER_TO_REGION.contains_key(primer_pair[0].as_str())
+            && PRIMER_TO_REGION.contains_key(primer_pair[1].as_str())
+        {
+            continue;
+        }
+        let structure = PrimerStructure::of(&primer_pair[0], &primer_pair[1]);
+        let issues = structure.issues();
+        if !issues.is_empty() {
+            warn!(
+                "Primers {}, {} may form secondary structures ({}): hairpin stem of {} bp, self-dimer of {} bp, primer dimer of {} bp",
+                primer_pair[0],
+                primer_pair[1],
+                issues.join(", "),
+                structure.hairpin,
+                structure.self_dimer,
+                structure.pair_dimer
+            );
+        }
+    }
+}
+
+// Name of a built-in primer given its sequence, `-` for other primers
+fn primer_name(
+    primer: &str,
+    builtins: &phf::Map<&'static str, &'static str>,
+) -> &'static str {
+    builtins
+        .entries()
+        .find(|(_, sequence)| **sequence == primer)
+        .map_or("-", |(name, _)| name)
+}
+
+/// Write built-in and user-defined regions with their primers, secondary
+/// structure scores and built-in primer names as a TSV table
+pub fn write_regions<W: Write>(
+    writer: &mut W,
+    custom_regions: &BTreeMap<String, Vec<String>>,
+) -> io::Result<()> {
+    writeln!(
+        writer,
+        "region\tforward\treverse\thairpin\tself_dimer\tpair_dimer\tissues\tforward_name\treverse_name"
+    )?;
+    let builtins = builtin_regions();
+    let regions = builtins
+        .iter()
+        .map(|region| {
+            (
+                region.to_string(),
+                region_to_primer(region).unwrap_or_default(),
+            )
+        })
+        .chain(custom_regions.iter().map(|(k, v)| (k.clone(), v.clone())));
+    for (region, primers) in regions {
+        let structure = PrimerStructure::of(&primers[0], &primers[1]);
+        let issues = structure.issues();
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            region,
+            primers[0],
+            primers[1],
+            structure.hairpin,
+            structure.self_dimer,
+            structure.pair_dimer,
+            if issues.is_empty() {
+                "-".to_string()
+            } else {
+                issues.join(",")
+            },
+            primer_name(&primers[0], &FORWARD_PRIMERS),
+            primer_name(&primers[1], &REVERSE_PRIMERS)
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Report, for each built-in primer, the number of input records it matches
+/// and the mean edit distance of its hits to `{prefix}.primers.tsv`
+pub fn primer_report(
+    file: &str,
+    prefix: &str,
+    options: &Options,
+) -> anyhow::Result<()> {
+    let (records, _) = read_records(file, options.input_format)
+        .with_context(|| "Cannot read file")?;
+    // Matchers of the primers are built when first searched
+    let matchers = Matchers::new(&[]);
+    let primers = builtin_primers();
+
+    // Number of matched records and sum of edit distances per primer
+    let mut tallies = vec![(0usize, 0usize); primers.len()];
+    let mut total = 0;
+
+    for record in records {
+        let record = record?;
+        let mut seq = &record.seq[..];
+        if let (Some(threshold), Some(qual)) =
+            (options.quality_trim, &record.qual)
+        {
+            seq = &seq[..quality_trim(qual, threshold)];
+        }
+        // RNA is searched as DNA so that DNA primers match it
+        let dna_seq;
+        if sequence_type(std::str::from_utf8(seq)?) == Some(Alphabet::Rna) {
+            dna_seq = rna_to_dna(seq);
+            seq = &dna_seq;
+        }
+        total += 1;
+
+        for ((_, direction, primer), tally) in
+            primers.iter().zip(tallies.iter_mut())
+        {
+            // Reverse primers are searched on the sequence strand
+            let pattern = if *direction == "reverse" {
+                to_reverse_complement(primer, "dna")
+            } else {
+                primer.to_string()
+            };
+            if let Some(hit) = primer_hit(
+                &matchers,
+                pattern.as_bytes(),
+                seq,
+                *direction == "reverse",
+                options,
+            ) {
+                tally.0 += 1;
+                tally.1 += hit.dist as usize;
+            }
+        }
+    }
+
+    let mut writer =
+        io::BufWriter::new(File::create(format!("{}.primers.tsv", prefix))?);
+    writeln!(
+        writer,
+        "primer\tdirection\tsequence\tmatched\trecords\tmean_distance"
+    )?;
+    for ((name, direction, primer), (matched, distances)) in
+        primers.iter().zip(tallies)
+    {
+        let mean = if matched == 0 {
+            "NA".to_string()
+        } else {
+            format!("{:.2}", distances as f64 / matched as f64)
+        };
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            name, direction, primer, matched, total, mean
+        )?;
+    }
+
+    Ok(())
+}
+
+// Tests --------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::{Read, Write};
+    use tempfile::{NamedTempFile, TempDir};
+
+    // Extract regions to the `sample` prefix of a temporary directory,
+    // returned so that the outputs can be read
+    fn run_stats(
+        input: &str,
+        primers: Vec<Vec<String>>,
+        options: &Options,
+    ) -> (TempDir, ExtractionStats) {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let prefix = dir.path().join("sample");
+        let stats = get_hypervar_regions(
+            input,
+            primers,
+            prefix.to_str().unwrap(),
+            options,
+        )
+        .unwrap();
+
+        (dir, stats)
+    }
+
+    // Extract regions as `run_stats`, with the written amplicons
+    fn run(
+        input: &str,
+        primers: Vec<Vec<String>>,
+        options: &Options,
+    ) -> (TempDir, String) {
+        let (dir, _) = run_stats(input, primers, options);
+        let amplicons =
+            fs::read_to_string(dir.path().join("sample.fa")).unwrap();
+
+        (dir, amplicons)
+    }
+
+    // Records of a FASTA file content
+    fn records(fasta: &str) -> Vec<fasta::Record> {
+        fasta::Reader::new(fasta.as_bytes())
+            .records()
+            .map(|record| record.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_primers_to_region_ok() {
+        assert_eq!(
+            primers_to_region(&[
+                "CCTACGGGNGGCWGCAG".to_string(),
+                "GTGCCAGCMGCCGCGGTAA".to_string()
+            ]),
+            "v3v4".to_string()
+        );
+    }
+
+    #[test]
+    fn test_primers_to_region_ok2() {
+        assert_eq!(
+            primers_to_region(&[
+                "GTGCCAGCMGCCGCGGTAA".to_string(),
+                "GTGCCAGCMGCCGCGGTAA".to_string()
+            ]),
+            "v4".to_string()
+        );
+    }
+
+    #[test]
+    fn test_primers_to_region_v4() {
+        // Both v4 primers name the same region, which is not doubled
+        let primers = region_to_primer("v4").unwrap();
+        assert_eq!(primers_to_region(&primers), "v4");
+        assert_eq!(
+            primers_to_region(&[primers[0].clone(), primers[0].clone()]),
+            "v4"
+        );
+        assert_eq!(
+            primers_to_region(&region_to_primer("v3v4").unwrap()),
+            "v3v4"
+        );
+    }
+
+    #[test]
+    fn test_primers_to_region_empty() {
+        assert_eq!(
+            primers_to_region(&["ZZZZZ".to_string(), "AAAAAA".to_string()]),
+            "".to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_qiime_primers() {
+        assert_eq!(
+            parse_qiime_primers("tests/primers_qiime.tsv").unwrap(),
+            vec![
+                (
+                    "v4".to_string(),
+                    vec![
+                        "GTGCCAGCMGCCGCGGTAA".to_string(),
+                        "GGACTACHVGGGTWTCTAAT".to_string()
+                    ]
+                ),
+                (
+                    "v3v4".to_string(),
+                    vec![
+                        "CCTACGGGNGGCWGCAG".to_string(),
+                        "GACTACHVGGGTATCTAATCC".to_string()
+                    ]
+                ),
+            ]
+        );
+
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "id\tforward-primer").unwrap();
+        writeln!(file, "v4\tGTGCCAGCMGCCGCGGTAA").unwrap();
+        let error = parse_qiime_primers(file.path().to_str().unwrap())
+            .unwrap_err()
+            .to_string();
+        assert!(error.ends_with("has no reverse-primer column"));
+
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "id\tforward-primer\treverse-primer").unwrap();
+        writeln!(file, "v4\tGTGCCAGCMGCCGCGGTAA").unwrap();
+        let error = parse_qiime_primers(file.path().to_str().unwrap())
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("Line 2 of primer manifest"));
+    }
+
+    #[test]
+    fn test_complement_dna() {
+        assert_eq!(
+            to_complement("ATCGATCGATCGATCGRYKBVDHX", "dna"),
+            String::from("TAGCTAGCTAGCTAGCYRMVBHDX")
+        );
+    }
+
+    #[test]
+    fn test_complement_rna() {
+        assert_eq!(
+            to_complement("AUCGAUCGAUCGAUCGRYKBVDHMXN", "rna"),
+            String::from("UAGCUAGCUAGCUAGCYRMVBHDKXN")
+        );
+    }
+
+    #[test]
+    fn test_reverse_complement() {
+        assert_eq!(
+            to_reverse_complement("GTGCCAGCMGCCGCGGTAAN", "dna"),
+            "NTTACCGCGGCKGCTGGCAC"
+        );
+    }
+
+    #[test]
+    fn test_sequence_type_dna_ok() {
+        assert_eq!(sequence_type("ATCGATCGATCG"), Some(Alphabet::Dna));
+    }
+
+    #[test]
+    fn test_sequence_type_dna_iupac_ok() {
+        assert_eq!(sequence_type("ATCGMTGCAATCG"), Some(Alphabet::Dna));
+    }
+
+    #[test]
+    fn test_sequence_type_rna_ok() {
+        assert_eq!(sequence_type("AGCUUUGCA"), Some(Alphabet::Rna));
+    }
+
+    #[test]
+    fn test_sequence_type_rna_iupac_ok() {
+        assert_eq!(sequence_type("GUUUUAACCCAAM"), Some(Alphabet::Rna));
+    }
+
+    #[test]
+    fn test_sequence_type_lowercase_ok() {
+        assert_eq!(sequence_type("atcgmtgcaatcg"), Some(Alphabet::Dna));
+        assert_eq!(sequence_type("GuuUUaacCCAAm"), Some(Alphabet::Rna));
+    }
+
+    #[test]
+    fn test_sequence_type_err() {
+        assert_eq!(sequence_type("ATCXXXRMGU"), None);
+    }
+
+    #[test]
+    fn test_region_to_primer_its1() {
+        let primers = region_to_primer("its1").unwrap();
+        assert_eq!(
+            primers,
+            vec!["CTTGGTCATTTAGAGGAAGTAA", "GCTGCGTTCTTCATCGATGC"]
+        );
+        assert_eq!(primers_to_region(&primers), "its1");
+    }
+
+    #[test]
+    fn test_region_to_primer_18s_v4() {
+        let primers = region_to_primer("18s_v4").unwrap();
+        assert_eq!(primers, vec!["CCAGCASCYGCGGTAATTCC", "ACTTTCGTTCTTGATYRA"]);
+        assert_eq!(primers_to_region(&primers), "18s_v4");
+    }
+
+    #[test]
+    fn test_region_to_primer_ok() {
+        assert_eq!(
+            region_to_primer("v1v2").unwrap(),
+            vec!["AGAGTTTGATCMTGGCTCAG", "ACTGCTGCSYCCCGTAGGAGTCT"]
+        );
+        assert_eq!(
+            region_to_primer("v1v3").unwrap(),
+            vec!["AGAGTTTGATCMTGGCTCAG", "ATTACCGCGGCTGCTGG"]
+        );
+        assert_eq!(
+            region_to_primer("v1v9").unwrap(),
+            vec!["AGAGTTTGATCMTGGCTCAG", "TACGGYTACCTTGTTAYGACTT"]
+        );
+        assert_eq!(
+            region_to_primer("v3v4").unwrap(),
+            vec!["CCTACGGGNGGCWGCAG", "GACTACHVGGGTATCTAATCC"]
+        );
+        assert_eq!(
+            region_to_primer("v3v5").unwrap(),
+            vec!["CCTACGGGNGGCWGCAG", "CCGTCAATTYMTTTRAGT"]
+        );
+        assert_eq!(
+            region_to_primer("v4").unwrap(),
+            vec!["GTGCCAGCMGCCGCGGTAA", "GGACTACHVGGGTWTCTAAT"]
+        );
+        assert_eq!(
+            region_to_primer("v4v5").unwrap(),
+            vec!["GTGYCAGCMGCCGCGGTAA", "CCCCGYCAATTCMTTTRAGT"]
+        );
+        assert_eq!(
+            region_to_primer("v5v7").unwrap(),
+            vec!["AACMGGATTAGATACCCKG", "ACGTCATCCCCACCTTCC"]
+        );
+        assert_eq!(
+            region_to_primer("v6v9").unwrap(),
+            vec!["TAAAACTYAAAKGAATTGACGGGG", "TACGGYTACCTTGTTAYGACTT"]
+        );
+        assert_eq!(
+            region_to_primer("v7v9").unwrap(),
+            vec!["YAACGAGCGCAACCC", "TACGGYTACCTTGTTAYGACTT"]
+        );
+    }
+
+    #[test]
+    fn test_expected_lengths() {
+        // Lengths match the binding sites of the region primers
+        for (region, &expected) in EXPECTED_LENGTHS.entries() {
+            let primers = region_to_primer(region).unwrap();
+            let start = PRIMER_POSITIONS[primers[0].as_str()];
+            let end = PRIMER_POSITIONS[primers[1].as_str()] + primers[1].len();
+            assert_eq!(end - start, expected, "{}", region);
+        }
+    }
+
+    #[test]
+    fn test_primer_set_version() {
+        let version = primer_set_version();
+        assert_eq!(version.len(), 8);
+        assert!(version.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(version, primer_set_version());
+    }
+
+    #[test]
+    fn test_region_to_primer_unknown() {
+        assert!(region_to_primer("").is_err());
+        assert_eq!(
+            region_to_primer("v99").unwrap_err().to_string(),
+            "unsupported region: v99"
+        );
+    }
+
+    #[test]
+    fn test_write_fa_ok2() {
+        let mut tmpfile =
+            NamedTempFile::new().expect("Cannot create temp file");
+        writeln!(tmpfile, ">id_str desc\nATCGCCG")
+            .expect("Cannot write to tmp file");
+
+        let mut fa_records = fasta::Reader::from_file(tmpfile)
+            .expect("Cannot read file.")
+            .records();
+
+        while let Some(Ok(rec)) = fa_records.next() {
+            assert_eq!(rec.id(), "id_str");
+            assert_eq!(rec.desc(), Some("desc"));
+            assert_eq!(rec.seq(), b"ATCGCCG");
+        }
+    }
+
+    #[test]
+    fn test_combine_vec() {
+        let first = vec!["ab", "cd", "ef"];
+        let second = vec!["cd", "ef", "gh"];
+        assert_eq!(
+            combine_vec(first, second),
+            vec![
+                vec!["ab".to_string(), "cd".to_string()],
+                vec!["cd".to_string(), "ef".to_string()],
+                vec!["ef".to_string(), "gh".to_string()]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dedupe_primers() {
+        let v4 = region_to_primer("v4").unwrap();
+        let v3v4 = region_to_primer("v3v4").unwrap();
+        assert_eq!(
+            dedupe_primers(&[v4.clone(), v3v4.clone(), v4.clone(), v4.clone()]),
+            (vec![v4.clone(), v3v4.clone()], vec![v4.clone()])
+        );
+        assert_eq!(
+            dedupe_primers(&[v4.clone(), v3v4.clone()]),
+            (vec![v4, v3v4], vec![])
+        );
+    }
+
+    #[test]
+    fn test_validate_primers() {
+        let mut primers = vec![
+            region_to_primer("v4").unwrap(),
+            vec!["".to_string(), "GACTACNVGGGTWTCTAATCC".to_string()],
+        ];
+        assert!(validate_primers(&primers).is_ok());
+
+        for (primer, message) in [
+            ("GUGCCAGC", "invalid character 'U' at position 2"),
+            ("GTG1CAGC", "invalid character '1' at position 4"),
+            ("GTGCcAGC", "invalid character 'c' at position 5"),
+        ] {
+            primers[0][0] = primer.to_string();
+            let err = validate_primers(&primers).unwrap_err().to_string();
+            assert!(err.contains(primer));
+            assert!(err.contains(message), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_combine_vec_not_ok() {
+        let first = vec!["ab", "cd", "ef"];
+        let second = vec!["ab"];
+        assert_ne!(
+            combine_vec(first, second),
+            vec![
+                vec!["ab".to_string(), "cd".to_string()],
+                vec!["cd".to_string(), "ef".to_string()],
+                vec!["ef".to_string(), "gh".to_string()]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_hypervar_regions() {
+        run(
+            "tests/test.fa.gz",
+            vec![vec![
+                "AGAGTTTGATCMTGGCTCAG".to_string(),
+                "TACGGYTACCTTGTTAYGACTT".to_string(),
+            ]],
+            &Options::default(),
+        );
+    }
+
+    #[test]
+    fn test_iupac_consensus() {
+        assert_eq!(
+            iupac_consensus(&[
+                b"ACGTA".to_vec(),
+                b"AGGTC".to_vec(),
+                b"ACGCG".to_vec()
+            ]),
+            Some(b"ASGYV".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_iupac_consensus_unequal_length() {
+        assert_eq!(iupac_consensus(&[b"ACGT".to_vec(), b"ACG".to_vec()]), None);
+        assert_eq!(iupac_consensus(&[]), None);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_consensus() {
+        let options = Options {
+            consensus: true,
+            ..Default::default()
+        };
+        let (dir, amplicons) = run(
+            "tests/test.fa",
+            vec![vec![
+                "GTGCCAGCMGCCGCGGTAA".to_string(),
+                "GGACTACHVGGGTWTCTAAT".to_string(),
+            ]],
+            &options,
+        );
+
+        let extracted = &records(&amplicons)[0];
+        let consensus = &records(
+            &fs::read_to_string(dir.path().join("sample.consensus.fa"))
+                .unwrap(),
+        )[0];
+        assert_eq!(consensus.id(), "v4");
+        assert_eq!(consensus.desc(), Some("amplicons=1"));
+        assert_eq!(consensus.seq(), extracted.seq());
+    }
+
+    #[test]
+    fn test_hit_cache() {
+        let hit = PrimerHit {
+            start: 0,
+            end: 4,
+            dist: 0,
+        };
+        let mut cache = HitCache::new(2);
+        cache.insert(b"AAAA", vec![(Some(hit), None)]);
+        cache.insert(b"CCCC", vec![(None, None)]);
+        assert_eq!(cache.get(b"AAAA"), Some(vec![(Some(hit), None)]));
+
+        // CCCC is the least recently used entry and gets evicted
+        cache.insert(b"GGGG", vec![(None, Some(hit))]);
+        assert_eq!(cache.get(b"CCCC"), None);
+        assert_eq!(cache.get(b"AAAA"), Some(vec![(Some(hit), None)]));
+        assert_eq!(cache.get(b"GGGG"), Some(vec![(None, Some(hit))]));
+        assert_eq!(cache.get(b"TTTT"), None);
+
+        // Inserting a cached sequence again does not evict another one
+        cache.insert(b"AAAA", vec![(None, None)]);
+        assert_eq!(cache.get(b"GGGG"), Some(vec![(None, Some(hit))]));
+        assert_eq!(cache.get(b"AAAA"), Some(vec![(None, None)]));
+        assert_eq!(cache.entries.len(), cache.recency.len());
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_cache() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let seq = fs::read_to_string("tests/test.fa").unwrap();
+        let input = dir.path().join("redundant.fa");
+        fs::write(&input, seq.repeat(3)).unwrap();
+        let primers = vec![
+            region_to_primer("v4").unwrap(),
+            region_to_primer("v3v4").unwrap(),
+        ];
+
+        let mut outputs = Vec::new();
+        for cache in [None, Some(1)] {
+            let options = Options {
+                cache,
+                ..Default::default()
+            };
+            let (out, amplicons) =
+                run(input.to_str().unwrap(), primers.clone(), &options);
+            outputs.push((
+                amplicons,
+                fs::read_to_string(out.path().join("sample.gff")).unwrap(),
+            ));
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(outputs[1].0.matches("region=v4 ").count(), 3);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_match_seq() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let options = Options {
+            mismatch: 1,
+            match_seq: true,
+            ..Default::default()
+        };
+        let forward = "GTGCCAGCAGCCGCGGTAA";
+        let reverse_site = "ATTAGAAACCCTGGTAGTCC";
+        let input = dir.path().join("in.fa");
+        fs::write(
+            &input,
+            format!(">seq1\nTTTT{}ACGTACGT{}TTTT\n", forward, reverse_site),
+        )
+        .unwrap();
+        let (_out, amplicons) = run(
+            input.to_str().unwrap(),
+            vec![vec![
+                "GTGCCAGCMGCCGCGGTAA".to_string(),
+                "GGACTACCAGGGTATCTAAT".to_string(),
+            ]],
+            &options,
+        );
+
+        let record = &records(&amplicons)[0];
+        let desc = record.desc().unwrap();
+        assert!(desc.contains(&format!("fwd_match_seq={}", forward)));
+        assert!(desc.contains(&format!("rev_match_seq={}", reverse_site)));
+        // Matches are the bounds of the extracted amplicon
+        assert!(record.seq().starts_with(forward.as_bytes()));
+        assert!(record.seq().ends_with(reverse_site.as_bytes()));
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_only_complete() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("in.fa");
+        // Only the forward primer is present
+        fs::write(&input, ">seq1\nTTTTGTGCCAGCAGCCGCGGTAAACGTACGT\n").unwrap();
+        let primers = vec![region_to_primer("v4").unwrap()];
+
+        for (only_complete, expected) in [(true, None), (false, Some(27))] {
+            let options = Options {
+                only_complete,
+                ..Default::default()
+            };
+            let (_out, amplicons) =
+                run(input.to_str().unwrap(), primers.clone(), &options);
+
+            let record = records(&amplicons).into_iter().next();
+            assert_eq!(record.as_ref().map(|r| r.seq().len()), expected);
+            if let Some(record) = record {
+                assert!(record.desc().unwrap().contains("partial=forward"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_group_by_region() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let seq = fs::read_to_string("tests/test.fa").unwrap();
+        let input = dir.path().join("in.fa");
+        fs::write(&input, seq.repeat(2)).unwrap();
+        let primers = vec![
+            region_to_primer("v4").unwrap(),
+            region_to_primer("v3v4").unwrap(),
+        ];
+
+        let regions_of = |group_by_region: bool| {
+            let options = Options {
+                group_by_region,
+                ..Default::default()
+            };
+            let (_out, amplicons) =
+                run(input.to_str().unwrap(), primers.clone(), &options);
+            records(&amplicons)
+                .iter()
+                .map(|r| {
+                    r.desc().unwrap().split(' ').next().unwrap().to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            regions_of(false),
+            vec!["region=v4", "region=v3v4", "region=v4", "region=v3v4"]
+        );
+        assert_eq!(
+            regions_of(true),
+            vec!["region=v4", "region=v4", "region=v3v4", "region=v3v4"]
+        );
+    }
+
+    #[test]
+    fn test_best_hit_max_hits() {
+        let mut builder = MyersBuilder::new();
+        builder.ambig(b'N', &b"ACGT"[..]);
+        let mut myers = builder.build_64(b"ACGTN");
+        // First hit has one mismatch, second one is exact
+        let seq = b"ACGAATTTTTTACGTA";
+
+        assert_eq!(
+            best_hit(&mut myers, seq, 1, None, false),
+            Some(PrimerHit {
+                start: 11,
+                end: 16,
+                dist: 0
+            })
+        );
+        assert_eq!(
+            best_hit(&mut myers, seq, 1, Some(1), false).map(|h| h.dist),
+            Some(1)
+        );
+        assert_eq!(best_hit(&mut myers, seq, 1, Some(0), false), None);
+    }
+
+    #[test]
+    fn test_best_hit_ties() {
+        let mut myers = MyersBuilder::new().build_64(b"ACGTA");
+        let seq = b"TTACGTATTTTACGTATT";
+
+        assert_eq!(
+            best_hit(&mut myers, seq, 0, None, false).map(|h| h.start),
+            Some(2)
+        );
+        assert_eq!(
+            best_hit(&mut myers, seq, 0, None, true).map(|h| h.start),
+            Some(11)
+        );
+    }
+
+    #[test]
+    fn test_find_primer_hits_ties() {
+        // Two exact v4 forward primer sites, then two exact reverse primer
+        // sites
+        let forward = "GTGCCAGCAGCCGCGGTAA";
+        let reverse = "ATTAGATACCCTGGTAGTCC";
+        let seq = format!(
+            "AAAA{}CCCC{}{}GGGG{}AAAA",
+            forward,
+            forward,
+            "ACGT".repeat(10),
+            reverse
+        ) + reverse;
+        let hits = find_primer_hits(
+            &Matchers::new(&[]),
+            seq.as_bytes(),
+            &[region_to_primer("v4").unwrap()],
+            "dna",
+            &Options::default(),
+        );
+
+        let (forward_hit, reverse_hit) = hits[0];
+        assert_eq!(forward_hit.map(|h| h.start), Some(4));
+        assert_eq!(reverse_hit.map(|h| h.end), Some(seq.len()));
+    }
+
+    #[test]
+    fn test_matchers_reused() {
+        let primers: Vec<Vec<String>> = EXPECTED_LENGTHS
+            .keys()
+            .map(|region| region_to_primer(region).unwrap())
+            .collect();
+        let matchers = Matchers::new(&primers);
+        assert!(matchers
+            .built
+            .borrow()
+            .contains_key(primers[0][0].as_bytes()));
+
+        // Matchers reused over records find the same hits as matchers built
+        // for each record
+        let records = fasta::Reader::from_file("tests/test.fa").unwrap();
+        for record in records.records() {
+            let record = record.unwrap();
+            let search = |matchers: &Matchers| {
+                find_primer_hits(
+                    matchers,
+                    record.seq(),
+                    &primers,
+                    "dna",
+                    &Options::default(),
+                )
+            };
+            assert_eq!(search(&matchers), search(&Matchers::new(&[])));
+        }
+    }
+
+    #[test]
+    fn test_resolved_match() {
+        assert_eq!(
+            resolved_match(b"AGAGTTTGATCMTGGCTCAG", b"AGAGTTTGATCATGGCTCAG"),
+            b"AGAGTTTGATCATGGCTCAG"
+        );
+        // A site base is deleted, another one is inserted
+        assert_eq!(resolved_match(b"ACGTNACGT", b"ACTGACGGT"), b"AC-TGACGT");
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_degenerate_expand() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("input.fa");
+        // The M of 27F matches a C, the H and V of 806R match a C and a G
+        fs::write(
+            &input,
+            format!(
+                ">seq1\nAAAAAGAGTTTGATCCTGGCTCAG{}ATTAGATACCCTGGTAGTCCAAAA\n",
+                "ACGT".repeat(10)
+            ),
+        )
+        .unwrap();
+        let options = Options {
+            degenerate_expand: true,
+            ..Default::default()
+        };
+        let (_out, amplicons) = run(
+            input.to_str().unwrap(),
+            vec![vec![
+                "AGAGTTTGATCMTGGCTCAG".to_string(),
+                "GGACTACHVGGGTWTCTAAT".to_string(),
+            ]],
+            &options,
+        );
+
+        let record = records(&amplicons).remove(0);
+        let desc = record.desc().unwrap();
+        assert!(desc.contains(" fwd_match=AGAGTTTGATCCTGGCTCAG"), "{}", desc);
+        assert!(desc.contains(" rev_match=GGACTACCAGGGTATCTAAT"), "{}", desc);
+    }
+
+    #[test]
+    fn test_best_hit_sw() {
+        let mut penalties = Penalties {
+            mismatch: 1,
+            gap_open: 2,
+            gap_extend: 1,
+        };
+        // One base deleted from the AAAA homopolymer of the site
+        let seq = b"CCCCGTGCCAGCAGCCGCGGTAAACCCC";
+        let pattern = b"GTGCCAGCMGCCGCGGTAAAA";
+        assert_eq!(best_hit_sw(pattern, seq, 0, &penalties), None);
+        // A mismatch with the next base is cheaper than a gap
+        assert_eq!(
+            best_hit_sw(pattern, seq, 1, &penalties),
+            Some(PrimerHit {
+                start: 4,
+                end: 25,
+                dist: 1
+            })
+        );
+        // A gap is cheaper than a mismatch
+        penalties.mismatch = 4;
+        assert_eq!(
+            best_hit_sw(pattern, seq, 1, &penalties),
+            Some(PrimerHit {
+                start: 4,
+                end: 24,
+                dist: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_sw_indel() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("in.fa");
+        // The forward primer binding site has a deleted base
+        let forward_site = "GTGCCAGCGCCGCGGTAA";
+        let reverse_site = "ATTAGATACCCTGGTAGTCC";
+        fs::write(
+            &input,
+            format!(
+                ">seq1\nTTTT{}ACGTACGT{}TTTT\n",
+                forward_site, reverse_site
+            ),
+        )
+        .unwrap();
+        let options = Options {
+            mismatch: 1,
+            matcher: Matcher::SmithWaterman(Penalties {
+                mismatch: 1,
+                gap_open: 2,
+                gap_extend: 1,
+            }),
+            ..Default::default()
+        };
+        let (_out, amplicons) = run(
+            input.to_str().unwrap(),
+            vec![vec![
+                "GTGCCAGCMGCCGCGGTAA".to_string(),
+                "GGACTACCAGGGTATCTAAT".to_string(),
+            ]],
+            &options,
+        );
+
+        assert_eq!(
+            records(&amplicons)[0].seq(),
+            format!("{}ACGTACGT{}", forward_site, reverse_site).as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_reverse_only() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("in.fa");
+        let reverse_site = "ATTAGATACCCTGGTAGTCC";
+        fs::write(&input, format!(">seq1\nAAAACCCCGGGG{}TTTT\n", reverse_site))
+            .unwrap();
+        let primers =
+            vec![vec!["".to_string(), "GGACTACCAGGGTATCTAAT".to_string()]];
+
+        // A window longer than the sequence upstream part starts at the
+        // sequence start
+        for (length, expected) in [
+            (None, format!("AAAACCCCGGGG{}", reverse_site)),
+            (Some(24), format!("GGGG{}", reverse_site)),
+            (Some(100), format!("AAAACCCCGGGG{}", reverse_site)),
+        ] {
+            let options = Options {
+                length,
+                ..Default::default()
+            };
+            let (_out, amplicons) =
+                run(input.to_str().unwrap(), primers.clone(), &options);
+
+            let record = &records(&amplicons)[0];
+            assert_eq!(record.seq(), expected.as_bytes());
+            assert_eq!(
+                record.desc(),
+                Some(
+                    "reverse=GGACTACCAGGGTATCTAAT rev_mismatch=0 primers=kept"
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_concat_amplicons() {
+        let labels = vec!["v3v4".to_string(), "v4".to_string()];
+        let parts = vec![Some(b"ACGT".to_vec()), None];
+        let mut concat = Concat {
+            spacer: b"NN".to_vec(),
+            policy: ConcatPolicy::Skip,
+        };
+        assert!(concat_amplicons("id", &labels, &parts, &concat).is_none());
+
+        concat.policy = ConcatPolicy::Pad;
+        let record = concat_amplicons("id", &labels, &parts, &concat).unwrap();
+        assert_eq!(record.seq(), b"ACGTNN");
+        assert_eq!(record.desc(), Some("regions=v3v4,v4 missing=v4"));
+
+        // Nothing to pad when no region was extracted
+        assert!(
+            concat_amplicons("id", &labels, &[None, None], &concat).is_none()
+        );
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_concat() {
+        let options = Options {
+            concat: Some(Concat {
+                spacer: b"NNNN".to_vec(),
+                policy: ConcatPolicy::Skip,
+            }),
+            ..Default::default()
+        };
+        let primers = vec![
+            region_to_primer("v4").unwrap(),
+            region_to_primer("v3v4").unwrap(),
+        ];
+        let (dir, amplicons) = run("tests/test.fa", primers, &options);
+
+        let mut amplicons = records(&amplicons)
+            .iter()
+            .map(|r| r.seq().to_vec())
+            .collect::<Vec<_>>();
+        let concat = records(
+            &fs::read_to_string(dir.path().join("sample.concat.fa")).unwrap(),
+        );
+        assert_eq!(amplicons.len(), 2);
+        assert_eq!(concat.len(), 1);
+        assert_eq!(concat[0].desc(), Some("regions=v4,v3v4"));
+        amplicons.insert(1, b"NNNN".to_vec());
+        assert_eq!(concat[0].seq(), amplicons.concat());
+    }
+
+    #[test]
+    fn test_hairpin_score() {
+        // GGGG and CCCC fold into a stem closed by a loop of four bases
+        assert_eq!(hairpin_score("GGGGAAAACCCC"), 4);
+        // Loops shorter than three bases cannot close
+        assert_eq!(hairpin_score("GGGAACCC"), 2);
+        assert_eq!(hairpin_score("AAAAAAAA"), 0);
+        // Ambiguous bases do not pair and break the stem in two
+        assert_eq!(hairpin_score("GGNGAAAACNCC"), 2);
+    }
+
+    #[test]
+    fn test_dimer_score() {
+        assert_eq!(dimer_score("AAAA", "TTTT"), 4);
+        assert_eq!(dimer_score("AAAA", "AAAA"), 0);
+        // Palindromic primers anneal to themselves
+        assert_eq!(dimer_score("TTGAATTCAA", "TTGAATTCAA"), 10);
+        // Overhanging ends
+        assert_eq!(dimer_score("CCCCACGT", "ACGTAAAA"), 4);
+        assert_eq!(dimer_score("", "ACGT"), 0);
+    }
+
+    #[test]
+    fn test_primer_structure() {
+        let structure = PrimerStructure::of("GGGGAAAACCCC", "AAAAAAAA");
+        assert_eq!(
+            structure,
+            PrimerStructure {
+                hairpin: 4,
+                self_dimer: 4,
+                pair_dimer: 0,
+            }
+        );
+        assert_eq!(structure.issues(), vec!["hairpin"]);
+        let v4 = region_to_primer("v3v4").unwrap();
+        assert!(PrimerStructure::of(&v4[0], &v4[1]).issues().is_empty());
+    }
+
+    #[test]
+    fn test_primer_report() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let prefix = dir.path().join("sample");
+        let prefix = prefix.to_str().unwrap();
+        let options = Options {
+            mismatch: 1,
+            ..Default::default()
+        };
+        primer_report("tests/test.fa", prefix, &options).unwrap();
+
+        let report = fs::read_to_string(format!("{}.primers.tsv", prefix))
+            .expect("Cannot read report");
+        let rows = report
+            .lines()
+            .map(|line| line.split('\t').collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows[0],
+            vec![
+                "primer",
+                "direction",
+                "sequence",
+                "matched",
+                "records",
+                "mean_distance"
+            ]
+        );
+        assert_eq!(rows.len(), 1 + 9 + 11);
+        assert_eq!(rows[1][0], "27F");
+        assert_eq!(rows[8][0], "ITS1F");
+        assert_eq!(rows[10][0], "336R");
+        // Primers of the regions found in the test sequence
+        let row = |name: &str| rows.iter().find(|r| r[0] == name).unwrap();
+        assert_eq!(row("515F")[3..], ["1", "1", "0.00"]);
+        assert_eq!(row("806R")[3..], ["1", "1", "0.00"]);
+        // The test sequence starts after the 27F primer site
+        assert_eq!(row("27F")[3..], ["0", "1", "NA"]);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_primer_sites() {
+        let options = Options {
+            primer_sites: true,
+            ..Default::default()
+        };
+        let (dir, amplicons) = run(
+            "tests/test.fa",
+            vec![region_to_primer("v4").unwrap()],
+            &options,
+        );
+
+        let seq = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        let amplicon = &records(&amplicons)[0];
+        let sites = records(
+            &fs::read_to_string(dir.path().join("sample.sites.fa")).unwrap(),
+        );
+        assert_eq!(sites.len(), 2);
+        assert_eq!(sites[0].id(), format!("{}_fwd", seq.id()));
+        assert_eq!(sites[1].id(), format!("{}_rev", seq.id()));
+
+        // Sites are sliced from the sequence at their reported 1-based
+        // coordinates and bound the amplicon
+        for site in &sites {
+            let coord = |key: &str| -> usize {
+                site.desc()
+                    .unwrap()
+                    .split(' ')
+                    .find_map(|x| x.strip_prefix(key))
+                    .unwrap()
+                    .parse()
+                    .unwrap()
+            };
+            assert_eq!(
+                site.seq(),
+                &seq.seq()[coord("start=") - 1..coord("end=")]
+            );
+        }
+        assert!(amplicon.seq().starts_with(sites[0].seq()));
+        assert!(amplicon.seq().ends_with(sites[1].seq()));
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_min_distances() {
+        let seq = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        let seq = std::str::from_utf8(seq.seq()).unwrap();
+        // Forward primer at 100..120 and reverse primer at 130..150
+        let primers = vec![vec![
+            seq[100..120].to_string(),
+            to_reverse_complement(&seq[130..150], "dna"),
+        ]];
+        let count = |forward: usize, reverse: usize| {
+            let options = Options {
+                min_forward_distance: Some(forward),
+                min_reverse_distance: Some(reverse),
+                ..Default::default()
+            };
+            run("tests/test.fa", primers.clone(), &options)
+                .1
+                .matches('>')
+                .count()
+        };
+
+        assert_eq!(count(30, 10), 1);
+        assert_eq!(count(31, 0), 0);
+        assert_eq!(count(0, 11), 0);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_primers_out_of_order() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let record = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        let seq = std::str::from_utf8(record.seq()).unwrap();
+        // Forward primer at 100..120 and reverse primer at 200..220
+        let primers = vec![vec![
+            seq[100..120].to_string(),
+            to_reverse_complement(&seq[200..220], "dna"),
+        ]];
+        // Reverse primer site before the forward one, as in sequences
+        // stored in the other orientation
+        let swapped = format!("{}{}", &seq[150..250], &seq[50..150]);
+        let input = dir.path().join("input.fa");
+        let mut writer = fasta::Writer::to_file(&input).unwrap();
+        writer.write("swapped", None, swapped.as_bytes()).unwrap();
+        writer.write("forward", None, seq.as_bytes()).unwrap();
+        drop(writer);
+
+        let options = Options {
+            collect_errors: true,
+            ..Default::default()
+        };
+        let (out, stats) =
+            run_stats(input.to_str().unwrap(), primers, &options);
+        assert_eq!(stats.records, 2);
+        assert_eq!(
+            stats.errors.lines(),
+            vec!["region not extracted: 1 record (e.g. swapped)"]
+        );
+
+        let records =
+            records(&fs::read_to_string(out.path().join("sample.fa")).unwrap());
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id(), "forward");
+        assert_eq!(records[0].seq(), &record.seq()[100..220]);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_minus_strand() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let record = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        let seq = std::str::from_utf8(record.seq()).unwrap();
+        let input = dir.path().join("input.fa");
+        let mut writer = fasta::Writer::to_file(&input).unwrap();
+        writer.write("plus", None, record.seq()).unwrap();
+        writer
+            .write("minus", None, to_reverse_complement(seq, "dna").as_bytes())
+            .unwrap();
+        drop(writer);
+
+        let (out, amplicons) = run(
+            input.to_str().unwrap(),
+            vec![region_to_primer("v3v5").unwrap()],
+            &Options::default(),
+        );
+
+        let records = records(&amplicons);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].seq(), records[1].seq());
+
+        let gff = fs::read_to_string(out.path().join("sample.gff")).unwrap();
+        let features = gff
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| line.split('\t').collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(features[0][3..7], ["268", "827", ".", "+"]);
+        let (start, end) = (seq.len() - 827 + 1, seq.len() - 267);
+        assert_eq!(
+            features[1][3..7],
+            [start.to_string(), end.to_string(), ".".into(), "-".into()]
+        );
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_dedup() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let record = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        // The third record differs from the first two in its v4 amplicon
+        let mut other = record.seq().to_vec();
+        other[500] = if other[500] == b'A' { b'C' } else { b'A' };
+        let input = dir.path().join("input.fa");
+        let mut writer = fasta::Writer::to_file(&input).unwrap();
+        writer.write("seq1", None, record.seq()).unwrap();
+        writer.write("seq2", None, record.seq()).unwrap();
+        writer.write("seq3", None, &other).unwrap();
+        drop(writer);
+
+        let options = Options {
+            dedup: true,
+            ..Default::default()
+        };
+        let (out, stats) = run_stats(
+            input.to_str().unwrap(),
+            vec![region_to_primer("v4").unwrap()],
+            &options,
+        );
+
+        let ids =
+            records(&fs::read_to_string(out.path().join("sample.fa")).unwrap())
+                .iter()
+                .map(|record| record.id().to_string())
+                .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["seq1", "seq3"]);
+        // Duplicates are still counted as extracted
+        assert_eq!(stats.regions[0].1.extracted, 3);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_strand() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let record = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        let seq = std::str::from_utf8(record.seq()).unwrap();
+        let input = dir.path().join("input.fa");
+        let mut writer = fasta::Writer::to_file(&input).unwrap();
+        writer.write("plus", None, record.seq()).unwrap();
+        writer
+            .write("minus", None, to_reverse_complement(seq, "dna").as_bytes())
+            .unwrap();
+        drop(writer);
+
+        let extracted = |strand: Strand| {
+            let options = Options {
+                strand,
+                ..Default::default()
+            };
+            let (_out, amplicons) = run(
+                input.to_str().unwrap(),
+                vec![region_to_primer("v4").unwrap()],
+                &options,
+            );
+            records(&amplicons)
+                .iter()
+                .map(|record| record.id().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(extracted(Strand::Plus), vec!["plus"]);
+        assert_eq!(extracted(Strand::Minus), vec!["minus"]);
+        assert_eq!(extracted(Strand::Both), vec!["plus", "minus"]);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_length_bounds() {
+        // The v4 amplicon of the test sequence is 292 bases long
+        let count = |min: Option<usize>, max: Option<usize>| {
+            let options = Options {
+                min_length: min,
+                max_length: max,
+                ..Default::default()
+            };
+            run(
+                "tests/test.fa",
+                vec![region_to_primer("v4").unwrap()],
+                &options,
+            )
+            .1
+            .matches('>')
+            .count()
+        };
+
+        assert_eq!(count(Some(293), None), 0);
+        assert_eq!(count(Some(292), Some(292)), 1);
+        assert_eq!(count(None, Some(291)), 0);
+        assert_eq!(count(None, None), 1);
+    }
+
+    #[test]
+    fn test_primer_window() {
+        let options = Options {
+            auto_window: true,
+            ..Default::default()
+        };
+        let v4 = region_to_primer("v4").unwrap();
+        assert_eq!(primer_window(&v4[0], 1500, &options), 314..733);
+        assert_eq!(primer_window(&v4[0], 600, &options), 314..600);
+        assert_eq!(primer_window(&v4[1], 1500, &options), 586..1006);
+        // Custom primers and disabled windows search the whole sequence
+        assert_eq!(primer_window("ACGT", 1500, &options), 0..1500);
+        assert_eq!(primer_window(&v4[0], 1500, &Options::default()), 0..1500);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_auto_window() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let record = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        // A copy of the v4 forward primer binding site upstream of v1
+        let seq = [&record.seq()[416..435], record.seq()].concat();
+        let input = dir.path().join("spurious.fa");
+        fasta::Writer::to_file(&input)
+            .unwrap()
+            .write("seq", None, &seq)
+            .unwrap();
+        let gff_coords = |auto_window: bool| {
+            let options = Options {
+                auto_window,
+                ..Default::default()
+            };
+            let (out, _) = run(
+                input.to_str().unwrap(),
+                vec![region_to_primer("v4").unwrap()],
+                &options,
+            );
+            let gff =
+                fs::read_to_string(out.path().join("sample.gff")).unwrap();
+            let feature =
+                gff.lines().find(|line| !line.starts_with('#')).unwrap();
+            let fields = feature.split('\t');
+            fields.skip(3).take(2).collect::<Vec<_>>().join("-")
+        };
+
+        assert_eq!(gff_coords(false), "1-727");
+        assert_eq!(gff_coords(true), "436-727");
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_stats() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("input.fa");
+        many_records(&input, 3);
+        // Too short to have the v4 primers
+        let mut file = OpenOptions::new().append(true).open(&input).unwrap();
+        writeln!(file, ">short\nGGGCAGGCTTAACACATGCAAGTCGAGCG").unwrap();
+        drop(file);
+        let (_out, stats) = run_stats(
+            input.to_str().unwrap(),
+            vec![
+                region_to_primer("v4").unwrap(),
+                region_to_primer("v1v2").unwrap(),
+            ],
+            &Options::default(),
+        );
+        assert_eq!(
+            stats,
+            ExtractionStats {
+                records: 4,
+                regions: vec![
+                    (
+                        "v4".to_string(),
+                        RegionStats {
+                            extracted: 3,
+                            not_found: 1
+                        }
+                    ),
+                    (
+                        "v1v2".to_string(),
+                        RegionStats {
+                            extracted: 0,
+                            not_found: 4
+                        }
+                    )
+                ],
+                errors: ErrorSummary::default(),
+            }
+        );
+
+        let mut output = Vec::new();
+        write_stats(&mut output, &stats).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "STATS\trecords\t4\nSTATS\tregion\tv4\t3\nSTATS\tregion\tv1v2\t0\n"
+        );
     }
 
     #[test]
-    fn test_sequence_type_rna_ok() {
-        assert_eq!(sequence_type("AGCUUUGCA"), Some(Alphabet::Rna));
+    fn test_get_hypervar_regions_collect_errors() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("input.fa");
+        many_records(&input, 5);
+        let mut file = OpenOptions::new().append(true).open(&input).unwrap();
+        // Both T and U, neither DNA nor RNA
+        writeln!(file, ">mixed\nACGTACGUACGU").unwrap();
+        drop(file);
+        let options = Options {
+            collect_errors: true,
+            ..Default::default()
+        };
+        let (_out, stats) = run_stats(
+            input.to_str().unwrap(),
+            vec![
+                region_to_primer("v4").unwrap(),
+                region_to_primer("v1v2").unwrap(),
+            ],
+            &options,
+        );
+        assert_eq!(stats.records, 6);
+        assert_eq!(
+            stats.errors.lines(),
+            vec![
+                "sequence type not recognized: 1 record (e.g. mixed)",
+                "region not found: 5 records (e.g. seq0, seq1, seq2)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_trim() {
+        let record = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        let seq = std::str::from_utf8(record.seq()).unwrap();
+        // Forward primer at 100..120 and reverse primer at 200..220
+        let primers = vec![vec![
+            seq[100..120].to_string(),
+            to_reverse_complement(&seq[200..220], "dna"),
+        ]];
+        let amplicon = |trim: Trim| {
+            let options = Options {
+                trim,
+                ..Default::default()
+            };
+            let (out, amplicons) =
+                run("tests/test.fa", primers.clone(), &options);
+            let amplicon = &records(&amplicons)[0];
+            assert!(amplicon
+                .desc()
+                .unwrap()
+                .ends_with(&format!(" primers={}", trim.note())));
+            let gff =
+                fs::read_to_string(out.path().join("sample.gff")).unwrap();
+            let coords = gff
+                .lines()
+                .find(|line| !line.starts_with('#'))
+                .unwrap()
+                .split('\t')
+                .skip(3)
+                .take(2)
+                .map(|x| x.parse::<usize>().unwrap())
+                .collect::<Vec<_>>();
+            (amplicon.seq().to_vec(), coords)
+        };
+
+        for (trim, start, end) in [
+            (Trim::None, 100, 220),
+            (Trim::Forward, 120, 220),
+            (Trim::Reverse, 100, 200),
+            (Trim::Both, 120, 200),
+        ] {
+            assert_eq!(
+                amplicon(trim),
+                (record.seq()[start..end].to_vec(), vec![start + 1, end])
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_emit_both() {
+        let record = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        let options = Options {
+            trim: Trim::Both,
+            emit_both: true,
+            ..Default::default()
+        };
+        let (out, amplicons) = run(
+            "tests/test.fa",
+            vec![region_to_primer("v4").unwrap()],
+            &options,
+        );
+
+        // v4 primers bind 416..435 and 688..708
+        let untrimmed = &records(&amplicons)[0];
+        let trimmed = &records(
+            &fs::read_to_string(out.path().join("sample.trimmed.fa")).unwrap(),
+        )[0];
+        assert_eq!(untrimmed.seq(), &record.seq()[416..708]);
+        assert_eq!(trimmed.seq(), &record.seq()[435..688]);
+        assert_eq!(trimmed.id(), untrimmed.id());
+        assert_eq!(
+            trimmed.desc(),
+            untrimmed
+                .desc()
+                .map(|desc| desc.replace("primers=kept", "primers=trimmed"))
+                .as_deref()
+        );
+        // Coordinates are those of the untrimmed amplicon
+        let gff = fs::read_to_string(out.path().join("sample.gff")).unwrap();
+        assert!(gff.contains("\t417\t708\t"));
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_trim_adjacent_primers() {
+        let record = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        let seq = std::str::from_utf8(record.seq()).unwrap();
+        // Nothing is left between primers binding 100..120 and 120..140
+        let primers = vec![vec![
+            seq[100..120].to_string(),
+            to_reverse_complement(&seq[120..140], "dna"),
+        ]];
+        let options = Options {
+            trim: Trim::Both,
+            ..Default::default()
+        };
+        assert_eq!(run("tests/test.fa", primers, &options).1, "");
+    }
+
+    #[test]
+    fn test_strands_agree() {
+        let hit = |start, end| PrimerHit {
+            start,
+            end,
+            dist: 0,
+        };
+        assert!(strands_agree(None, None, 0));
+        assert!(strands_agree(Some(hit(10, 30)), Some(hit(10, 30)), 0));
+        assert!(strands_agree(Some(hit(10, 30)), Some(hit(12, 29)), 2));
+        assert!(!strands_agree(Some(hit(10, 30)), Some(hit(12, 29)), 1));
+        assert!(!strands_agree(Some(hit(10, 30)), None, 10));
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_strand_agreement() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let record = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        // A second v4 forward primer binding site at the sequence end is the
+        // first one found on the minus strand
+        let seq = [record.seq(), &record.seq()[416..435]].concat();
+        let input = dir.path().join("repeated.fa");
+        fasta::Writer::to_file(&input)
+            .unwrap()
+            .write("seq", None, &seq)
+            .unwrap();
+        let count = |file: &str| {
+            let options = Options {
+                strand_agreement: Some(0),
+                ..Default::default()
+            };
+            run(file, vec![region_to_primer("v4").unwrap()], &options)
+                .1
+                .matches('>')
+                .count()
+        };
+
+        assert_eq!(count("tests/test.fa"), 1);
+        assert_eq!(count(input.to_str().unwrap()), 0);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_resume_from() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("input.fa");
+        many_records(&input, 5);
+        let prefix = dir.path().join("sample");
+        let prefix = prefix.to_str().unwrap();
+        let resume = |resume_from: &str, append: bool| {
+            let options = Options {
+                resume_from: Some(resume_from.to_string()),
+                append,
+                ..Default::default()
+            };
+            get_hypervar_regions(
+                input.to_str().unwrap(),
+                vec![region_to_primer("v4").unwrap()],
+                prefix,
+                &options,
+            )
+            .unwrap()
+        };
+        let ids = |ext: &str| {
+            fs::read_to_string(format!("{}.{}", prefix, ext))
+                .unwrap()
+                .lines()
+                .filter(|line| !line.starts_with("##sequence-region"))
+                .map(|line| line.split([' ', '\t']).next().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(resume("seq3", false).records, 2);
+        assert_eq!(
+            ids("fa").iter().step_by(2).collect::<Vec<_>>(),
+            [">seq3", ">seq4"]
+        );
+
+        // Appended records follow the previous ones under a single header
+        assert_eq!(resume("seq1", true).records, 4);
+        assert_eq!(
+            ids("gff"),
+            [
+                "##gff-version",
+                "seq3",
+                "seq4",
+                "seq1",
+                "seq2",
+                "seq3",
+                "seq4"
+            ]
+        );
+
+        assert_eq!(resume("missing", false).records, 0);
+        assert_eq!(ids("fa"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_id_prefix_suffix() {
+        let options = Options {
+            id_prefix: "S1_".to_string(),
+            id_suffix: "|v".to_string(),
+            primer_sites: true,
+            coverage: true,
+            ..Default::default()
+        };
+        let (dir, _) = run(
+            "tests/test.fa",
+            vec![region_to_primer("v4").unwrap()],
+            &options,
+        );
+
+        let id = "S1_Allorhizobium_borbori__DN316__EF125187|v";
+        // Records ids of fasta headers, or first fields of other lines
+        let ids = |ext: &str| {
+            fs::read_to_string(dir.path().join(format!("sample.{}", ext)))
+                .unwrap()
+                .lines()
+                .filter(|line| {
+                    !line.starts_with('#') && !line.starts_with("track")
+                })
+                .filter(|line| !ext.ends_with("fa") || line.starts_with('>'))
+                .map(|line| {
+                    line.trim_start_matches('>')
+                        .split([' ', '\t'])
+                        .next()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids("fa"), [id]);
+        assert_eq!(ids("gff"), [id]);
+        assert_eq!(ids("bedgraph"), [id]);
+        assert_eq!(
+            ids("sites.fa"),
+            [format!("{}_fwd", id), format!("{}_rev", id)]
+        );
+    }
+
+    #[test]
+    fn test_infer_region() {
+        let v4 = region_to_primer("v4").unwrap();
+        assert_eq!(infer_region(&v4), Some("v4".to_string()));
+        // One base off 341F and 805R
+        let primers = vec![
+            "CCTACGGGAGGCWGCAG".to_string(),
+            "GACTACHVGGGTATCTAATCG".to_string(),
+        ];
+        assert_eq!(infer_region(&primers), Some("v3v4".to_string()));
+        let primers = vec!["".to_string(), "GGACTACHVGGGTWTCTAAA".to_string()];
+        assert_eq!(infer_region(&primers), Some("v4".to_string()));
+        // Forward primers are not compared to reverse ones
+        let primers = vec![v4[1].clone(), v4[0].clone()];
+        assert_eq!(infer_region(&primers), None);
+        let primers = vec!["ACGTACGTACGT".to_string(), v4[1].clone()];
+        assert_eq!(infer_region(&primers), None);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_infer_region() {
+        let options = Options {
+            infer_region: true,
+            ..Default::default()
+        };
+        // 515F and 806R with their last base changed still match within a
+        // mismatch
+        let primers = vec![vec![
+            "GTGCCAGCMGCCGCGGTAT".to_string(),
+            "GGACTACHVGGGTWTCTAAA".to_string(),
+        ]];
+        let (_dir, fasta) = run(
+            "tests/test.fa",
+            primers,
+            &Options {
+                mismatch: 1,
+                ..options
+            },
+        );
+
+        let header = fasta.lines().next().unwrap();
+        assert!(header.contains(" region_inferred=v4 "));
+        assert!(!header.contains(" region=v4"));
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_sequence_region() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let record = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        let input = dir.path().join("input.fa");
+        let mut writer = fasta::Writer::to_file(&input).unwrap();
+        writer.write("full", None, record.seq()).unwrap();
+        writer.write("none", None, b"ACGTACGTACGT").unwrap();
+        writer.write("short", None, &record.seq()[..800]).unwrap();
+        drop(writer);
+        let options = Options {
+            group_by_region: true,
+            ..Default::default()
+        };
+        let (out, _) = run(
+            input.to_str().unwrap(),
+            vec![
+                region_to_primer("v3v4").unwrap(),
+                region_to_primer("v4").unwrap(),
+            ],
+            &options,
+        );
+
+        // Records without features are not described, others once
+        let gff = fs::read_to_string(out.path().join("sample.gff")).unwrap();
+        let pragmas = gff
+            .lines()
+            .filter(|line| line.starts_with("##sequence-region"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pragmas,
+            [
+                format!("##sequence-region full 1 {}", record.seq().len()),
+                "##sequence-region short 1 800".to_string()
+            ]
+        );
+        let lines = gff.lines().collect::<Vec<_>>();
+        assert!(lines[1].starts_with("##sequence-region full"));
+        assert!(lines[2].starts_with("full\t"));
+    }
+
+    #[test]
+    fn test_coverage() {
+        assert_eq!(coverage(&[]), vec![]);
+        assert_eq!(coverage(&[(10, 20)]), vec![(10, 20, 1)]);
+        // Overlapping, nested and adjacent intervals
+        assert_eq!(
+            coverage(&[(0, 10), (5, 15), (20, 30), (30, 40), (22, 25)]),
+            vec![
+                (0, 5, 1),
+                (5, 10, 2),
+                (10, 15, 1),
+                (20, 22, 1),
+                (22, 25, 2),
+                (25, 40, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_length_histogram() {
+        assert_eq!(length_histogram(&[], 10), vec![]);
+        assert_eq!(length_histogram(&[292], 10), vec![(290, 1)]);
+        // Empty bins between the shortest and longest lengths are kept
+        assert_eq!(
+            length_histogram(&[101, 109, 110, 135, 99], 10),
+            vec![(90, 1), (100, 2), (110, 1), (120, 0), (130, 1)]
+        );
+        assert_eq!(length_histogram(&[3, 3, 4], 1), vec![(3, 2), (4, 1)]);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_length_histogram() {
+        let options = Options {
+            length_bin: Some(50),
+            ..Default::default()
+        };
+        let (dir, _) = run(
+            "tests/test.fa",
+            vec![
+                region_to_primer("v3v4").unwrap(),
+                region_to_primer("v1v2").unwrap(),
+                region_to_primer("v4").unwrap(),
+            ],
+            &options,
+        );
+
+        // v1v2 is not found and has no bins
+        assert_eq!(
+            fs::read_to_string(dir.path().join("sample.lengths.tsv")).unwrap(),
+            "region\tbin_start\tbin_end\tcount\n\
+            v3v4\t400\t449\t1\n\
+            v4\t250\t299\t1\n"
+        );
+    }
+
+    #[test]
+    fn test_sample_name() {
+        assert_eq!(sample_name("data/sampleA.fa"), "sampleA");
+        assert_eq!(sample_name("sampleB.fastq.gz"), "sampleB");
+        assert_eq!(sample_name("run.1.tar.gz"), "run.1");
+        assert_eq!(sample_name("reads"), "reads");
+    }
+
+    #[test]
+    fn test_length_summary() {
+        assert_eq!(length_summary(&[]), None);
+        assert_eq!(
+            length_summary(&[64, 59, 60, 61]),
+            Some(LengthSummary {
+                count: 4,
+                min: 59,
+                max: 64,
+                mean: 61.0,
+                median: 60.5
+            })
+        );
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_length_stats() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        // v4 amplicons of 59, 60 and 64 bases
+        let input = dir.path().join("input.fa");
+        let records = [20, 21, 25]
+            .iter()
+            .enumerate()
+            .map(|(i, &insert)| {
+                format!(
+                    ">seq{}\nAAAAGTGCCAGCAGCCGCGGTAA{}ATTAGATACCCTGGTAGTCCAAAA\n",
+                    i,
+                    "C".repeat(insert)
+                )
+            })
+            .collect::<String>();
+        fs::write(&input, records).unwrap();
+        let options = Options {
+            length_stats: true,
+            ..Default::default()
+        };
+        let (out, _) = run(
+            input.to_str().unwrap(),
+            vec![
+                region_to_primer("v4").unwrap(),
+                region_to_primer("v1v2").unwrap(),
+            ],
+            &options,
+        );
+
+        // (59 + 60 + 64) / 3 = 61
+        assert_eq!(
+            fs::read_to_string(out.path().join("sample.stats.tsv")).unwrap(),
+            "region\tcount\tmin\tmax\tmean\tmedian\n\
+            v4\t3\t59\t64\t61.00\t60\n\
+            v1v2\t0\tNA\tNA\tNA\tNA\n"
+        );
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_coverage() {
+        let options = Options {
+            coverage: true,
+            ..Default::default()
+        };
+        let (dir, _) = run(
+            "tests/test.fa",
+            vec![
+                region_to_primer("v3v4").unwrap(),
+                region_to_primer("v4").unwrap(),
+            ],
+            &options,
+        );
+
+        let gff = fs::read_to_string(dir.path().join("sample.gff")).unwrap();
+        let coords = gff
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| {
+                let fields = line.split('\t').collect::<Vec<_>>();
+                // GFF3 starts are 1-based, bedGraph ones 0-based
+                let start = fields[3].parse::<usize>().unwrap() - 1;
+                (start, fields[4].parse::<usize>().unwrap())
+            })
+            .collect::<Vec<_>>();
+        let (v3v4, v4) = (&coords[0], &coords[1]);
+        let id = "Allorhizobium_borbori__DN316__EF125187";
+
+        // v4 is nested within v3v4
+        let bedgraph =
+            fs::read_to_string(dir.path().join("sample.bedgraph")).unwrap();
+        assert_eq!(
+            bedgraph,
+            format!(
+                "track type=bedGraph name=hyperex\n\
+                {id}\t{}\t{}\t1\n{id}\t{}\t{}\t2\n{id}\t{}\t{}\t1\n",
+                v3v4.0,
+                v4.0,
+                v4.0,
+                v3v4.1,
+                v3v4.1,
+                v4.1,
+                id = id
+            )
+        );
+    }
+
+    #[test]
+    fn test_benchmark_regions() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("input.fa");
+        let seq = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        // The fragment lacks the v3 forward primer site
+        let mut writer = fasta::Writer::to_file(&input).unwrap();
+        writer.write("full", None, seq.seq()).unwrap();
+        writer.write("fragment", None, &seq.seq()[400..]).unwrap();
+        drop(writer);
+
+        let primers = ["v1v2", "v3v4", "v4"]
+            .iter()
+            .map(|region| region_to_primer(region).unwrap())
+            .collect::<Vec<_>>();
+        let benchmarks = benchmark_regions(
+            input.to_str().unwrap(),
+            &primers,
+            &Options::default(),
+        )
+        .unwrap();
+
+        let ranking = benchmarks
+            .iter()
+            .map(|b| (b.region.as_str(), b.extracted, b.records))
+            .collect::<Vec<_>>();
+        assert_eq!(ranking, vec![("v4", 2, 2), ("v3v4", 1, 2), ("v1v2", 0, 2)]);
+        assert_eq!(benchmarks[0].mean_mismatch, Some(0.0));
+        assert_eq!(benchmarks[2].mean_length, None);
+
+        let mut table = Vec::new();
+        write_benchmark(&mut table, &benchmarks).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            "rank\tregion\textracted\trecords\tfraction\tmean_length\tmean_mismatch"
+        );
+        assert_eq!(lines[1], "1\tv4\t2\t2\t1.000\t292.00\t0.00");
+        assert_eq!(lines[3], "3\tv1v2\t0\t2\t0.000\tNA\tNA");
+    }
+
+    #[test]
+    fn test_ambiguous_fraction() {
+        assert_eq!(ambiguous_fraction(b"ACGT"), 0.0);
+        assert_eq!(ambiguous_fraction(b"ACNN"), 0.5);
+        assert_eq!(ambiguous_fraction(b"acgu"), 0.0);
+        assert_eq!(ambiguous_fraction(b"RYKM"), 1.0);
+        assert_eq!(ambiguous_fraction(b""), 0.0);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_max_ambiguous() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("input.fa");
+        let seq = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        // Mask the middle of the v4 amplicon with Ns in a second record
+        let mut masked = seq.seq().to_vec();
+        let start = find_primer_hits(
+            &Matchers::new(&[]),
+            &masked,
+            &[region_to_primer("v4").unwrap()],
+            "dna",
+            &Options::default(),
+        )[0]
+        .0
+        .unwrap()
+        .end;
+        masked[start + 20..start + 120].fill(b'N');
+        let mut writer = fasta::Writer::to_file(&input).unwrap();
+        writer.write("clean", None, seq.seq()).unwrap();
+        writer.write("masked", None, &masked).unwrap();
+        drop(writer);
+        let options = Options {
+            max_ambiguous: Some(0.1),
+            ..Default::default()
+        };
+        let (out, amplicons) = run(
+            input.to_str().unwrap(),
+            vec![region_to_primer("v4").unwrap()],
+            &options,
+        );
+
+        let ids = |fasta: &str| {
+            records(fasta)
+                .iter()
+                .map(|r| (r.id().to_string(), r.desc().map(|d| d.to_string())))
+                .collect::<Vec<_>>()
+        };
+        let kept = ids(&amplicons);
+        let ambiguous =
+            ids(&fs::read_to_string(out.path().join("sample.ambiguous.fa"))
+                .unwrap());
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].0, "clean");
+        assert_eq!(ambiguous.len(), 1);
+        assert_eq!(ambiguous[0].0, "masked");
+        assert!(ambiguous[0]
+            .1
+            .as_deref()
+            .unwrap()
+            .contains("ambiguous_fraction=0.3"));
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_max_ambiguous_threshold() {
+        // Half of the v4 amplicon bases are Ns
+        let seq = format!(
+            "AAAAGTGCCAGCAGCCGCGGTAA{}ATTAGATACCCTGGTAGTCCAAAA",
+            "N".repeat(39)
+        );
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("input.fa");
+        fs::write(&input, format!(">seq1\n{}\n", seq)).unwrap();
+
+        let kept = |max: f64| {
+            let options = Options {
+                max_ambiguous: Some(max),
+                ..Default::default()
+            };
+            run(
+                input.to_str().unwrap(),
+                vec![region_to_primer("v4").unwrap()],
+                &options,
+            )
+            .1
+            .matches('>')
+            .count()
+        };
+
+        assert_eq!(kept(0.2), 0);
+        assert_eq!(kept(1.0), 1);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_max_ambiguous_fastq() {
+        let seq = format!(
+            "AAAAGTGCCAGCAGCCGCGGTAA{}ATTAGATACCCTGGTAGTCCAAAA",
+            "N".repeat(39)
+        );
+        let qual = (0..seq.len())
+            .map(|i| (b'#' + i as u8 % 40) as char)
+            .collect::<String>();
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("reads.fq");
+        fs::write(&input, format!("@read1\n{}\n+\n{}\n", seq, qual)).unwrap();
+        let options = Options {
+            max_ambiguous: Some(0.2),
+            ..Default::default()
+        };
+        let (out, _) = run_stats(
+            input.to_str().unwrap(),
+            vec![region_to_primer("v4").unwrap()],
+            &options,
+        );
+
+        // Amplicons set aside keep their qualities
+        let record =
+            fastq::Reader::from_file(out.path().join("sample.ambiguous.fq"))
+                .unwrap()
+                .records()
+                .next()
+                .unwrap()
+                .unwrap();
+        assert_eq!(record.id(), "read1");
+        assert_eq!(record.seq(), &seq.as_bytes()[4..seq.len() - 4]);
+        assert_eq!(record.qual(), &qual.as_bytes()[4..seq.len() - 4]);
+        assert!(!out.path().join("sample.ambiguous.fa").exists());
+    }
+
+    #[test]
+    fn test_is_tar_archive() {
+        assert!(is_tar_archive("genomes.tar.gz"));
+        assert!(is_tar_archive("genomes.tgz"));
+        assert!(is_tar_archive("genomes.tar"));
+        assert!(!is_tar_archive("genomes.fa.gz"));
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn test_get_hypervar_regions_tar() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let seq = fs::read("tests/test.fa").unwrap();
+        let archive = dir.path().join("genomes.tar.gz");
+        let encoder = flate2::write::GzEncoder::new(
+            File::create(&archive).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut builder = tar::Builder::new(encoder);
+        for name in ["first.fa", "nested/second.fasta", "README"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(seq.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, &seq[..]).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        let (_out, amplicons) = run(
+            archive.to_str().unwrap(),
+            vec![region_to_primer("v4").unwrap()],
+            &Options::default(),
+        );
+
+        let descs = records(&amplicons)
+            .iter()
+            .map(|r| r.desc().unwrap().to_string())
+            .collect::<Vec<_>>();
+        // README is not a sequence file and is skipped
+        assert_eq!(descs.len(), 2);
+        assert!(descs[0].ends_with(" source=first.fa"));
+        assert!(descs[1].ends_with(" source=nested/second.fasta"));
+    }
+
+    // Write a FASTA file of `n` copies of the test sequence named seq0..seqN
+    fn many_records(path: &Path, n: usize) {
+        let seq = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        let mut writer = fasta::Writer::to_file(path).unwrap();
+        for i in 0..n {
+            writer.write(&format!("seq{}", i), None, seq.seq()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_threads_keep_order() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("input.fa");
+        many_records(&input, 50);
+        let primers = vec![
+            region_to_primer("v4").unwrap(),
+            region_to_primer("v3v4").unwrap(),
+        ];
+        let extracted = |threads: usize, keep_order: bool| {
+            let options = Options {
+                threads,
+                keep_order,
+                ..Default::default()
+            };
+            run(input.to_str().unwrap(), primers.clone(), &options).1
+        };
+
+        let single = extracted(1, true);
+        let ids = single
+            .lines()
+            .filter(|l| l.starts_with('>'))
+            .map(|l| l.split(' ').next().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(ids.len(), 100);
+        assert_eq!(ids[0], ">seq0");
+        assert_eq!(ids[99], ">seq49");
+        // With more records than can be waiting to be written
+        assert_eq!(extracted(2, true), single);
+
+        // Without keeping order, the same records are written
+        let mut unordered = extracted(2, false)
+            .lines()
+            .map(|l| l.to_string())
+            .collect::<Vec<_>>();
+        let mut expected =
+            single.lines().map(|l| l.to_string()).collect::<Vec<_>>();
+        unordered.sort();
+        expected.sort();
+        assert_eq!(unordered, expected);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_read_error() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("input.fq");
+        // The second record has no separator line
+        fs::write(&input, "@seq1\nACGT\n+\nIIII\n@seq2\nACGT\nIIII\n").unwrap();
+
+        for threads in [1, 2] {
+            let options = Options {
+                threads,
+                ..Default::default()
+            };
+            assert!(get_hypervar_regions(
+                input.to_str().unwrap(),
+                vec![region_to_primer("v4").unwrap()],
+                dir.path().join("sample").to_str().unwrap(),
+                &options,
+            )
+            .is_err());
+        }
+        assert!(benchmark_regions(
+            input.to_str().unwrap(),
+            &[region_to_primer("v4").unwrap()],
+            &Options::default(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_check_input() {
+        assert_eq!(
+            check_input("tests/test.fa.gz", InputFormat::Auto).unwrap(),
+            InputCheck {
+                id: "Allorhizobium_borbori__DN316__EF125187".to_string(),
+                alphabet: Alphabet::Dna,
+                fastq: false,
+            }
+        );
+
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, ">seq1\nACGTXACGT").unwrap();
+        assert!(
+            check_input(file.path().to_str().unwrap(), InputFormat::Auto)
+                .is_err()
+        );
+        let file = NamedTempFile::new().unwrap();
+        assert!(
+            check_input(file.path().to_str().unwrap(), InputFormat::Auto)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_sanitize_records() {
+        let records: SeqRecords<'static> = Box::new(
+            vec![
+                Ok(SeqRecord {
+                    id: "clean".to_string(),
+                    desc: None,
+                    seq: b"AC-gT".to_vec(),
+                    qual: None,
+                    positions: None,
+                }),
+                Ok(SeqRecord {
+                    id: "ruler".to_string(),
+                    desc: None,
+                    seq: b"10 ACG TA20 C".to_vec(),
+                    qual: Some(b"0123456789abc".to_vec()),
+                    positions: None,
+                }),
+            ]
+            .into_iter(),
+        );
+        let records = sanitize_records(records)
+            .map(|r| r.unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(records[0].seq, b"AC-gT");
+        assert_eq!(records[0].positions, None);
+        assert_eq!(records[1].seq, b"ACGTAC");
+        assert_eq!(records[1].qual, Some(b"34578c".to_vec()));
+        assert_eq!(records[1].positions, Some(vec![3, 4, 5, 7, 8, 12]));
+        assert_eq!(records[1].input_range(1, 4), (4, 8));
+        assert_eq!(records[1].input_range(0, 6), (3, 13));
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_stray_characters() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let record = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        // Position rulers every 100 bases, as in pasted alignments
+        let mut seq = Vec::new();
+        for (i, chunk) in record.seq().chunks(100).enumerate() {
+            seq.extend_from_slice(format!("{} ", i * 100 + 1).as_bytes());
+            seq.extend_from_slice(chunk);
+            seq.push(b' ');
+        }
+        let input = dir.path().join("ruler.fa");
+        fs::write(&input, [b">seq\n", &seq[..], b"\n"].concat()).unwrap();
+        let (out, amplicons) = run(
+            input.to_str().unwrap(),
+            vec![region_to_primer("v4").unwrap()],
+            &Options::default(),
+        );
+
+        let amplicon = records(&amplicons).remove(0);
+        assert_eq!(amplicon.seq(), &record.seq()[416..708]);
+        // v4 starts at base 16 of the fifth chunk, after "401 ", the first
+        // chunk being 103 characters long and the next ones 105
+        let gff = fs::read_to_string(out.path().join("sample.gff")).unwrap();
+        let coords = gff
+            .lines()
+            .find(|line| !line.starts_with('#'))
+            .unwrap()
+            .split('\t')
+            .collect::<Vec<_>>();
+        let start = 103 + 3 * 105 + 4 + 16;
+        assert_eq!(&seq[start..start + 19], &record.seq()[416..435]);
+        assert_eq!(coords[3], (start + 1).to_string());
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_invalid_character() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let record = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        // An X inside the v4 amplicon of the first record
+        let mut bad = record.seq().to_vec();
+        bad[500] = b'X';
+        let input = dir.path().join("input.fa");
+        let mut writer = fasta::Writer::to_file(&input).unwrap();
+        writer.write("bad", None, &bad).unwrap();
+        writer.write("clean", None, record.seq()).unwrap();
+        drop(writer);
+        let options = Options {
+            collect_errors: true,
+            ..Default::default()
+        };
+        let (out, stats) = run_stats(
+            input.to_str().unwrap(),
+            vec![region_to_primer("v4").unwrap()],
+            &options,
+        );
+
+        let ids =
+            records(&fs::read_to_string(out.path().join("sample.fa")).unwrap())
+                .iter()
+                .map(|r| r.id().to_string())
+                .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["clean"]);
+        assert_eq!(
+            stats.errors.lines(),
+            vec!["sequence type not recognized: 1 record (e.g. bad)"]
+        );
+    }
+
+    #[test]
+    fn test_check_gaps() {
+        let record = |id: &str, seq: &[u8], qual: Option<&[u8]>| {
+            Ok(SeqRecord {
+                id: id.to_string(),
+                desc: None,
+                seq: seq.to_vec(),
+                qual: qual.map(|q| q.to_vec()),
+                positions: None,
+            })
+        };
+        let records = || -> SeqRecords<'static> {
+            Box::new(
+                vec![
+                    record("a", b"ACGT", None),
+                    record("b", b"AC-G.T", Some(b"IIJJKK")),
+                ]
+                .into_iter(),
+            )
+        };
+
+        let mut warned = false;
+        let checked = check_gaps(records(), &Options::default(), &mut warned)
+            .map(|r| r.unwrap().seq)
+            .collect::<Vec<_>>();
+        assert!(warned);
+        assert_eq!(checked, vec![b"ACGT".to_vec(), b"AC-G.T".to_vec()]);
+
+        let options = Options {
+            degap: true,
+            ..Default::default()
+        };
+        let mut warned = false;
+        let degapped = check_gaps(records(), &options, &mut warned)
+            .map(|r| r.unwrap())
+            .collect::<Vec<_>>();
+        assert!(!warned);
+        assert_eq!(degapped[1].seq, b"ACGT");
+        assert_eq!(degapped[1].qual, Some(b"IIJK".to_vec()));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_get_hypervar_regions_sqlite() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let db = dir.path().join("sample.db");
+        let options = Options {
+            sqlite: Some(db.clone()),
+            ..Default::default()
+        };
+        run(
+            "tests/test.fa",
+            vec![
+                region_to_primer("v3v4").unwrap(),
+                region_to_primer("v4").unwrap(),
+            ],
+            &options,
+        );
+
+        let connection = rusqlite::Connection::open(&db).unwrap();
+        let mut statement = connection
+            .prepare(
+                "SELECT record_id, region, start, end, strand, length(sequence)
+                FROM hits ORDER BY start",
+            )
+            .unwrap();
+        let hits = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, String>(4)?,
+                    row.get::<_, i64>(5)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let id = "Allorhizobium_borbori__DN316__EF125187".to_string();
+        assert_eq!(
+            hits,
+            vec![
+                (
+                    id.clone(),
+                    "v3v4".to_string(),
+                    267,
+                    707,
+                    "+".to_string(),
+                    440
+                ),
+                (id, "v4".to_string(), 416, 708, "+".to_string(), 292),
+            ]
+        );
+        let mismatches: i64 = connection
+            .query_row("SELECT sum(mismatches) FROM hits", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mismatches, 0);
+    }
+
+    #[test]
+    fn test_handle_output_files() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let existing = dir.path().join("existing.fa");
+        let missing = dir.path().join("missing.gff");
+        fs::write(&existing, ">seq\nACGT\n").unwrap();
+        let files = vec![existing.clone(), missing];
+
+        assert!(handle_output_files(&files, false)
+            .unwrap_err()
+            .to_string()
+            .contains("already exists"));
+        assert!(existing.exists());
+
+        // Missing files are not removed
+        handle_output_files(&files, true).unwrap();
+        assert!(!existing.exists());
+        handle_output_files(&files, true).unwrap();
+    }
+
+    #[test]
+    fn test_handle_output_files_removal_error() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        // A directory in place of an output file cannot be removed
+        let blocking = dir.path().join("out.fa");
+        fs::create_dir(&blocking).unwrap();
+
+        let error = handle_output_files(&[blocking], true).unwrap_err();
+        assert!(format!("{:#}", error).starts_with(&format!(
+            "cannot overwrite existing file {}: ",
+            dir.path().join("out.fa").display()
+        )));
+    }
+
+    #[test]
+    fn test_region_dir_prefix() {
+        assert_eq!(
+            region_dir_prefix("out/sample", "v4"),
+            PathBuf::from("out/v4/sample")
+        );
+        assert_eq!(
+            region_dir_prefix("sample", ""),
+            PathBuf::from("unknown/sample")
+        );
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_split_dir() {
+        let options = Options {
+            split_dir: true,
+            ..Default::default()
+        };
+        let primers = vec![
+            region_to_primer("v4").unwrap(),
+            region_to_primer("v3v4").unwrap(),
+            region_to_primer("v1v2").unwrap(),
+        ];
+        let (dir, _) = run_stats("tests/test.fa", primers.clone(), &options);
+        let prefix = dir.path().join("sample");
+        let prefix = prefix.to_str().unwrap();
+
+        // v1v2 is not found in the sequence so no directory is created
+        assert_eq!(
+            split_dir_prefixes(prefix, &primers),
+            vec![
+                dir.path().join("v4").join("sample"),
+                dir.path().join("v3v4").join("sample"),
+                dir.path().join("v1v2").join("sample"),
+            ]
+        );
+        for region in ["v4", "v3v4"] {
+            let fa =
+                fs::read_to_string(dir.path().join(region).join("sample.fa"))
+                    .unwrap();
+            assert_eq!(fa.matches('>').count(), 1);
+            assert!(fa.contains(&format!("region={} ", region)));
+            let gff =
+                fs::read_to_string(dir.path().join(region).join("sample.gff"))
+                    .unwrap();
+            assert!(gff.starts_with("##gff-version 3\n##sequence-region "));
+            assert_eq!(gff.lines().count(), 3);
+        }
+        assert!(!dir.path().join("v1v2").exists());
+        assert!(!dir.path().join("sample.fa").exists());
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_split_by_region() {
+        let options = Options {
+            split_by_region: true,
+            ..Default::default()
+        };
+        let primers = vec![
+            region_to_primer("v3v4").unwrap(),
+            region_to_primer("v4").unwrap(),
+        ];
+        let (dir, _) = run_stats("tests/test.fa", primers.clone(), &options);
+        let prefix = dir.path().join("sample");
+        let prefix = prefix.to_str().unwrap();
+
+        assert_eq!(
+            split_file_prefixes(prefix, &primers),
+            vec![dir.path().join("sample.v3v4"), dir.path().join("sample.v4")]
+        );
+        for region in ["v3v4", "v4"] {
+            let records = records(
+                &fs::read_to_string(
+                    dir.path().join(format!("sample.{}.fa", region)),
+                )
+                .unwrap(),
+            );
+            assert_eq!(records.len(), 1);
+            assert!(records[0]
+                .desc()
+                .unwrap()
+                .starts_with(&format!("region={} ", region)));
+            assert!(dir.path().join(format!("sample.{}.gff", region)).exists());
+        }
+        assert_eq!(
+            region_file_prefix(prefix, ""),
+            dir.path().join("sample.unknown")
+        );
+        assert!(!dir.path().join("sample.fa").exists());
     }
 
     #[test]
-    fn test_sequence_type_rna_iupac_ok() {
-        assert_eq!(sequence_type("GUUUUAACCCAAM"), Some(Alphabet::Rna));
+    fn test_setup_logging() {
+        assert!(setup_logging(false, false, None, None).is_ok());
     }
 
     #[test]
-    fn test_sequence_type_err() {
-        assert_eq!(sequence_type("ATCXXXRMGU"), None);
+    fn test_read_file() {
+        let myfile = "tests/test.fa.gz";
+        assert!(read_file(myfile, InputFormat::Auto).is_ok());
     }
 
     #[test]
-    fn test_region_to_primer_ok() {
-        assert_eq!(
-            region_to_primer("v1v2").unwrap(),
-            vec!["AGAGTTTGATCMTGGCTCAG", "ACTGCTGCSYCCCGTAGGAGTCT"]
-        );
+    fn test_read_file_forced_format() {
+        // Gzip data behind a misleading extension
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let misnamed = dir.path().join("test.fa");
+        fs::copy("tests/test.fa.gz", &misnamed).unwrap();
+        let misnamed = misnamed.to_str().unwrap();
+
+        let (mut records, _) =
+            read_records(misnamed, InputFormat::Gzip).unwrap();
         assert_eq!(
-            region_to_primer("v1v3").unwrap(),
-            vec!["AGAGTTTGATCMTGGCTCAG", "ATTACCGCGGCTGCTGG"]
+            records.next().unwrap().unwrap().id,
+            "Allorhizobium_borbori__DN316__EF125187"
         );
+
+        // Forcing a plain input does not decompress it
+        let (mut reader, compression) =
+            read_file(misnamed, InputFormat::Plain).unwrap();
+        let mut magic = [0u8; 2];
+        reader.read_exact(&mut magic).unwrap();
+        assert_eq!(magic, [0x1f, 0x8b]);
+        assert_eq!(compression, niffler::compression::Format::No);
+
+        // Forcing a wrong compression fails on read
+        let (mut reader, _) =
+            read_file("tests/test.fa", InputFormat::Gzip).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_read_records_fastq() {
+        let mut tmpfile =
+            NamedTempFile::new().expect("Cannot create temp file");
+        writeln!(tmpfile, "@read1 desc\nACGT\n+\nIIII")
+            .expect("Cannot write to tmp file");
+
+        let (mut records, is_fastq) =
+            read_records(tmpfile.path().to_str().unwrap(), InputFormat::Auto)
+                .unwrap();
+        assert!(is_fastq);
         assert_eq!(
-            region_to_primer("v1v9").unwrap(),
-            vec!["AGAGTTTGATCMTGGCTCAG", "TACGGYTACCTTGTTAYGACTT"]
+            records.next().unwrap().unwrap(),
+            SeqRecord {
+                id: "read1".to_string(),
+                desc: Some("desc".to_string()),
+                seq: b"ACGT".to_vec(),
+                qual: Some(b"IIII".to_vec()),
+                positions: None,
+            }
         );
+        assert!(records.next().is_none());
+    }
+
+    #[test]
+    fn test_read_records_fasta() {
+        let (mut records, is_fastq) =
+            read_records("tests/test.fa.gz", InputFormat::Auto).unwrap();
+        assert!(!is_fastq);
+        let record = records.next().unwrap().unwrap();
+        assert_eq!(record.id, "Allorhizobium_borbori__DN316__EF125187");
+        assert_eq!(record.qual, None);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_mismatch_attributes() {
+        // The reverse primer site of v4 has one mismatch
+        let seq = "GTGCCAGCAGCCGCGGTAAACGTACGTACGTACGTACGTCTTAGATACCCTGGTAGTCC";
+        let primers = vec![region_to_primer("v4").unwrap()];
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("input.fa");
+        fs::write(&input, format!(">seq1\n{}\n", seq)).unwrap();
+        let options = Options {
+            mismatch: 1,
+            ..Default::default()
+        };
+        let (out, amplicons) = run(input.to_str().unwrap(), primers, &options);
+
+        let record = records(&amplicons).remove(0);
+        assert!(record
+            .desc()
+            .unwrap()
+            .ends_with(" fwd_mismatch=0 rev_mismatch=1 primers=kept"));
+        let gff = fs::read_to_string(out.path().join("sample.gff")).unwrap();
+        let feature = gff.lines().find(|line| !line.starts_with('#')).unwrap();
         assert_eq!(
-            region_to_primer("v3v4").unwrap(),
-            vec!["CCTACGGGNGGCWGCAG", "GACTACHVGGGTATCTAATCC"]
+            feature.split('\t').nth(8),
+            Some(
+                "Note=Hypervariable region v4;fwd_mismatch=0;rev_mismatch=1;fwd_start=1;fwd_end=19;rev_start=40;rev_end=59"
+            )
         );
-        assert_eq!(
-            region_to_primer("v3v5").unwrap(),
-            vec!["CCTACGGGNGGCWGCAG", "CCGTCAATTYMTTTRAGT"]
+
+        // Primers of no region are not given a note
+        let (out, _) = run(
+            input.to_str().unwrap(),
+            vec![vec![
+                "GTGCCAGCAGCCGCGGTAA".to_string(),
+                "GGACTACCAGGGTATCTAAG".to_string(),
+            ]],
+            &Options::default(),
         );
+        let gff = fs::read_to_string(out.path().join("sample.gff")).unwrap();
+        let feature = gff.lines().find(|line| !line.starts_with('#')).unwrap();
         assert_eq!(
-            region_to_primer("v4").unwrap(),
-            vec!["GTGCCAGCMGCCGCGGTAA", "GGACTACHVGGGTWTCTAAT"]
+            feature.split('\t').nth(8),
+            Some("fwd_mismatch=0;rev_mismatch=0;fwd_start=1;fwd_end=19;rev_start=40;rev_end=59")
         );
-        assert_eq!(
-            region_to_primer("v4v5").unwrap(),
-            vec!["GTGYCAGCMGCCGCGGTAA", "CCCCGYCAATTCMTTTRAGT"]
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_reverse_mismatch() {
+        // The reverse primer site of v4 has one mismatch
+        let seq = "GTGCCAGCAGCCGCGGTAAACGTACGTACGTACGTACGTCTTAGATACCCTGGTAGTCC";
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("input.fa");
+        fs::write(&input, format!(">seq1\n{}\n", seq)).unwrap();
+        let extracted = |mismatch: u8, reverse_mismatch: Option<u8>| {
+            let options = Options {
+                mismatch,
+                reverse_mismatch,
+                ..Default::default()
+            };
+            let (_out, stats) = run_stats(
+                input.to_str().unwrap(),
+                vec![region_to_primer("v4").unwrap()],
+                &options,
+            );
+            stats.regions[0].1.extracted
+        };
+
+        assert_eq!(extracted(1, None), 1);
+        assert_eq!(extracted(0, None), 0);
+        assert_eq!(extracted(0, Some(1)), 1);
+        assert_eq!(extracted(1, Some(0)), 0);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_mask() {
+        let options = Options {
+            mask: true,
+            ..Default::default()
+        };
+        let (dir, _) = run(
+            "tests/test.fa",
+            vec![region_to_primer("v4").unwrap()],
+            &options,
         );
-        assert_eq!(
-            region_to_primer("v5v7").unwrap(),
-            vec!["AACMGGATTAGATACCCKG", "ACGTCATCCCCACCTTCC"]
+
+        let input = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        let masked =
+            fasta::Reader::from_file(dir.path().join("sample.masked.fa"))
+                .unwrap()
+                .records()
+                .next()
+                .unwrap()
+                .unwrap();
+        assert_eq!(masked.id(), input.id());
+        assert_eq!(masked.seq().to_ascii_uppercase(), input.seq());
+
+        // Lowercase bases span the GFF feature, 1-based and inclusive
+        let gff = fs::read_to_string(dir.path().join("sample.gff")).unwrap();
+        let fields = gff
+            .lines()
+            .find(|line| !line.starts_with('#'))
+            .unwrap()
+            .split('\t')
+            .collect::<Vec<_>>();
+        let start = fields[3].parse::<usize>().unwrap() - 1;
+        let end = fields[4].parse::<usize>().unwrap();
+        let lowercase = masked
+            .seq()
+            .iter()
+            .enumerate()
+            .filter(|(_, base)| base.is_ascii_lowercase())
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        assert_eq!(lowercase, (start..end).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_gff_coordinates() {
+        // v4 primers bind bases 5 to 23 and 44 to 63, counting from 1
+        let seq = "AAAAGTGCCAGCAGCCGCGGTAAACGTACGTACGTACGTACGTATTAGATACCCTGGTAGTCCAAAA";
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("input.fa");
+        fs::write(&input, format!(">seq1\n{}\n", seq)).unwrap();
+        let (out, amplicons) = run(
+            input.to_str().unwrap(),
+            vec![region_to_primer("v4").unwrap()],
+            &Options::default(),
         );
-        assert_eq!(
-            region_to_primer("v6v9").unwrap(),
-            vec!["TAAAACTYAAAKGAATTGACGGGG", "TACGGYTACCTTGTTAYGACTT"]
+
+        let gff = fs::read_to_string(out.path().join("sample.gff")).unwrap();
+        let fields = gff
+            .lines()
+            .find(|line| !line.starts_with('#'))
+            .unwrap()
+            .split('\t')
+            .collect::<Vec<_>>();
+        assert_eq!(fields[3..5], ["5", "63"]);
+        assert!(fields[8]
+            .ends_with(";fwd_start=5;fwd_end=23;rev_start=44;rev_end=63"));
+        let record = records(&amplicons).remove(0);
+        assert_eq!(record.seq(), &seq.as_bytes()[4..63]);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_header_format() {
+        let seq = "AAAAGTGCCAGCAGCCGCGGTAAACGTACGTACGTACGTACGTATTAGATACCCTGGTAGTCCAAAA";
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("input.fa");
+        fs::write(&input, format!(">seq1\n{}\n", seq)).unwrap();
+        let options = Options {
+            header_format: Some(
+                "{id};region={region};fwd={fwd};rev={rev};{start}-{end};mm={fwd_mismatch},{rev_mismatch}"
+                    .to_string(),
+            ),
+            ..Default::default()
+        };
+        let (_out, amplicons) = run(
+            input.to_str().unwrap(),
+            vec![region_to_primer("v4").unwrap()],
+            &options,
         );
+
+        let record = records(&amplicons).remove(0);
+        assert_eq!(record.id(), "seq1");
         assert_eq!(
-            region_to_primer("v7v9").unwrap(),
-            vec!["YAACGAGCGCAACCC", "TACGGYTACCTTGTTAYGACTT"]
+            record.desc(),
+            Some("seq1;region=v4;fwd=GTGCCAGCMGCCGCGGTAA;rev=GGACTACHVGGGTWTCTAAT;5-63;mm=0,0")
         );
-        assert_eq!(region_to_primer("").unwrap(), vec![""]);
     }
 
     #[test]
-    fn test_write_fa_ok2() {
-        let mut tmpfile =
-            NamedTempFile::new().expect("Cannot create temp file");
-        writeln!(tmpfile, ">id_str desc\nATCGCCG")
-            .expect("Cannot write to tmp file");
+    fn test_get_hypervar_regions_anchor_3prime() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let extract = |name: &str, forward: &str, reverse: &str| {
+            let input = dir.path().join(format!("{}.fa", name));
+            fs::write(
+                &input,
+                format!(
+                    ">seq1\nAAAA{}ACGTACGTACGTACGTACGT{}AAAA\n",
+                    forward, reverse
+                ),
+            )
+            .unwrap();
+            let options = Options {
+                mismatch: 1,
+                anchor_3prime: Some(3),
+                ..Default::default()
+            };
+            run(
+                input.to_str().unwrap(),
+                vec![region_to_primer("v4").unwrap()],
+                &options,
+            )
+            .1
+            .matches('>')
+            .count()
+        };
+        let (forward, reverse) =
+            ("GTGCCAGCAGCCGCGGTAA", "ATTAGATACCCTGGTAGTCC");
 
-        let mut fa_records = fasta::Reader::from_file(tmpfile)
-            .expect("Cannot read file.")
-            .records();
+        // Mismatches at the 5' end of the primers are allowed
+        assert_eq!(extract("fwd_5", "CTGCCAGCAGCCGCGGTAA", reverse), 1);
+        assert_eq!(extract("rev_5", forward, "ATTAGATACCCTGGTAGTCA"), 1);
+        // The same mismatches at their 3' end are rejected, the reverse
+        // primer 3' end being at the start of its binding site
+        assert_eq!(extract("fwd_3", "GTGCCAGCAGCCGCGGTCA", reverse), 0);
+        assert_eq!(extract("rev_3", forward, "ACTAGATACCCTGGTAGTCC"), 0);
+    }
 
-        while let Some(Ok(rec)) = fa_records.next() {
-            assert_eq!(rec.id(), "id_str");
-            assert_eq!(rec.desc(), Some("desc"));
-            assert_eq!(rec.seq(), b"ATCGCCG");
-        }
+    #[test]
+    fn test_render_header() {
+        let values = [("region", "v4".to_string())];
+        assert_eq!(render_header("{region}|{x}{", &values), "v4|{x}{");
     }
 
     #[test]
-    fn test_combine_vec() {
-        let first = vec!["ab", "cd", "ef"];
-        let second = vec!["cd", "ef", "gh"];
-        assert_eq!(
-            combine_vec(first, second),
-            vec![
-                vec!["ab".to_string(), "cd".to_string()],
-                vec!["cd".to_string(), "ef".to_string()],
-                vec!["ef".to_string(), "gh".to_string()]
-            ]
+    fn test_get_hypervar_regions_lowercase() {
+        let seq = "aaaaGTGCCAGCAGCCGCGGTAAacgtacgtacgtacgtacgtattagataccctggtagtccaaaa";
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("input.fa");
+        fs::write(&input, format!(">seq1\n{}\n", seq)).unwrap();
+        let (_out, amplicons) = run(
+            input.to_str().unwrap(),
+            vec![region_to_primer("v4").unwrap()],
+            &Options::default(),
         );
+
+        let record = records(&amplicons).remove(0);
+        assert_eq!(record.seq(), &seq.to_ascii_uppercase().as_bytes()[4..63]);
     }
 
     #[test]
-    fn test_combine_vec_not_ok() {
-        let first = vec!["ab", "cd", "ef"];
-        let second = vec!["ab"];
-        assert_ne!(
-            combine_vec(first, second),
+    fn test_get_hypervar_regions_rna() {
+        let dna = "AAAAGTGCCAGCAGCCGCGGTAAACGTACGTACGTACGTACGTATTAGATACCCTGGTAGTCCAAAA";
+        let rna = dna.replace('T', "U");
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let extract = |name: &str, seq: &str| {
+            let input = dir.path().join(name).with_extension("fa");
+            fs::write(&input, format!(">seq1\n{}\n", seq)).unwrap();
+            let (out, amplicons) = run(
+                input.to_str().unwrap(),
+                vec![region_to_primer("v4").unwrap()],
+                &Options::default(),
+            );
+            let gff =
+                fs::read_to_string(out.path().join("sample.gff")).unwrap();
+            let fields = gff
+                .lines()
+                .find(|line| !line.starts_with('#'))
+                .unwrap()
+                .split('\t')
+                .map(|field| field.to_string())
+                .collect::<Vec<_>>();
+            let record = records(&amplicons).remove(0);
+            (fields[3..5].to_vec(), record.seq().to_vec())
+        };
+
+        let (dna_coordinates, dna_amplicon) = extract("dna", dna);
+        let (rna_coordinates, rna_amplicon) = extract("rna", &rna);
+        assert_eq!(dna_coordinates, ["5", "63"]);
+        assert_eq!(rna_coordinates, dna_coordinates);
+        assert_eq!(dna_amplicon, &dna.as_bytes()[4..63]);
+        assert_eq!(rna_amplicon, &rna.as_bytes()[4..63]);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_bed() {
+        let options = Options {
+            format: AnnotationFormat::Both,
+            ..Default::default()
+        };
+        let (dir, _) = run(
+            "tests/test.fa",
             vec![
-                vec!["ab".to_string(), "cd".to_string()],
-                vec!["cd".to_string(), "ef".to_string()],
-                vec!["ef".to_string(), "gh".to_string()]
-            ]
+                region_to_primer("v3v4").unwrap(),
+                region_to_primer("v4").unwrap(),
+            ],
+            &options,
+        );
+
+        let gff = fs::read_to_string(dir.path().join("sample.gff")).unwrap();
+        let bed = fs::read_to_string(dir.path().join("sample.bed")).unwrap();
+        let features = gff
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect::<Vec<_>>();
+        assert_eq!(features.len(), 2);
+        for (feature, line) in features.iter().zip(bed.lines()) {
+            let gff_fields = feature.split('\t').collect::<Vec<_>>();
+            let bed_fields = line.split('\t').collect::<Vec<_>>();
+            let gff_start = gff_fields[3].parse::<usize>().unwrap();
+            let bed_start = bed_fields[1].parse::<usize>().unwrap();
+            assert_eq!(bed_fields[0], gff_fields[0]);
+            assert_eq!(bed_start, gff_start - 1);
+            assert_eq!(bed_fields[2], gff_fields[4]);
+            assert_eq!(bed_fields[5], gff_fields[6]);
+        }
+        assert_eq!(
+            bed.lines()
+                .map(|line| line.split('\t').nth(3))
+                .collect::<Vec<_>>(),
+            vec![Some("v3v4"), Some("v4")]
+        );
+
+        // Only BED coordinates are written in BED format
+        let options = Options {
+            format: AnnotationFormat::Bed,
+            ..Default::default()
+        };
+        let (dir, _) = run(
+            "tests/test.fa",
+            vec![region_to_primer("v4").unwrap()],
+            &options,
         );
+        assert!(dir.path().join("sample.bed").exists());
+        assert!(!dir.path().join("sample.gff").exists());
     }
 
     #[test]
-    fn test_get_hypervar_regions() {
+    fn test_get_hypervar_regions_json() {
+        let options = Options {
+            format: AnnotationFormat::Json,
+            ..Default::default()
+        };
+        let primers = region_to_primer("v4").unwrap();
+        let (dir, amplicons) =
+            run("tests/test.fa", vec![primers.clone()], &options);
+        let prefix = dir.path().join("sample");
+        assert!(!prefix.with_extension("gff").exists());
+
+        let json = fs::read_to_string(prefix.with_extension("json")).unwrap();
+        let entries: Vec<serde_json::Value> =
+            serde_json::from_str(&json).unwrap();
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        let record = records(&amplicons).remove(0);
+        assert_eq!(entry["id"], record.id());
+        assert_eq!(entry["region"], "v4");
+        assert_eq!(entry["start"], 416);
+        assert_eq!(entry["end"], 708);
+        assert_eq!(entry["strand"], "+");
+        assert_eq!(entry["forward_primer"], primers[0].as_str());
+        assert_eq!(entry["reverse_primer"], primers[1].as_str());
+        assert_eq!(entry["fwd_mismatch"], 0);
+        assert_eq!(entry["rev_mismatch"], 0);
+        assert_eq!(
+            entry["sequence"],
+            String::from_utf8_lossy(record.seq()).as_ref()
+        );
+        assert_eq!(record.seq().len(), 292);
+
+        // A JSON array can not be appended to
+        let options = Options {
+            append: true,
+            ..options
+        };
         assert!(get_hypervar_regions(
-            "tests/test.fa.gz",
-            vec![vec![
-                "AGAGTTTGATCMTGGCTCAG".to_string(),
-                "TACGGYTACCTTGTTAYGACTT".to_string()
-            ]],
-            "hyperex",
-            0
+            "tests/test.fa",
+            vec![primers],
+            prefix.to_str().unwrap(),
+            &options,
         )
-        .is_ok());
-        fs::remove_file("hyperex.fa").expect("cannot delete file");
-        fs::remove_file("hyperex.gff").expect("cannot delete file");
+        .is_err());
     }
 
     #[test]
-    fn test_setup_logging() {
-        assert!(setup_logging(false).is_ok());
+    fn test_get_hypervar_regions_compression() {
+        let extract = |compression: OutputCompression| {
+            let options = Options {
+                compression,
+                ..Default::default()
+            };
+            run_stats(
+                "tests/test.fa",
+                vec![region_to_primer("v4").unwrap()],
+                &options,
+            )
+            .0
+        };
+        let plain_dir = extract(OutputCompression::None);
+        let plain = plain_dir.path().join("sample");
+
+        for compression in [
+            OutputCompression::Gzip,
+            OutputCompression::Xz,
+            OutputCompression::Bzip2,
+        ] {
+            let ext = compression.extension().unwrap();
+            let dir = extract(compression);
+            let prefix = dir.path().join("sample");
+            for file_ext in ["fa", "gff"] {
+                let path = format!("{}.{}.{}", prefix.display(), file_ext, ext);
+                let (mut reader, _) = niffler::from_path(&path).unwrap();
+                let mut decoded = Vec::new();
+                reader.read_to_end(&mut decoded).unwrap();
+                assert_eq!(
+                    decoded,
+                    fs::read(plain.with_extension(file_ext)).unwrap(),
+                    "{}",
+                    path
+                );
+            }
+            assert!(!prefix.with_extension("fa").exists());
+        }
+
+        // Compressed files can not be appended to
+        let options = Options {
+            compression: OutputCompression::Gzip,
+            append: true,
+            ..Default::default()
+        };
+        assert!(get_hypervar_regions(
+            "tests/test.fa",
+            vec![region_to_primer("v4").unwrap()],
+            plain_dir.path().join("append").to_str().unwrap(),
+            &options,
+        )
+        .is_err());
     }
 
     #[test]
-    fn test_read_file() {
-        let myfile = "tests/test.fa.gz";
-        assert!(read_file(myfile).is_ok());
+    fn test_get_hypervar_regions_all_hits() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let record = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        // Two copies of the v4 amplicon, at 16..308 and 336..628
+        let part = &record.seq()[400..720];
+        let input = dir.path().join("input.fa");
+        let mut writer = fasta::Writer::to_file(&input).unwrap();
+        writer.write("seq1", None, &[part, part].concat()).unwrap();
+        drop(writer);
+
+        let extract = |all_hits: bool| {
+            let options = Options {
+                all_hits,
+                ..Default::default()
+            };
+            let (out, amplicons) = run(
+                input.to_str().unwrap(),
+                vec![region_to_primer("v4").unwrap()],
+                &options,
+            );
+            let gff =
+                fs::read_to_string(out.path().join("sample.gff")).unwrap();
+            (records(&amplicons), gff)
+        };
+
+        let (records, _) = extract(false);
+        assert_eq!(records.len(), 1);
+
+        let (records, gff) = extract(true);
+        let ids = records.iter().map(|record| record.id()).collect::<Vec<_>>();
+        assert_eq!(ids, ["seq1_1", "seq1_2"]);
+        assert_eq!(records[0].seq(), &record.seq()[416..708]);
+        assert_eq!(records[1].seq(), &record.seq()[416..708]);
+        let coords = gff
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| {
+                let fields = line.split('\t').collect::<Vec<_>>();
+                format!("{}:{}-{}", fields[0], fields[3], fields[4])
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(coords, ["seq1:17-308", "seq1:337-628"]);
+        assert_eq!(gff.matches("##sequence-region seq1 1 640").count(), 1);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_max_regions_per_record() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let record = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        // Three copies of the v4 amplicon
+        let part = &record.seq()[400..720];
+        let input = dir.path().join("input.fa");
+        let mut writer = fasta::Writer::to_file(&input).unwrap();
+        writer.write("seq1", None, &part.repeat(3)).unwrap();
+        drop(writer);
+
+        let options = Options {
+            all_hits: true,
+            max_regions_per_record: Some(2),
+            ..Default::default()
+        };
+        let (out, stats) = run_stats(
+            input.to_str().unwrap(),
+            vec![region_to_primer("v4").unwrap()],
+            &options,
+        );
+
+        let amplicons =
+            fs::read_to_string(out.path().join("sample.fa")).unwrap();
+        let ids = records(&amplicons)
+            .iter()
+            .map(|record| record.id().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["seq1_1", "seq1_2"]);
+        assert_eq!(stats.regions[0].1.extracted, 2);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_presence() {
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let record = fasta::Reader::from_file("tests/test.fa")
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        // Both regions, the v4 amplicon only and no region
+        let input = dir.path().join("input.fa");
+        let mut writer = fasta::Writer::to_file(&input).unwrap();
+        writer.write("seq1", None, record.seq()).unwrap();
+        writer.write("seq2", None, &record.seq()[400..720]).unwrap();
+        writer.write("seq3", None, &b"ACGT".repeat(50)).unwrap();
+        drop(writer);
+
+        let primers = vec![
+            region_to_primer("v3v4").unwrap(),
+            region_to_primer("v4").unwrap(),
+        ];
+        for (values, matrix) in [
+            (
+                Presence::Binary,
+                "id\tv3v4\tv4\nseq1\t1\t1\nseq2\t0\t1\nseq3\t0\t0\n",
+            ),
+            (
+                Presence::Mismatch,
+                "id\tv3v4\tv4\nseq1\t0\t0\nseq2\tNA\t0\nseq3\tNA\tNA\n",
+            ),
+        ] {
+            let options = Options {
+                presence: Some(values),
+                ..Default::default()
+            };
+            let (out, _) =
+                run_stats(input.to_str().unwrap(), primers.clone(), &options);
+
+            let prefix = out.path().join("sample");
+            let presence =
+                fs::read_to_string(prefix.with_extension("presence.tsv"))
+                    .unwrap();
+            assert_eq!(presence, matrix);
+            let rows = presence.lines().collect::<Vec<_>>();
+            assert_eq!(rows.len(), 4);
+            assert!(rows.iter().all(|row| row.split('\t').count() == 3));
+            assert!(!prefix.with_extension("fa").exists());
+            assert!(!prefix.with_extension("gff").exists());
+        }
+    }
+
+    #[test]
+    fn test_quality_trim() {
+        assert_eq!(quality_trim(b"IIII#%#", 20), 4);
+        assert_eq!(quality_trim(b"IIII", 20), 4);
+        assert_eq!(quality_trim(b"I#I#", 20), 3);
+        assert_eq!(quality_trim(b"####", 20), 0);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_quality_trim() {
+        // The reverse primer site is found with one mismatch in the good
+        // quality part of the read and exactly in the low quality tail
+        let forward = "GTGCCAGCAGCCGCGGTAA";
+        let insert = "ACGTACGTACGTACGTACGT";
+        let reverse_site = "ATTAGAAACCCTGGTAGTCC";
+        let reverse_tail = "ATTAGATACCCTGGTAGTCC";
+        let seq =
+            format!("{}{}{}{}", forward, insert, reverse_site, reverse_tail);
+        let qual = format!(
+            "{}{}",
+            "I".repeat(seq.len() - reverse_tail.len()),
+            "#".repeat(reverse_tail.len())
+        );
+        let primers = vec![vec![
+            forward.to_string(),
+            "GGACTACCAGGGTATCTAAT".to_string(),
+        ]];
+
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("reads.fq");
+        fs::write(&input, format!("@read1\n{}\n+\n{}\n", seq, qual)).unwrap();
+        let input = input.to_str().unwrap();
+
+        for (quality_trim, expected) in [
+            (None, seq.len()),
+            (Some(20), seq.len() - reverse_tail.len()),
+        ] {
+            let options = Options {
+                mismatch: 1,
+                quality_trim,
+                ..Default::default()
+            };
+            let (out, _) = run_stats(input, primers.clone(), &options);
+
+            let record = fastq::Reader::from_file(out.path().join("sample.fq"))
+                .unwrap()
+                .records()
+                .next()
+                .unwrap()
+                .unwrap();
+            assert_eq!(record.seq(), &seq.as_bytes()[..expected]);
+            assert_eq!(record.qual(), &qual.as_bytes()[..expected]);
+        }
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_fastq_minus_strand() {
+        let forward = "GTGCCAGCAGCCGCGGTAA";
+        let insert = "ACGTACGTACGTACGTACGT";
+        let reverse = "GGACTACCAGGGTATCTAAT";
+        let amplicon = format!(
+            "{}{}{}",
+            forward,
+            insert,
+            to_reverse_complement(reverse, "dna")
+        );
+        let seq = format!("AAAA{}", to_reverse_complement(&amplicon, "dna"));
+        let qual = (0..seq.len())
+            .map(|i| (b'#' + i as u8 % 40) as char)
+            .collect::<String>();
+        let primers = vec![vec![forward.to_string(), reverse.to_string()]];
+
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("reads.fq");
+        fs::write(&input, format!("@read1\n{}\n+\n{}\n", seq, qual)).unwrap();
+        let (out, _) =
+            run_stats(input.to_str().unwrap(), primers, &Options::default());
+
+        let record = fastq::Reader::from_file(out.path().join("sample.fq"))
+            .unwrap()
+            .records()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.seq(), amplicon.as_bytes());
+        // Qualities follow their bases on the minus strand
+        let expected = qual.bytes().skip(4).rev().collect::<Vec<_>>();
+        assert_eq!(record.qual(), &expected[..]);
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_fastq_emit_both() {
+        let forward = "GTGCCAGCAGCCGCGGTAA";
+        let insert = "ACGTACGTACGTACGTACGT";
+        let reverse = "GGACTACCAGGGTATCTAAT";
+        let amplicon = format!(
+            "{}{}{}",
+            forward,
+            insert,
+            to_reverse_complement(reverse, "dna")
+        );
+        let seq = format!("AAAA{}CC", amplicon);
+        let qual = (0..seq.len())
+            .map(|i| (b'#' + i as u8 % 40) as char)
+            .collect::<String>();
+        let primers = vec![vec![forward.to_string(), reverse.to_string()]];
+
+        let dir = tempfile::tempdir().expect("Cannot create temp dir");
+        let input = dir.path().join("reads.fq");
+        fs::write(&input, format!("@read1\n{}\n+\n{}\n", seq, qual)).unwrap();
+        let options = Options {
+            trim: Trim::Both,
+            emit_both: true,
+            ..Default::default()
+        };
+        let (out, _) = run_stats(input.to_str().unwrap(), primers, &options);
+
+        let first = |file: PathBuf| {
+            fastq::Reader::from_file(file)
+                .unwrap()
+                .records()
+                .next()
+                .unwrap()
+                .unwrap()
+        };
+        let untrimmed = first(out.path().join("sample.fq"));
+        assert_eq!(untrimmed.seq(), amplicon.as_bytes());
+        assert_eq!(untrimmed.qual(), &qual.as_bytes()[4..4 + amplicon.len()]);
+        // Trimmed amplicons keep the qualities of their bases
+        let trimmed = first(out.path().join("sample.trimmed.fq"));
+        let start = 4 + forward.len();
+        assert_eq!(trimmed.seq(), insert.as_bytes());
+        assert_eq!(
+            trimmed.qual(),
+            &qual.as_bytes()[start..start + insert.len()]
+        );
+        assert!(!out.path().join("sample.trimmed.fa").exists());
+        // and are annotated with their own coordinates
+        let gff =
+            fs::read_to_string(out.path().join("sample.trimmed.gff")).unwrap();
+        assert!(gff.contains(&format!(
+            "\t{}\t{}\t",
+            start + 1,
+            start + insert.len()
+        )));
     }
 
     #[test]
@@ -644,6 +8520,175 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_file_to_vec_region_names() {
+        let mut tmpfile =
+            NamedTempFile::new().expect("Cannot create temp file");
+        tmpfile
+            .write_all(
+                b"CCTACGGGNGGCWGCAG,ATTACCGCGGCTGCTGG,v3-custom\n\
+                GTGCCAGCMGCCGCGGTAA,GACTACHVGGGTATCTAATCC\n\
+                GTGCCAGCMGCCGCGGTAA,GGACTACHVGGGTWTCTAAT,\n",
+            )
+            .expect("Cannot write to tmp file");
+
+        let primers = file_to_vec(tmpfile.path().to_str().unwrap()).unwrap();
+        assert_eq!(primers[0][2], "v3-custom");
+        assert_eq!(primers[1].len(), 2);
+        assert_eq!(primers[2].len(), 2);
+        assert_eq!(primers_to_region(&primers[0]), "v3-custom");
+        assert_eq!(primers_to_region(&primers[2]), "v4");
+
+        let mut tmpfile =
+            NamedTempFile::new().expect("Cannot create temp file");
+        tmpfile
+            .write_all(b"CCTACGGGNGGCWGCAG,ATTACCGCGGCTGCTGG,v3,v4\n")
+            .expect("Cannot write to tmp file");
+        assert!(file_to_vec(tmpfile.path().to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_file_to_vec_bom() {
+        let mut tmpfile =
+            NamedTempFile::new().expect("Cannot create temp file");
+        tmpfile
+            .write_all(b"\xef\xbb\xbfCCTACGGGNGGCWGCAG,ATTACCGCGGCTGCTGG\n")
+            .expect("Cannot write to tmp file");
+
+        assert_eq!(
+            file_to_vec(tmpfile.path().to_str().unwrap()).unwrap(),
+            vec![vec![
+                "CCTACGGGNGGCWGCAG".to_string(),
+                "ATTACCGCGGCTGCTGG".to_string()
+            ]]
+        );
+    }
+
+    #[test]
+    fn test_read_records_bom() {
+        let mut tmpfile =
+            NamedTempFile::new().expect("Cannot create temp file");
+        tmpfile
+            .write_all(b"\xef\xbb\xbf>seq1\nACGT\n")
+            .expect("Cannot write to tmp file");
+
+        let (mut records, is_fastq) =
+            read_records(tmpfile.path().to_str().unwrap(), InputFormat::Auto)
+                .unwrap();
+        assert!(!is_fastq);
+        assert_eq!(records.next().unwrap().unwrap().id, "seq1");
+    }
+
+    #[test]
+    fn test_parse_primer_pairs() {
+        assert_eq!(
+            parse_primer_pairs("ACGT,TTTT; GGGG,CCCC\n").unwrap(),
+            vec![
+                vec!["ACGT".to_string(), "TTTT".to_string()],
+                vec!["GGGG".to_string(), "CCCC".to_string()]
+            ]
+        );
+        assert!(parse_primer_pairs("ACGT").is_err());
+        assert!(parse_primer_pairs("ACGT,TTTT,GGGG").is_err());
+        assert!(parse_primer_pairs("ACGT,").is_err());
+    }
+
+    #[test]
+    fn test_load_region_defs() {
+        let mut tmpfile =
+            NamedTempFile::new().expect("Cannot create temp file");
+        writeln!(
+            tmpfile,
+            "[its2]\nforward = \"gcatcgatgaagaacgcagc\"\nreverse = \"ITS4\"\n\n\
+            [myv4]\nforward = \"515F\"\nreverse = \"806R\""
+        )
+        .expect("Cannot write to tmp file");
+
+        let defs = load_region_defs(tmpfile.path().to_str().unwrap())
+            .unwrap()
+            .primers;
+        assert_eq!(
+            defs["its2"],
+            vec![
+                "GCATCGATGAAGAACGCAGC".to_string(),
+                "TCCTCCGCTTATTGATATGC".to_string(),
+                "its2".to_string()
+            ]
+        );
+        assert_eq!(defs["myv4"][..2], region_to_primer("v4").unwrap()[..]);
+        assert_eq!(primers_to_region(&defs["myv4"]), "myv4");
+    }
+
+    #[test]
+    fn test_load_region_defs_gff_types() {
+        let mut tmpfile =
+            NamedTempFile::new().expect("Cannot create temp file");
+        writeln!(
+            tmpfile,
+            "[v4]\ngff_type = \"SO:0001637\"\n\n\
+            [its2]\nforward = \"GCATCGATGAAGAACGCAGC\"\n\
+            reverse = \"TCCTCCGCTTATTGATATGC\"\ngff_type = \"ITS2\""
+        )
+        .expect("Cannot write to tmp file");
+
+        let defs = load_region_defs(tmpfile.path().to_str().unwrap()).unwrap();
+        assert_eq!(defs.primers.keys().collect::<Vec<_>>(), vec!["its2"]);
+        assert_eq!(defs.gff_types["v4"], "SO:0001637");
+        assert_eq!(defs.gff_types["its2"], "ITS2");
+    }
+
+    #[test]
+    fn test_get_hypervar_regions_gff_types() {
+        let options = Options {
+            gff_types: HashMap::from([(
+                "v4".to_string(),
+                "SO:0001637".to_string(),
+            )]),
+            ..Default::default()
+        };
+        let (out, _) = run(
+            "tests/test.fa",
+            vec![
+                region_to_primer("v3v4").unwrap(),
+                region_to_primer("v4").unwrap(),
+            ],
+            &options,
+        );
+
+        let gff = fs::read_to_string(out.path().join("sample.gff")).unwrap();
+        let types = gff
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| line.split('\t').nth(2).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(types, vec!["region", "SO:0001637"]);
+    }
+
+    #[test]
+    fn test_load_region_defs_invalid() {
+        let load = |content: &str| {
+            let mut tmpfile =
+                NamedTempFile::new().expect("Cannot create temp file");
+            write!(tmpfile, "{}", content).expect("Cannot write to tmp file");
+            load_region_defs(tmpfile.path().to_str().unwrap())
+        };
+
+        // Not a primer sequence
+        assert!(load("[r]\nforward = \"ACGT-X\"\nreverse = \"ACGT\"").is_err());
+        // Primers are DNA, as on the command line
+        assert!(load("[r]\nforward = \"ACGU\"\nreverse = \"ACGT\"").is_err());
+        // Built-in regions primers cannot be redefined
+        assert!(load("[v4]\nforward = \"ACGT\"\nreverse = \"ACGT\"").is_err());
+        assert!(load("[v4]\nreverse = \"ACGT\"").is_err());
+        // GFF types are a single column
+        assert!(load("[v4]\ngff_type = \"a\\tb\"").is_err());
+        // Missing and unknown fields
+        assert!(load("[r]\nforward = \"ACGT\"").is_err());
+        assert!(
+            load("[r]\nforward = \"A\"\nreverse = \"A\"\nother = 1").is_err()
+        );
+    }
+
     #[test]
     fn test_file_to_vec_no_ok() {
         assert!(file_to_vec("test.fa").is_err());
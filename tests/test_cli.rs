@@ -0,0 +1,1164 @@
+use assert_cmd::Command;
+use predicates::prelude::*;
+use std::fs;
+use std::path::Path;
+
+fn input(name: &str) -> String {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join(name)
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+#[test]
+fn test_region_not_hijacked_by_file() {
+    let dir = tempfile::tempdir().unwrap();
+    // A file named like a region must not be read as a primer file
+    fs::write(dir.path().join("v4"), "ACGT,TTTT\n").unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--region", "v4", &input("test.fa")])
+        .assert()
+        .success();
+
+    let output = fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+    assert!(output.contains("region=v4"));
+}
+
+#[test]
+fn test_region_file() {
+    let dir = tempfile::tempdir().unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--region-file", &input("primers.txt"), &input("test.fa")])
+        .assert()
+        .success();
+
+    let output = fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+    assert!(output.contains("forward=GTGCCAGCMGCCGCGGTAA"));
+}
+
+#[test]
+fn test_primers_file() {
+    let dir = tempfile::tempdir().unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--primers-file", &input("primers.txt"), &input("test.fa")])
+        .assert()
+        .success();
+
+    let output = fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+    assert!(output.contains("forward=GTGCCAGCMGCCGCGGTAA"));
+}
+
+#[test]
+fn test_region_file_name_rejected_as_region() {
+    let dir = tempfile::tempdir().unwrap();
+    // A primer file given to --region is an unknown region name
+    fs::write(dir.path().join("primers.txt"), "ACGT,TTTT\n").unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--region", "primers.txt", &input("test.fa")])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("not a supported region name"));
+}
+
+#[test]
+fn test_qiime_primers_file() {
+    let dir = tempfile::tempdir().unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args([
+            "--region-file",
+            &input("primers_qiime.tsv"),
+            "--primers-format",
+            "qiime",
+            &input("test.fa"),
+        ])
+        .assert()
+        .success();
+
+    let output = fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+    assert!(output.contains("region=v4"));
+    assert!(output.contains("forward=GTGCCAGCMGCCGCGGTAA"));
+}
+
+#[test]
+fn test_progress() {
+    let dir = tempfile::tempdir().unwrap();
+    for (prefix, progress) in [("plain", false), ("progress", true)] {
+        let mut command = Command::cargo_bin("hyperex").unwrap();
+        command.current_dir(dir.path()).args(["-p", prefix]);
+        if progress {
+            command.arg("--progress");
+        }
+        command.arg(input("test.fa")).assert().success();
+    }
+
+    for ext in ["fa", "gff"] {
+        assert_eq!(
+            fs::read_to_string(dir.path().join(format!("progress.{}", ext)))
+                .unwrap(),
+            fs::read_to_string(dir.path().join(format!("plain.{}", ext)))
+                .unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_mismatch_per_primer() {
+    let dir = tempfile::tempdir().unwrap();
+    // The reverse primer site of v4 has one mismatch
+    fs::write(
+        dir.path().join("input.fa"),
+        ">seq1\nGTGCCAGCAGCCGCGGTAAACGTACGTACGTACGTACGTCTTAGATACCCTGGTAGTCC\n",
+    )
+    .unwrap();
+    let extract = |mismatch: &str| {
+        Command::cargo_bin("hyperex")
+            .unwrap()
+            .current_dir(dir.path())
+            .args(["--force", "--region", "v4", "-m", mismatch, "input.fa"])
+            .assert()
+            .success();
+        fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap()
+    };
+
+    assert!(extract("1").contains("rev_mismatch=1"));
+    assert!(extract("0,1").contains("rev_mismatch=1"));
+    assert!(extract("1,0").is_empty());
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--force", "--region", "v4", "-m", "0,30", "input.fa"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_region_file_conflicts_with_region() {
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .args(["--region", "v4", "--region-file", &input("primers.txt")])
+        .assert()
+        .failure();
+}
+
+// Regression tests for the reported `slice index starts at X but ends at Y`
+// panics: with a large mismatch budget, primers match spuriously and the
+// forward primer can be found downstream of the reverse primer
+
+#[test]
+fn test_reverse_oriented_sequence_does_not_panic() {
+    let dir = tempfile::tempdir().unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["-m", "5", &input("reverse.fa")])
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("panicked").not());
+}
+
+#[test]
+fn test_short_v5v6_sequence_does_not_panic() {
+    let dir = tempfile::tempdir().unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["-m", "5", &input("short_v5v6.fa")])
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("panicked").not());
+}
+
+#[test]
+fn test_large_mismatch_does_not_panic() {
+    let dir = tempfile::tempdir().unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["-m", "8", &input("test.fa")])
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("panicked").not());
+}
+
+#[test]
+fn test_reverse_primer_only() {
+    let dir = tempfile::tempdir().unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args([
+            "-r",
+            "GGACTACHVGGGTWTCTAAT",
+            "--length",
+            "300",
+            &input("test.fa"),
+        ])
+        .assert()
+        .success();
+
+    let output = fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+    let seq = output.lines().nth(1).unwrap();
+    assert_eq!(seq.len(), 300);
+    assert!(seq.ends_with("ATTAGATACCCTGGTAGTCC"));
+}
+
+#[test]
+fn test_invalid_primer() {
+    let dir = tempfile::tempdir().unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args([
+            "-f",
+            "GTGCCAGCMGCCGCGGUAA",
+            "-r",
+            "GGACTACHVGGGTWTCTAAT",
+            &input("test.fa"),
+        ])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "Primer GTGCCAGCMGCCGCGGUAA contains invalid character 'U'",
+        ));
+    assert!(!dir.path().join("hyperex_out.fa").exists());
+}
+
+#[test]
+fn test_trim_primers() {
+    let dir = tempfile::tempdir().unwrap();
+    let amplicon = |args: &[&str]| {
+        Command::cargo_bin("hyperex")
+            .unwrap()
+            .current_dir(dir.path())
+            .args(["--force", "--region", "v4", &input("test.fa")])
+            .args(args)
+            .assert()
+            .success();
+        let output =
+            fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+        let mut lines = output.lines();
+        (
+            lines.next().unwrap().to_string(),
+            lines.next().unwrap().len(),
+        )
+    };
+
+    // v4 primers are 19 and 20 bases long
+    let (kept_header, kept) = amplicon(&[]);
+    let (trimmed_header, trimmed) = amplicon(&["--trim-primers"]);
+    assert!(kept_header.ends_with(" primers=kept"));
+    assert!(trimmed_header.ends_with(" primers=trimmed"));
+    assert_eq!(kept, 292);
+    assert_eq!(trimmed, kept - 19 - 20);
+}
+
+#[test]
+fn test_stdin_keeps_user_infile() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("infile.fa"), ">mine\nACGT\n").unwrap();
+    let data = fs::read(input("test.fa")).unwrap();
+
+    // Both runs read stdin at the same time
+    std::thread::scope(|scope| {
+        for prefix in ["first", "second"] {
+            let (dir, data) = (dir.path(), data.clone());
+            scope.spawn(move || {
+                Command::cargo_bin("hyperex")
+                    .unwrap()
+                    .current_dir(dir)
+                    .args(["--region", "v4", "-p", prefix, "-"])
+                    .write_stdin(data)
+                    .assert()
+                    .success();
+            });
+        }
+    });
+
+    assert_eq!(
+        fs::read_to_string(dir.path().join("infile.fa")).unwrap(),
+        ">mine\nACGT\n"
+    );
+    let first = fs::read_to_string(dir.path().join("first.fa")).unwrap();
+    assert!(!first.is_empty());
+    assert_eq!(
+        fs::read_to_string(dir.path().join("second.fa")).unwrap(),
+        first
+    );
+}
+
+#[test]
+fn test_its1_region() {
+    let dir = tempfile::tempdir().unwrap();
+    // ITS1F binding site, ITS1 and the reverse complement of ITS2
+    let amplicon = format!(
+        "CTTGGTCATTTAGAGGAAGTAA{}GCATCGATGAAGAACGCAGC",
+        "ACGGTTACCA".repeat(12)
+    );
+    fs::write(
+        dir.path().join("its.fa"),
+        format!(">fungus\nTTGACCATGA{}GGTAACCTTA\n", amplicon),
+    )
+    .unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--region", "its1", "its.fa"])
+        .assert()
+        .success();
+
+    let output = fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+    let mut lines = output.lines();
+    assert!(lines.next().unwrap().contains("region=its1 "));
+    assert_eq!(lines.next(), Some(amplicon.as_str()));
+    let gff = fs::read_to_string(dir.path().join("hyperex_out.gff")).unwrap();
+    assert!(gff.contains("\t11\t172\t"));
+}
+
+#[test]
+fn test_18s_v4_region() {
+    let dir = tempfile::tempdir().unwrap();
+    // TAReuk454FWD1 binding site, V4 and the reverse complement of
+    // TAReukREV3, ambiguous bases being resolved
+    let amplicon = format!(
+        "CCAGCAGCCGCGGTAATTCC{}TCGATCAAGAACGAAAGT",
+        "ACGGTTACCA".repeat(30)
+    );
+    fs::write(
+        dir.path().join("euk.fa"),
+        format!(">protist\nTTGACCATGA{}GGTAACCTTA\n", amplicon),
+    )
+    .unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--region", "18s_v4", "euk.fa"])
+        .assert()
+        .success();
+
+    let output = fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+    let mut lines = output.lines();
+    assert!(lines.next().unwrap().contains("region=18s_v4 "));
+    assert_eq!(lines.next(), Some(amplicon.as_str()));
+}
+
+#[test]
+fn test_multiple_files() {
+    let dir = tempfile::tempdir().unwrap();
+    // Both samples have a record with the same id
+    let record = fs::read_to_string(input("test.fa")).unwrap();
+    fs::write(dir.path().join("sampleA.fa"), &record).unwrap();
+    fs::write(dir.path().join("sampleB.fasta"), &record).unwrap();
+    let id = "Allorhizobium_borbori__DN316__EF125187";
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--region", "v4", "sampleA.fa", "sampleB.fasta"])
+        .assert()
+        .success();
+    let output = fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+    let ids = output
+        .lines()
+        .filter(|line| line.starts_with('>'))
+        .map(|line| line.split(' ').next().unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        ids,
+        vec![format!(">sampleA_{}", id), format!(">sampleB_{}", id)]
+    );
+
+    // Each file has its own outputs with --split
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--region", "v4", "--split", "sampleA.fa", "sampleB.fasta"])
+        .assert()
+        .success();
+    for sample in ["sampleA", "sampleB"] {
+        let output = fs::read_to_string(
+            dir.path().join(format!("hyperex_out.{}.fa", sample)),
+        )
+        .unwrap();
+        assert!(output.starts_with(&format!(">{} ", id)));
+        assert!(dir
+            .path()
+            .join(format!("hyperex_out.{}.gff", sample))
+            .exists());
+    }
+}
+
+#[test]
+fn test_duplicated_region() {
+    let dir = tempfile::tempdir().unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--region", "v4", "--region", "v4", &input("test.fa")])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("is supplied more than once"));
+    let output = fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+    assert_eq!(output.matches('>').count(), 2);
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args([
+            "--force",
+            "--dedupe-primers",
+            "--region",
+            "v4",
+            "--region",
+            "v4",
+            &input("test.fa"),
+        ])
+        .assert()
+        .success();
+    let output = fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+    assert_eq!(output.matches('>').count(), 1);
+}
+
+#[test]
+fn test_region_from_env() {
+    let dir = tempfile::tempdir().unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .env("HYPEREX_REGION", "v4")
+        .arg(input("test.fa"))
+        .assert()
+        .success();
+
+    let output = fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+    assert_eq!(output.matches('>').count(), 1);
+    assert!(output.contains("region=v4"));
+}
+
+#[test]
+fn test_primers_from_env() {
+    let dir = tempfile::tempdir().unwrap();
+
+    // HYPEREX_PRIMERS takes precedence over HYPEREX_REGION
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .env("HYPEREX_REGION", "v4")
+        .env("HYPEREX_PRIMERS", "CCTACGGGNGGCWGCAG,GACTACHVGGGTATCTAATCC")
+        .arg(input("test.fa"))
+        .assert()
+        .success();
+
+    let output = fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+    assert_eq!(output.matches('>').count(), 1);
+    assert!(output.contains("region=v3v4"));
+}
+
+#[test]
+fn test_env_overridden_by_cli() {
+    let dir = tempfile::tempdir().unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .env("HYPEREX_REGION", "v4")
+        .env("HYPEREX_PRIMERS", "ACGT,TTTT")
+        .args(["--region", "v3v4", &input("test.fa")])
+        .assert()
+        .success();
+
+    let output = fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+    assert_eq!(output.matches('>').count(), 1);
+    assert!(output.contains("region=v3v4"));
+}
+
+#[test]
+fn test_regions_def() {
+    let dir = tempfile::tempdir().unwrap();
+    let defs = dir.path().join("regions.toml");
+    fs::write(&defs, "[myv4]\nforward = \"515F\"\nreverse = \"806R\"\n")
+        .unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args([
+            "--regions-def",
+            defs.to_str().unwrap(),
+            "--region",
+            "myv4",
+            &input("test.fa"),
+        ])
+        .assert()
+        .success();
+
+    let output = fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+    assert_eq!(output.matches('>').count(), 1);
+    assert!(output.contains("region=myv4 forward=GTGCCAGCMGCCGCGGTAA"));
+}
+
+#[test]
+fn test_benchmark() {
+    let dir = tempfile::tempdir().unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["benchmark", &input("test.fa")])
+        .assert()
+        .success()
+        .stdout(predicates::str::starts_with(
+            "rank\tregion\textracted\trecords\tfraction\tmean_length\tmean_mismatch\n1\tv3v4\t1\t1\t",
+        ));
+
+    // Nothing is written in benchmark mode
+    assert!(!dir.path().join("hyperex_out.fa").exists());
+}
+
+#[test]
+fn test_force_without_existing_outputs() {
+    let dir = tempfile::tempdir().unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--force", "--region", "v4", &input("test.fa")])
+        .assert()
+        .success();
+}
+
+#[test]
+fn test_force_removal_failure() {
+    let dir = tempfile::tempdir().unwrap();
+    // A directory in place of the fasta output cannot be removed
+    fs::create_dir(dir.path().join("hyperex_out.fa")).unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--force", "--region", "v4", &input("test.fa")])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "error: cannot overwrite existing file hyperex_out.fa",
+        ))
+        .stderr(predicates::str::contains("panicked").not());
+}
+
+#[test]
+fn test_threads_same_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let record = fs::read_to_string(input("test.fa")).unwrap();
+    let seq = record.lines().skip(1).collect::<String>();
+    let mut records = String::new();
+    for i in 0..40 {
+        // Vary records so that some regions are missing
+        records.push_str(&format!(">seq{}\n{}\n", i, &seq[(i % 4) * 150..]));
+    }
+    fs::write(dir.path().join("input.fa"), records).unwrap();
+
+    for (prefix, threads) in [("single", "1"), ("auto", "0"), ("four", "4")] {
+        Command::cargo_bin("hyperex")
+            .unwrap()
+            .current_dir(dir.path())
+            .args(["-p", prefix, "--threads", threads, "input.fa"])
+            .assert()
+            .success();
+    }
+
+    for ext in ["fa", "gff"] {
+        let single =
+            fs::read(dir.path().join(format!("single.{}", ext))).unwrap();
+        assert!(!single.is_empty());
+        for prefix in ["auto", "four"] {
+            assert_eq!(
+                fs::read(dir.path().join(format!("{}.{}", prefix, ext)))
+                    .unwrap(),
+                single
+            );
+        }
+    }
+}
+
+#[test]
+fn test_aligned_input_warning() {
+    let dir = tempfile::tempdir().unwrap();
+    let record = fs::read_to_string(input("test.fa")).unwrap();
+    let seq = record.lines().skip(1).collect::<String>();
+    let gapped = format!("{}--..{}", &seq[..100], &seq[100..]);
+    fs::write(
+        dir.path().join("aligned.fa"),
+        format!(">seq1\n{}\n>seq2\n{}\n", gapped, gapped),
+    )
+    .unwrap();
+
+    let output = Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--region", "v4", "aligned.fa"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let log = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(log.matches("may be aligned").count(), 1);
+
+    let output = Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--region", "v4", "--degap", "--force", "aligned.fa"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(!String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("may be aligned"));
+    let gff = fs::read_to_string(dir.path().join("hyperex_out.gff")).unwrap();
+    assert!(gff.contains("\t417\t708\t"));
+}
+
+#[test]
+fn test_warn_file() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args([
+            "--warn-file",
+            "warnings.log",
+            "--region",
+            "v1v2",
+            &input("test.fa"),
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let console = format!(
+        "{}{}",
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap()
+    );
+    assert!(console.contains("This is hyperex"));
+    assert!(!console.contains("Region v1v2 not found"));
+
+    let warnings = fs::read_to_string(dir.path().join("warnings.log")).unwrap();
+    assert!(warnings.contains("Region v1v2 not found"));
+    assert!(!warnings.contains("This is hyperex"));
+}
+
+#[test]
+fn test_near_miss_hint() {
+    let dir = tempfile::tempdir().unwrap();
+    // The reverse primer site of v4 has one mismatch
+    fs::write(
+        dir.path().join("input.fa"),
+        ">seq1\nGTGCCAGCAGCCGCGGTAAACGTACGTACGTACGTACGTCTTAGATACCCTGGTAGTCC\n",
+    )
+    .unwrap();
+    let warnings = |mismatch: &str| {
+        Command::cargo_bin("hyperex")
+            .unwrap()
+            .current_dir(dir.path())
+            .args(["--force", "--warn-file", "warnings.log"])
+            .args(["--region", "v4", "-m", mismatch, "input.fa"])
+            .assert()
+            .success();
+        fs::read_to_string(dir.path().join("warnings.log")).unwrap()
+    };
+
+    assert!(warnings("0").contains(
+        "v4 reverse primer best match in seq1 had 1 mismatches; try --mismatch 0,1"
+    ));
+    fs::remove_file(dir.path().join("warnings.log")).unwrap();
+    assert!(!warnings("1").contains("best match"));
+}
+
+#[test]
+fn test_length_tolerance() {
+    let dir = tempfile::tempdir().unwrap();
+    // 100 bases are missing from the v4 amplicon, from 416 to 708
+    let test = fs::read_to_string(input("test.fa")).unwrap();
+    let mut lines = test.lines();
+    let header = lines.next().unwrap();
+    let seq = lines
+        .take_while(|line| !line.starts_with('>'))
+        .collect::<String>();
+    fs::write(
+        dir.path().join("input.fa"),
+        format!("{}\n{}{}\n", header, &seq[..500], &seq[600..]),
+    )
+    .unwrap();
+    let warnings = |tolerance: &str| {
+        Command::cargo_bin("hyperex")
+            .unwrap()
+            .current_dir(dir.path())
+            .args(["--force", "--warn-file", "warnings.log"])
+            .args(["--length-tolerance", tolerance])
+            .args(["--region", "v4", "input.fa"])
+            .assert()
+            .success();
+        let warnings =
+            fs::read_to_string(dir.path().join("warnings.log")).unwrap();
+        fs::remove_file(dir.path().join("warnings.log")).unwrap();
+        warnings
+    };
+
+    assert!(warnings("20").contains(
+        "is 192 bases long, 34.2% off its expected length of 292 bases"
+    ));
+    assert!(!warnings("40").contains("expected length"));
+}
+
+#[test]
+fn test_check() {
+    let dir = tempfile::tempdir().unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--check", "--region", "v4", &input("test.fa")])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Check passed, no output written"));
+    assert!(!dir.path().join("hyperex_out.fa").exists());
+    assert!(!dir.path().join("hyperex_out.gff").exists());
+
+    fs::write(dir.path().join("bad.fa"), ">seq1\nACGTXACGT\n").unwrap();
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--check", "--region", "v4", "bad.fa"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("not recognized as DNA or RNA"));
+}
+
+#[test]
+fn test_log_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let run = |args: &[&str]| {
+        Command::cargo_bin("hyperex")
+            .unwrap()
+            .current_dir(dir.path())
+            .args(["--force", "--region", "v4"])
+            .args(args)
+            .arg(input("test.fa"))
+            .assert()
+            .success();
+    };
+
+    run(&["--no-log-file"]);
+    assert!(!dir.path().join("hyperex.log").exists());
+
+    run(&["--log-file", "run.log"]);
+    assert!(!dir.path().join("hyperex.log").exists());
+    let log = fs::read_to_string(dir.path().join("run.log")).unwrap();
+    assert!(log.contains("This is hyperex"));
+}
+
+#[test]
+fn test_output_dir() {
+    let dir = tempfile::tempdir().unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--output-dir", "results/run1", "-p", "sample"])
+        .args(["--region", "v4", &input("test.fa")])
+        .assert()
+        .success();
+    let output_dir = dir.path().join("results").join("run1");
+    assert!(output_dir.is_dir());
+    let output = fs::read_to_string(output_dir.join("sample.fa")).unwrap();
+    assert!(output.contains("region=v4"));
+    assert!(output_dir.join("sample.gff").exists());
+    assert!(!dir.path().join("sample.fa").exists());
+}
+
+#[test]
+fn test_reverse_as_is() {
+    let dir = tempfile::tempdir().unwrap();
+
+    // 806R as ordered, then reverse complemented as found on the sequence
+    for (prefix, reverse, flag) in [
+        ("ordered", "GGACTACHVGGGTWTCTAAT", None),
+        ("as_is", "ATTAGAWACCCBDGTAGTCC", Some("--reverse-as-is")),
+    ] {
+        Command::cargo_bin("hyperex")
+            .unwrap()
+            .current_dir(dir.path())
+            .args(["-p", prefix, "-f", "GTGCCAGCMGCCGCGGTAA", "-r", reverse])
+            .args(flag)
+            .arg(input("test.fa"))
+            .assert()
+            .success();
+    }
+    let ordered = fs::read_to_string(dir.path().join("ordered.fa")).unwrap();
+    let as_is = fs::read_to_string(dir.path().join("as_is.fa")).unwrap();
+    assert!(!ordered.is_empty());
+    assert_eq!(ordered, as_is);
+}
+
+#[test]
+fn test_log_primer_set() {
+    let dir = tempfile::tempdir().unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--region", "v4", &input("test.fa")])
+        .assert()
+        .success();
+    let log = fs::read_to_string(dir.path().join("hyperex.log")).unwrap();
+    assert!(log.contains(&format!(
+        "Built-in primer set {}: ",
+        hyperex::utils::primer_set_version()
+    )));
+    // with the references of the primers
+    assert!(log.contains("515F, 806R: Caporaso et al. 2011"));
+}
+
+#[test]
+fn test_opposite_strand_hint() {
+    let dir = tempfile::tempdir().unwrap();
+    // Reverse complement of a sequence with the v4 primers, as read from
+    // the opposite strand
+    fs::write(
+        dir.path().join("input.fa"),
+        ">seq1\nGGACTACCAGGGTATCTAATACGTACGTACGTACGTACGTTTACCGCGGCTGCTGGCAC\n",
+    )
+    .unwrap();
+    let warnings = |strand: &str| {
+        Command::cargo_bin("hyperex")
+            .unwrap()
+            .current_dir(dir.path())
+            .args(["--force", "--warn-file", "warnings.log"])
+            .args(["--strand", strand, "--region", "v4", "input.fa"])
+            .assert()
+            .success();
+        let warnings =
+            fs::read_to_string(dir.path().join("warnings.log")).unwrap();
+        fs::remove_file(dir.path().join("warnings.log")).unwrap();
+        warnings
+    };
+
+    assert!(warnings("plus").contains(
+        "Reverse primer GGACTACHVGGGTWTCTAAT of v4 is found in seq1 as given"
+    ));
+    assert!(!warnings("both").contains("opposite strand"));
+}
+
+#[test]
+fn test_empty_input() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("empty.fa"), "").unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--warn-file", "warnings.log", "--region", "v4", "empty.fa"])
+        .assert()
+        .success();
+    let warnings = fs::read_to_string(dir.path().join("warnings.log")).unwrap();
+    assert!(warnings.contains("No sequences found in input"));
+    let fa = fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+    assert!(fa.is_empty());
+    let gff = fs::read_to_string(dir.path().join("hyperex_out.gff")).unwrap();
+    assert_eq!(gff, "##gff-version 3\n");
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--force", "--strict", "--region", "v4", "empty.fa"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("No sequences found in input"));
+}
+
+#[test]
+fn test_collect_errors() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--collect-errors", "--region", "v1v2", &input("test.fa")])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(!stdout.contains("Region v1v2 not found"));
+    assert!(stdout.contains("region not found: 1 record (e.g."));
+}
+
+#[test]
+fn test_short_sequences_warned_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let short = fs::read_to_string(input("short_v5v6.fa")).unwrap();
+    fs::write(
+        dir.path().join("short.fa"),
+        format!("{}{}", short, short.replacen('>', ">copy_", 1)),
+    )
+    .unwrap();
+
+    let output = Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--collect-errors", "--region", "v4", "short.fa"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.matches("less than 1500 bp").count(), 1);
+}
+
+#[test]
+fn test_region_file_names() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("primers.txt"),
+        "GTGCCAGCMGCCGCGGTAA,GGACTACHVGGGTWTCTAAT,my-v4\n",
+    )
+    .unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--region-file", "primers.txt", &input("test.fa")])
+        .assert()
+        .success();
+    let fasta = fs::read_to_string(dir.path().join("hyperex_out.fa")).unwrap();
+    assert!(fasta.contains(" region=my-v4 "));
+    let gff = fs::read_to_string(dir.path().join("hyperex_out.gff")).unwrap();
+    assert!(gff.contains("Note=Hypervariable region my-v4;"));
+}
+
+#[test]
+fn test_stats() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args([
+            "-q",
+            "--stats",
+            "--region",
+            "v4",
+            "--region",
+            "v1v2",
+            &input("test.fa"),
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stats = String::from_utf8(output.stderr)
+        .unwrap()
+        .lines()
+        .filter(|line| line.starts_with("STATS\t"))
+        .map(|line| line.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        stats,
+        vec![
+            "STATS\trecords\t1",
+            "STATS\tregion\tv4\t1",
+            "STATS\tregion\tv1v2\t0"
+        ]
+    );
+}
+
+#[test]
+fn test_emit_both_existing_output() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("hyperex_out.trimmed.fa"), "").unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--emit-both", "--region", "v4", &input("test.fa")])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("hyperex_out.trimmed.fa"));
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args([
+            "--emit-both",
+            "--force",
+            "--region",
+            "v4",
+            &input("test.fa"),
+        ])
+        .assert()
+        .success();
+    let trimmed =
+        fs::read_to_string(dir.path().join("hyperex_out.trimmed.fa")).unwrap();
+    assert!(trimmed.starts_with(">Allorhizobium_borbori__DN316__EF125187"));
+}
+
+#[test]
+fn test_list_regions() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("regions.toml"),
+        "[hairpin]\nforward = \"GGGGAAAACCCC\"\nreverse = \"806R\"\n",
+    )
+    .unwrap();
+
+    let output = Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--list-regions", "--regions-def", "regions.toml"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 14);
+    assert!(lines[0].starts_with("region\tforward\treverse\thairpin"));
+    assert!(lines[4].starts_with("v3v4\tCCTACGGGNGGCWGCAG\t"));
+    assert!(lines[4].ends_with("\t341F\t805R"));
+    assert!(lines[11].ends_with("\tITS1F\tITS2"));
+    assert!(lines[12].ends_with("\tTAReuk454FWD1\tTAReukREV3"));
+    assert!(lines[13].starts_with("hairpin\tGGGGAAAACCCC\t"));
+    assert!(lines[13].ends_with("\thairpin\t-\t806R"));
+}
+
+#[test]
+fn test_stdin_fastq_existing_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let fasta = fs::read_to_string(input("test.fa")).unwrap();
+    let seq = fasta.lines().skip(1).collect::<String>();
+    let fastq = format!("@r1\n{}\n+\n{}\n", seq, "I".repeat(seq.len()));
+    // Piped FASTQ amplicons are written to a FASTQ file, whatever the
+    // FASTA files around
+    fs::write(dir.path().join("out.fq"), "").unwrap();
+    fs::write(dir.path().join("out.fa"), "").unwrap();
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["-p", "out", "--region", "v4", "-"])
+        .write_stdin(fastq.clone())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("out.fq already exists"));
+    assert_eq!(fs::read_to_string(dir.path().join("out.fq")).unwrap(), "");
+
+    fs::remove_file(dir.path().join("out.fq")).unwrap();
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["-p", "out", "--region", "v4", "-"])
+        .write_stdin(fastq)
+        .assert()
+        .success();
+    assert!(fs::read_to_string(dir.path().join("out.fq"))
+        .unwrap()
+        .starts_with("@r1"));
+    assert_eq!(fs::read_to_string(dir.path().join("out.fa")).unwrap(), "");
+}
+
+#[test]
+fn test_append_checks_other_outputs() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("hyperex_out.fa"), "").unwrap();
+    fs::write(dir.path().join("hyperex_out.consensus.fa"), ">kept\nACGT\n")
+        .unwrap();
+
+    // Consensus sequences are not appended to
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args([
+            "--append",
+            "--consensus",
+            "--region",
+            "v4",
+            &input("test.fa"),
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("hyperex_out.consensus.fa"));
+    assert_eq!(
+        fs::read_to_string(dir.path().join("hyperex_out.consensus.fa"))
+            .unwrap(),
+        ">kept\nACGT\n"
+    );
+
+    Command::cargo_bin("hyperex")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(["--append", "--format", "json", "--region", "v4"])
+        .arg(input("test.fa"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+}
+
+#[test]
+fn test_sidecar_outputs_need_force() {
+    for (args, file) in [
+        (vec!["--consensus"], "hyperex_out.consensus.fa"),
+        (vec!["--concat-regions"], "hyperex_out.concat.fa"),
+        (vec!["--emit-primer-sites"], "hyperex_out.sites.fa"),
+        (
+            vec!["--max-ambiguous-fraction", "0.5"],
+            "hyperex_out.ambiguous.fa",
+        ),
+        (vec!["--stats-per-position"], "hyperex_out.bedgraph"),
+        (vec!["--length-histogram", "10"], "hyperex_out.lengths.tsv"),
+        (vec!["--stats"], "hyperex_out.stats.tsv"),
+        (vec!["--mask"], "hyperex_out.masked.fa"),
+    ] {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(file), "kept").unwrap();
+
+        Command::cargo_bin("hyperex")
+            .unwrap()
+            .current_dir(dir.path())
+            .args(&args)
+            .args(["--region", "v4", &input("test.fa")])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(file));
+        assert_eq!(fs::read_to_string(dir.path().join(file)).unwrap(), "kept");
+
+        Command::cargo_bin("hyperex")
+            .unwrap()
+            .current_dir(dir.path())
+            .args(&args)
+            .args(["--force", "--region", "v4", &input("test.fa")])
+            .assert()
+            .success();
+        assert_ne!(fs::read_to_string(dir.path().join(file)).unwrap(), "kept");
+    }
+}